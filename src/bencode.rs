@@ -1,7 +1,54 @@
-use std::collections::HashMap;
-use std::fmt;
-use std::str;
+//! Decoding and encoding of bencode, the serialization format of torrent
+//! files, tracker responses and several peer protocol extensions.
+//!
+//! Only `core` and `alloc` are used here, so this module is all that's left
+//! of the crate without the `std` feature.
+
+use alloc::collections::BTreeMap;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use core::fmt;
+use core::str;
+
+/// Why decoding failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The input ended partway through a value.
+    Eof,
+    /// A byte that can't start, or come next in, a value.
+    UnexpectedChar(u8),
+    LeadingZero,
+    NegativeZero,
+    /// A string length too big to be a length at all.
+    InvalidLength,
+    /// `dict_entry` was given something other than a dictionary.
+    NotDict,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Eof => write!(f, "reached eof"),
+            DecodeError::UnexpectedChar(c) => {
+                write!(f, "unexpected char: {}", *c as char)
+            }
+            DecodeError::LeadingZero => write!(f, "no leading zeroes allowed"),
+            DecodeError::NegativeZero => write!(f, "negative zero not allowed"),
+            DecodeError::InvalidLength => write!(f, "invalid string length"),
+            DecodeError::NotDict => write!(f, "should be a dictionary"),
+        }
+    }
+}
 
+#[cfg(feature = "std")]
+impl std::error::Error for DecodeError {}
+
+/// A decoded bencode value, borrowing its strings from the input.
+///
+/// `index` is the offset in the input the value started at and `size` the
+/// number of bytes it took up, so that the original encoding of a value can
+/// be sliced back out, as is done to hash the info dictionary. Values built
+/// with `int_val` and friends have both set to 0.
 pub enum BencodeVal<'a> {
     Int {
         index: usize,
@@ -20,11 +67,92 @@ pub enum BencodeVal<'a> {
     },
     Dict {
         index: usize,
-        dict: HashMap<&'a [u8], BencodeVal<'a>>,
+        dict: BTreeMap<&'a [u8], BencodeVal<'a>>,
         size: usize,
     },
 }
 
+impl<'a> BencodeVal<'a> {
+    /// Looks up each of `keys` in turn, descending through nested
+    /// dictionaries. `None` if a key is missing or a value on the way isn't a
+    /// dictionary. An empty path yields the value itself.
+    pub fn path(&self, keys: &[&[u8]]) -> Option<&BencodeVal<'a>> {
+        let mut val = self;
+        for key in keys {
+            val = match val {
+                BencodeVal::Dict {
+                    index: _,
+                    size: _,
+                    dict,
+                } => dict.get(*key)?,
+                _ => return None,
+            };
+        }
+
+        Some(val)
+    }
+}
+
+// `index` and `size` only record where a value was found in the input, so
+// two values are equal when their contents are, wherever they came from.
+impl<'a> PartialEq for BencodeVal<'a> {
+    fn eq(&self, other: &BencodeVal<'a>) -> bool {
+        match (self, other) {
+            (
+                BencodeVal::Int {
+                    index: _,
+                    size: _,
+                    int,
+                },
+                BencodeVal::Int {
+                    index: _,
+                    size: _,
+                    int: other,
+                },
+            ) => int == other,
+            (
+                BencodeVal::Str {
+                    index: _,
+                    size: _,
+                    byte_str,
+                },
+                BencodeVal::Str {
+                    index: _,
+                    size: _,
+                    byte_str: other,
+                },
+            ) => byte_str == other,
+            (
+                BencodeVal::List {
+                    index: _,
+                    size: _,
+                    list,
+                },
+                BencodeVal::List {
+                    index: _,
+                    size: _,
+                    list: other,
+                },
+            ) => list == other,
+            (
+                BencodeVal::Dict {
+                    index: _,
+                    size: _,
+                    dict,
+                },
+                BencodeVal::Dict {
+                    index: _,
+                    size: _,
+                    dict: other,
+                },
+            ) => dict == other,
+            _ => false,
+        }
+    }
+}
+
+impl<'a> Eq for BencodeVal<'a> {}
+
 impl<'a> fmt::Debug for BencodeVal<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -59,7 +187,10 @@ impl<'a> fmt::Debug for BencodeVal<'a> {
                     } else {
                         write!(f, ",")?;
                     }
-                    write!(f, "{:?}: {:?}", str::from_utf8(key).unwrap(), val)?;
+                    match str::from_utf8(key) {
+                        Ok(key) => write!(f, "{:?}: {:?}", key, val)?,
+                        _ => write!(f, "{:?}: {:?}", key, val)?,
+                    }
                 }
                 write!(f, "}}")
             }
@@ -67,29 +198,187 @@ impl<'a> fmt::Debug for BencodeVal<'a> {
     }
 }
 
-pub fn decode(bytes: &[u8]) -> Result<BencodeVal, Box<std::error::Error>> {
+/// Encodes `val`, writing dictionary keys in sorted order.
+pub fn encode(val: &BencodeVal) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    encode_into(val, &mut bytes);
+    bytes
+}
+
+fn encode_into(val: &BencodeVal, bytes: &mut Vec<u8>) {
+    match val {
+        BencodeVal::Int {
+            index: _,
+            size: _,
+            int,
+        } => {
+            bytes.push(b'i');
+            bytes.extend(int.to_string().as_bytes());
+            bytes.push(b'e');
+        }
+        BencodeVal::Str {
+            index: _,
+            size: _,
+            byte_str,
+        } => {
+            bytes.extend(byte_str.len().to_string().as_bytes());
+            bytes.push(b':');
+            bytes.extend(*byte_str);
+        }
+        BencodeVal::List {
+            index: _,
+            size: _,
+            list,
+        } => {
+            bytes.push(b'l');
+            for val in list {
+                encode_into(val, bytes);
+            }
+            bytes.push(b'e');
+        }
+        BencodeVal::Dict {
+            index: _,
+            size: _,
+            dict,
+        } => {
+            // Keys must be written in sorted order for the output to be
+            // valid bencode, and so that info hashes are reproducible, which
+            // is the order a `BTreeMap` iterates in.
+            bytes.push(b'd');
+            for (key, val) in dict {
+                bytes.extend(key.len().to_string().as_bytes());
+                bytes.push(b':');
+                bytes.extend(*key);
+                encode_into(val, bytes);
+            }
+            bytes.push(b'e');
+        }
+    }
+}
+
+/// Builds an integer for encoding.
+pub fn int_val<'a>(int: i64) -> BencodeVal<'a> {
+    BencodeVal::Int {
+        index: 0,
+        int,
+        size: 0,
+    }
+}
+
+/// Builds a byte string for encoding.
+pub fn str_val(byte_str: &[u8]) -> BencodeVal {
+    BencodeVal::Str {
+        index: 0,
+        byte_str,
+        size: 0,
+    }
+}
+
+/// Builds a list for encoding.
+pub fn list_val(list: Vec<BencodeVal>) -> BencodeVal {
+    BencodeVal::List {
+        index: 0,
+        list,
+        size: 0,
+    }
+}
+
+/// Builds a dictionary for encoding.
+pub fn dict_val<'a>(
+    dict: BTreeMap<&'a [u8], BencodeVal<'a>>,
+) -> BencodeVal<'a> {
+    BencodeVal::Dict {
+        index: 0,
+        dict,
+        size: 0,
+    }
+}
+
+/// The length of the encoding of the value at the start of `bytes`, found
+/// by scanning it rather than decoding it, so nothing is allocated.
+pub fn encoded_len(bytes: &[u8]) -> Result<usize, DecodeError> {
+    let is_dict = match bytes.first() {
+        Some(b'i') => return Ok(size(&decode_int(bytes)?)),
+        Some(c) if c.is_ascii_digit() => return Ok(size(&decode_str(bytes)?)),
+        Some(b'l') => false,
+        Some(b'd') => true,
+        Some(&c) => return Err(DecodeError::UnexpectedChar(c)),
+        None => return Err(DecodeError::Eof),
+    };
+
+    let mut index = 1;
+    loop {
+        match bytes.get(index) {
+            Some(b'e') => return Ok(index + 1),
+            Some(c) if is_dict && !c.is_ascii_digit() => {
+                return Err(DecodeError::UnexpectedChar(*c))
+            }
+            Some(_) => {}
+            None => return Err(DecodeError::Eof),
+        }
+        if is_dict {
+            index += size(&decode_str(&bytes[index..])?);
+        }
+        index += encoded_len(&bytes[index..])?;
+    }
+}
+
+/// The encoding of the value under `key` in the dictionary at the start of
+/// `bytes`, if it has one. Like `encoded_len`, the dictionary is scanned
+/// rather than decoded.
+pub fn dict_entry<'a>(
+    bytes: &'a [u8],
+    key: &[u8],
+) -> Result<Option<&'a [u8]>, DecodeError> {
+    if bytes.first() != Some(&b'd') {
+        return Err(DecodeError::NotDict);
+    }
+
+    let mut index = 1;
+    loop {
+        match bytes.get(index) {
+            Some(b'e') => return Ok(None),
+            Some(c) if c.is_ascii_digit() => {}
+            Some(&c) => return Err(DecodeError::UnexpectedChar(c)),
+            None => return Err(DecodeError::Eof),
+        }
+        let entry_key = match decode_str(&bytes[index..])? {
+            BencodeVal::Str { byte_str, size, .. } => {
+                index += size;
+                byte_str
+            }
+            _ => unreachable!(),
+        };
+        let len = encoded_len(&bytes[index..])?;
+        if entry_key == key {
+            return Ok(Some(&bytes[index..index + len]));
+        }
+        index += len;
+    }
+}
+
+/// Decodes the value at the start of `bytes`. Anything after it is ignored;
+/// compare the value's `size` with `bytes.len()` to reject trailing data.
+pub fn decode(bytes: &[u8]) -> Result<BencodeVal, DecodeError> {
     match bytes.iter().next() {
         Some(&c) => match c {
             b'i' => Ok(decode_int(bytes)?),
             b'l' => Ok(decode_list(bytes)?),
             b'd' => Ok(decode_dict(bytes)?),
             _ if c.is_ascii_digit() => Ok(decode_str(bytes)?),
-            _ => Err(From::from(format!("unexpected char: {}", c as char))),
+            _ => Err(DecodeError::UnexpectedChar(c)),
         },
-        None => Err(From::from("reached eof")),
+        None => Err(DecodeError::Eof),
     }
 }
 
-fn decode_int(bytes: &[u8]) -> Result<BencodeVal, Box<std::error::Error>> {
+fn decode_int(bytes: &[u8]) -> Result<BencodeVal, DecodeError> {
     if bytes.len() < 3 {
-        return Err(From::from("reached eof"));
+        return Err(DecodeError::Eof);
     }
 
     if bytes[0] != b'i' {
-        return Err(From::from(format!(
-            "unexpected delimiter: {}",
-            bytes[0] as char
-        )));
+        return Err(DecodeError::UnexpectedChar(bytes[0]));
     }
 
     if &bytes[1..3] == b"0e" {
@@ -101,19 +390,16 @@ fn decode_int(bytes: &[u8]) -> Result<BencodeVal, Box<std::error::Error>> {
     }
 
     if bytes[1] == b'0' {
-        return Err(From::from("no leading zeroes allowed"));
+        return Err(DecodeError::LeadingZero);
     }
 
     if &bytes[1..3] == b"-0" {
-        return Err(From::from("negative zero not allowed"));
+        return Err(DecodeError::NegativeZero);
     }
 
     if let Some(index) = bytes[2..].iter().position(|&x| !x.is_ascii_digit()) {
         if bytes[index + 2] != b'e' {
-            return Err(From::from(format!(
-                "unexpected char: {}",
-                bytes[index + 2] as char
-            )));
+            return Err(DecodeError::UnexpectedChar(bytes[index + 2]));
         }
 
         let integer = str::from_utf8(&bytes[1..index + 2])
@@ -127,48 +413,48 @@ fn decode_int(bytes: &[u8]) -> Result<BencodeVal, Box<std::error::Error>> {
         });
     }
 
-    Err(From::from("reached eof"))
+    Err(DecodeError::Eof)
 }
 
-fn decode_str(bytes: &[u8]) -> Result<BencodeVal, Box<std::error::Error>> {
+fn decode_str(bytes: &[u8]) -> Result<BencodeVal, DecodeError> {
     if bytes.len() < 2 {
-        return Err(From::from("reached eof"));
+        return Err(DecodeError::Eof);
     }
 
     if let Some(index) = bytes.iter().position(|&x| !x.is_ascii_digit()) {
         if bytes[index] != b':' {
-            return Err(From::from(format!(
-                "unexpected char: {}",
-                bytes[index] as char
-            )));
+            return Err(DecodeError::UnexpectedChar(bytes[index]));
         }
 
-        let len: usize =
-            str::from_utf8(&bytes[..index]).unwrap().parse().unwrap();
-        if len + index >= bytes.len() {
-            return Err(From::from("reached eof"));
-        }
+        let digits = str::from_utf8(&bytes[..index]).unwrap();
+        let len: usize = match digits.parse() {
+            Ok(len) => len,
+            Err(_) => return Err(DecodeError::InvalidLength),
+        };
+        // The string is `bytes[index + 1..end]`, so it ends exactly at the
+        // end of the input when `end == bytes.len()`.
+        let end = match (index + 1).checked_add(len) {
+            Some(end) if end <= bytes.len() => end,
+            _ => return Err(DecodeError::Eof),
+        };
 
         return Ok(BencodeVal::Str {
             index: 0,
-            byte_str: &bytes[index + 1..index + len + 1],
-            size: index + len + 1,
+            byte_str: &bytes[index + 1..end],
+            size: end,
         });
     }
 
-    Err(From::from("reached eof"))
+    Err(DecodeError::Eof)
 }
 
-fn decode_list(bytes: &[u8]) -> Result<BencodeVal, Box<std::error::Error>> {
+fn decode_list(bytes: &[u8]) -> Result<BencodeVal, DecodeError> {
     if bytes.len() < 2 {
-        return Err(From::from("reached eof"));
+        return Err(DecodeError::Eof);
     }
 
     if bytes[0] != b'l' {
-        return Err(From::from(format!(
-            "unexpected char: {}",
-            bytes[0] as char
-        )));
+        return Err(DecodeError::UnexpectedChar(bytes[0]));
     }
 
     if bytes[1] == b'e' {
@@ -183,10 +469,10 @@ fn decode_list(bytes: &[u8]) -> Result<BencodeVal, Box<std::error::Error>> {
     let mut index: usize = 1;
     loop {
         if index >= bytes.len() {
-            return Err(From::from("reached eof"));
+            return Err(DecodeError::Eof);
         }
 
-        let val = match bytes[index] {
+        let mut val = match bytes[index] {
             b'i' => decode_int(&bytes[index..])?,
             b'l' => decode_list(&bytes[index..])?,
             b'd' => decode_dict(&bytes[index..])?,
@@ -198,96 +484,38 @@ fn decode_list(bytes: &[u8]) -> Result<BencodeVal, Box<std::error::Error>> {
                     size: index + 1,
                 });
             }
-            _ => {
-                return Err(From::from(format!(
-                    "unexpected char: {}",
-                    bytes[index] as char
-                )))
-            }
+            _ => return Err(DecodeError::UnexpectedChar(bytes[index])),
         };
 
-        let (size, actual_val) = match val {
-            BencodeVal::Int {
-                index: _,
-                int,
-                size,
-            } => (
-                size,
-                BencodeVal::Int {
-                    index: index,
-                    int: int,
-                    size: size,
-                },
-            ),
-            BencodeVal::Str {
-                index: _,
-                byte_str,
-                size,
-            } => (
-                size,
-                BencodeVal::Str {
-                    index: index,
-                    byte_str: byte_str,
-                    size: size,
-                },
-            ),
-            BencodeVal::List {
-                index: _,
-                list,
-                size,
-            } => (
-                size,
-                BencodeVal::List {
-                    index: index,
-                    list: list,
-                    size: size,
-                },
-            ),
-            BencodeVal::Dict {
-                index: _,
-                dict,
-                size,
-            } => (
-                size,
-                BencodeVal::Dict {
-                    index: index,
-                    dict: dict,
-                    size: size,
-                },
-            ),
-        };
-
-        v.push(actual_val);
+        let size = shift(&mut val, index);
+        v.push(val);
         index += size;
     }
 }
 
-fn decode_dict(bytes: &[u8]) -> Result<BencodeVal, Box<std::error::Error>> {
+fn decode_dict(bytes: &[u8]) -> Result<BencodeVal, DecodeError> {
     if bytes.len() < 2 {
-        return Err(From::from("reached eof"));
+        return Err(DecodeError::Eof);
     }
 
     if bytes[0] != b'd' {
-        return Err(From::from(format!(
-            "unexpected char: {}",
-            bytes[0] as char
-        )));
+        return Err(DecodeError::UnexpectedChar(bytes[0]));
     }
 
     if bytes[1] == b'e' {
         return Ok(BencodeVal::Dict {
             index: 0,
-            dict: HashMap::new(),
+            dict: BTreeMap::new(),
             size: 2,
         });
     }
 
-    let mut d: HashMap<&[u8], BencodeVal> = HashMap::new();
+    let mut d: BTreeMap<&[u8], BencodeVal> = BTreeMap::new();
     let mut index: usize = 1;
 
     loop {
         if index >= bytes.len() {
-            return Err(From::from("reached eof"));
+            return Err(DecodeError::Eof);
         }
 
         if bytes[index] == b'e' {
@@ -299,10 +527,7 @@ fn decode_dict(bytes: &[u8]) -> Result<BencodeVal, Box<std::error::Error>> {
         }
 
         if !bytes[index].is_ascii_digit() {
-            return Err(From::from(format!(
-                "unexpected char: {}",
-                bytes[index] as char
-            )));
+            return Err(DecodeError::UnexpectedChar(bytes[index]));
         }
 
         let val = decode_str(&bytes[index..])?;
@@ -315,78 +540,59 @@ fn decode_dict(bytes: &[u8]) -> Result<BencodeVal, Box<std::error::Error>> {
                 index += size;
                 byte_str
             }
-            _ => return Err(From::from("not possible")),
+            _ => unreachable!(),
         };
 
         if index >= bytes.len() {
-            return Err(From::from("reached eof"));
+            return Err(DecodeError::Eof);
         }
 
-        let val = match bytes[index] {
+        let mut val = match bytes[index] {
             b'i' => decode_int(&bytes[index..])?,
             b'l' => decode_list(&bytes[index..])?,
             b'd' => decode_dict(&bytes[index..])?,
             c if c.is_ascii_digit() => decode_str(&bytes[index..])?,
-            _ => {
-                return Err(From::from(format!(
-                    "unexpected char: {}",
-                    bytes[index] as char
-                )))
-            }
+            _ => return Err(DecodeError::UnexpectedChar(bytes[index])),
         };
 
-        let (size, actual_val) = match val {
-            BencodeVal::Int {
-                index: _,
-                int,
-                size,
-            } => (
-                size,
-                BencodeVal::Int {
-                    index: index,
-                    int: int,
-                    size: size,
-                },
-            ),
-            BencodeVal::Str {
-                index: _,
-                byte_str,
-                size,
-            } => (
-                size,
-                BencodeVal::Str {
-                    index: index,
-                    byte_str: byte_str,
-                    size: size,
-                },
-            ),
-            BencodeVal::List {
-                index: _,
-                list,
-                size,
-            } => (
-                size,
-                BencodeVal::List {
-                    index: index,
-                    list: list,
-                    size: size,
-                },
-            ),
-            BencodeVal::Dict {
-                index: _,
-                dict,
-                size,
-            } => (
-                size,
-                BencodeVal::Dict {
-                    index: index,
-                    dict: dict,
-                    size: size,
-                },
-            ),
-        };
-
-        d.insert(key, actual_val);
+        let size = shift(&mut val, index);
+        d.insert(key, val);
         index += size;
     }
 }
+
+fn size(val: &BencodeVal) -> usize {
+    match val {
+        BencodeVal::Int { size, .. }
+        | BencodeVal::Str { size, .. }
+        | BencodeVal::List { size, .. }
+        | BencodeVal::Dict { size, .. } => *size,
+    }
+}
+
+/// Adds `offset` to the index of `val` and of everything nested in it, for
+/// a value decoded from a slice starting `offset` bytes further into the
+/// input, and returns its size.
+fn shift(val: &mut BencodeVal, offset: usize) -> usize {
+    match val {
+        BencodeVal::Int { index, size, .. }
+        | BencodeVal::Str { index, size, .. } => {
+            *index += offset;
+            *size
+        }
+        BencodeVal::List { index, list, size } => {
+            *index += offset;
+            for item in list.iter_mut() {
+                shift(item, offset);
+            }
+            *size
+        }
+        BencodeVal::Dict { index, dict, size } => {
+            *index += offset;
+            for item in dict.values_mut() {
+                shift(item, offset);
+            }
+            *size
+        }
+    }
+}
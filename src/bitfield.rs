@@ -0,0 +1,114 @@
+//! Sets of pieces, laid out as in a bitfield message: the first piece is
+//! the high bit of the first byte. The last byte has spare bits when the
+//! number of pieces isn't a multiple of 8, which are always kept clear.
+
+/// The pieces out of `len` that are set, such as those a peer has or those
+/// we've written.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    /// `len` pieces, none of them set.
+    pub fn new(len: usize) -> Bitfield {
+        Bitfield {
+            bytes: vec![0; (len + 7) / 8],
+            len,
+        }
+    }
+
+    /// `len` pieces, all of them set, as a `have all` message says.
+    pub fn full(len: usize) -> Bitfield {
+        let mut bitfield = Bitfield {
+            bytes: vec![0xff; (len + 7) / 8],
+            len,
+        };
+        bitfield.clear_spare_bits();
+        bitfield
+    }
+
+    /// The bitfield with bytes `bytes`, for `len` pieces. Missing bytes are
+    /// taken as clear, and bytes past the last piece and set spare bits are
+    /// dropped, as peers don't always get them right.
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Bitfield {
+        let mut bitfield = Bitfield::new(len);
+        let n = bitfield.bytes.len().min(bytes.len());
+        bitfield.bytes[..n].copy_from_slice(&bytes[..n]);
+        bitfield.clear_spare_bits();
+        bitfield
+    }
+
+    /// A bitfield with each piece set that's `true` in `pieces`.
+    pub fn from_pieces(pieces: &[bool]) -> Bitfield {
+        let mut bitfield = Bitfield::new(pieces.len());
+        for (index, _) in pieces.iter().enumerate().filter(|(_, &x)| x) {
+            bitfield.set(index);
+        }
+        bitfield
+    }
+
+    /// The number of pieces, set or not.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether piece `index` is set. Pieces past the end aren't.
+    pub fn get(&self, index: usize) -> bool {
+        index < self.len && self.bytes[index / 8] & mask(index) != 0
+    }
+
+    /// Sets piece `index`.
+    ///
+    /// # Panics
+    ///
+    /// If `index` is past the end.
+    pub fn set(&mut self, index: usize) {
+        assert!(index < self.len, "piece {} of {}", index, self.len);
+        self.bytes[index / 8] |= mask(index);
+    }
+
+    /// Sets every piece set in `other`, growing to its length if it's
+    /// longer.
+    pub fn union(&mut self, other: &Bitfield) {
+        if self.len < other.len {
+            self.bytes.resize(other.bytes.len(), 0);
+            self.len = other.len;
+        }
+        for (byte, new) in self.bytes.iter_mut().zip(&other.bytes) {
+            *byte |= new;
+        }
+    }
+
+    /// The number of pieces set.
+    pub fn count_ones(&self) -> usize {
+        self.bytes.iter().map(|x| x.count_ones() as usize).sum()
+    }
+
+    /// The indices of the pieces set, in order.
+    pub fn iter_set_indices<'a>(&'a self) -> impl Iterator<Item = usize> + 'a {
+        (0..self.len).filter(move |&index| self.get(index))
+    }
+
+    /// The bytes as sent in a bitfield message.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    fn clear_spare_bits(&mut self) {
+        if self.len % 8 != 0 {
+            let last = self.bytes.len() - 1;
+            self.bytes[last] &= !(0xff >> (self.len % 8));
+        }
+    }
+}
+
+/// The bit of piece `index` within its byte.
+fn mask(index: usize) -> u8 {
+    0b1000_0000 >> (index % 8)
+}
@@ -0,0 +1,166 @@
+use crate::Error;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+/// An inclusive range of addresses of a single family.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IpRange {
+    pub start: IpAddr,
+    pub end: IpAddr,
+}
+
+impl IpRange {
+    pub fn new(start: IpAddr, end: IpAddr) -> Result<IpRange, Error> {
+        if start.is_ipv4() != end.is_ipv4() {
+            return Err(From::from("range mixes IPv4 and IPv6 addresses"));
+        }
+
+        if start > end {
+            return Err(From::from("range start is after its end"));
+        }
+
+        Ok(IpRange { start, end })
+    }
+
+    /// Parses a CIDR block such as `10.0.0.0/8` or `fe80::/10`.
+    pub fn from_cidr(s: &str) -> Result<IpRange, Error> {
+        let mut parts = s.splitn(2, '/');
+        let addr = parse_ip(parts.next().unwrap_or(""))?;
+        let prefix: u32 = match parts.next() {
+            Some(prefix) => prefix.trim().parse()?,
+            None => return IpRange::new(addr, addr),
+        };
+
+        match addr {
+            IpAddr::V4(addr) => {
+                if prefix > 32 {
+                    return Err(From::from("IPv4 prefix is longer than 32"));
+                }
+
+                let mask =
+                    u32::max_value().checked_shl(32 - prefix).unwrap_or(0);
+                let start = u32::from(addr) & mask;
+                IpRange::new(
+                    IpAddr::V4(Ipv4Addr::from(start)),
+                    IpAddr::V4(Ipv4Addr::from(start | !mask)),
+                )
+            }
+            IpAddr::V6(addr) => {
+                if prefix > 128 {
+                    return Err(From::from("IPv6 prefix is longer than 128"));
+                }
+
+                let mask =
+                    u128::max_value().checked_shl(128 - prefix).unwrap_or(0);
+                let start = u128::from(addr) & mask;
+                IpRange::new(
+                    IpAddr::V6(Ipv6Addr::from(start)),
+                    IpAddr::V6(Ipv6Addr::from(start | !mask)),
+                )
+            }
+        }
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        // `IpAddr` orders every IPv4 address before every IPv6 address, so
+        // this never matches across families.
+        self.start <= *ip && *ip <= self.end
+    }
+}
+
+/// A set of address ranges peers must not be connected to.
+#[derive(Clone, Debug, Default)]
+pub struct Blocklist {
+    pub ranges: Vec<IpRange>,
+}
+
+impl Blocklist {
+    /// Parses a blocklist with one entry per line. Each line may be a CIDR
+    /// block, a P2P (`.p2p`) line like `description:1.2.3.0-1.2.3.255`, or an
+    /// eMule `ipfilter.dat` line like `1.2.3.0 - 1.2.3.255 , 000 , desc`.
+    /// Blank lines and lines starting with `#` are ignored.
+    pub fn parse(contents: &str) -> Result<Blocklist, Error> {
+        let mut ranges = vec![];
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some(range) = parse_line(line)? {
+                ranges.push(range);
+            }
+        }
+
+        Ok(Blocklist { ranges })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.ranges.iter().any(|range| range.contains(ip))
+    }
+}
+
+fn parse_line(line: &str) -> Result<Option<IpRange>, Error> {
+    // ipfilter.dat: "start - end , access level , description". Entries with
+    // an access level above 127 are allowed rather than blocked.
+    if line.contains(',') {
+        let fields: Vec<&str> = line.split(',').map(|x| x.trim()).collect();
+        if fields.len() >= 2 {
+            let level: u32 = fields[1].parse()?;
+            if level > 127 {
+                return Ok(None);
+            }
+        }
+
+        return parse_range(fields[0]).map(Some);
+    }
+
+    if line.contains('-') {
+        // P2P: "description:start-end". The description may itself contain
+        // colons, so split on the last one.
+        let range = match line.rfind(':') {
+            Some(i) if line[i + 1..].contains('-') => &line[i + 1..],
+            _ => line,
+        };
+        return parse_range(range).map(Some);
+    }
+
+    IpRange::from_cidr(line).map(Some)
+}
+
+fn parse_range(s: &str) -> Result<IpRange, Error> {
+    let mut parts = s.splitn(2, '-');
+    let start = parse_ip(parts.next().unwrap_or(""))?;
+    let end = match parts.next() {
+        Some(end) => parse_ip(end)?,
+        None => return Err(From::from(format!("invalid range: {}", s))),
+    };
+
+    IpRange::new(start, end)
+}
+
+/// Like `IpAddr::from_str`, but also accepts the zero-padded IPv4 octets
+/// (`001.002.003.004`) found in `ipfilter.dat` files.
+fn parse_ip(s: &str) -> Result<IpAddr, Error> {
+    let s = s.trim();
+    if let Ok(ip) = IpAddr::from_str(s) {
+        return Ok(ip);
+    }
+
+    let octets: Vec<&str> = s.split('.').collect();
+    if octets.len() != 4 {
+        return Err(From::from(format!("invalid IP address: {}", s)));
+    }
+
+    let mut addr = [0u8; 4];
+    for (i, octet) in octets.iter().enumerate() {
+        addr[i] = match octet.parse() {
+            Ok(octet) => octet,
+            Err(_) => {
+                return Err(From::from(format!("invalid IP address: {}", s)))
+            }
+        };
+    }
+
+    Ok(IpAddr::from(addr))
+}
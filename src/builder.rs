@@ -0,0 +1,208 @@
+use crate::bencode::{self, dict_val, int_val, list_val, str_val};
+use crate::metainfo::MIN_PIECE_LENGTH;
+use crate::Error;
+use sha1::{Digest, Sha1};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// The largest piece length picked automatically. Larger ones are allowed
+/// up to `MAX_PIECE_LENGTH`, but clients handle them less well.
+const MAX_AUTO_PIECE_LENGTH: u64 = 1 << 24;
+const TARGET_NUM_PIECES: u64 = 1500;
+
+/// Builds a `.torrent` file from a file or directory on disk.
+pub struct TorrentBuilder {
+    path: PathBuf,
+    piece_length: Option<u64>,
+    announce: Option<String>,
+    comment: Option<String>,
+    private: bool,
+}
+
+struct SourceFile {
+    path: PathBuf,
+    components: Vec<String>,
+    length: u64,
+}
+
+impl TorrentBuilder {
+    pub fn new<P: AsRef<Path>>(path: P) -> TorrentBuilder {
+        TorrentBuilder {
+            path: path.as_ref().to_path_buf(),
+            piece_length: None,
+            announce: None,
+            comment: None,
+            private: false,
+        }
+    }
+
+    /// Sets the piece length. When unset, one is picked from the total size.
+    pub fn piece_length(mut self, piece_length: u64) -> TorrentBuilder {
+        self.piece_length = Some(piece_length);
+        self
+    }
+
+    pub fn announce(mut self, announce: &str) -> TorrentBuilder {
+        self.announce = Some(announce.to_string());
+        self
+    }
+
+    pub fn comment(mut self, comment: &str) -> TorrentBuilder {
+        self.comment = Some(comment.to_string());
+        self
+    }
+
+    pub fn private(mut self, private: bool) -> TorrentBuilder {
+        self.private = private;
+        self
+    }
+
+    /// Hashes the files and returns the bencoded contents of the torrent.
+    pub fn build(&self) -> Result<Vec<u8>, Error> {
+        let name = match self.path.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => return Err(From::from("path has no file name")),
+        };
+
+        let metadata = fs::metadata(&self.path)?;
+        let mut files = vec![];
+        if metadata.is_dir() {
+            collect_files(&self.path, &mut vec![], &mut files)?;
+        } else {
+            files.push(SourceFile {
+                path: self.path.clone(),
+                components: vec![],
+                length: metadata.len(),
+            });
+        }
+
+        let total_length = files.iter().map(|file| file.length).sum();
+        let piece_length = match self.piece_length {
+            Some(0) => return Err(From::from("piece length must be positive")),
+            Some(piece_length) => piece_length,
+            None => auto_piece_length(total_length),
+        };
+        let pieces = hash_pieces(&files, piece_length)?;
+
+        let mut info = BTreeMap::new();
+        info.insert("name".as_bytes(), str_val(name.as_bytes()));
+        info.insert("piece length".as_bytes(), int_val(piece_length as i64));
+        info.insert("pieces".as_bytes(), str_val(&pieces));
+        if self.private {
+            info.insert("private".as_bytes(), int_val(1));
+        }
+        if metadata.is_dir() {
+            let list = files
+                .iter()
+                .map(|file| {
+                    let mut dict = BTreeMap::new();
+                    dict.insert(
+                        "length".as_bytes(),
+                        int_val(file.length as i64),
+                    );
+                    dict.insert(
+                        "path".as_bytes(),
+                        list_val(
+                            file.components
+                                .iter()
+                                .map(|x| str_val(x.as_bytes()))
+                                .collect(),
+                        ),
+                    );
+                    dict_val(dict)
+                })
+                .collect();
+            info.insert("files".as_bytes(), list_val(list));
+        } else {
+            info.insert("length".as_bytes(), int_val(total_length as i64));
+        }
+
+        let mut dict = BTreeMap::new();
+        dict.insert("info".as_bytes(), dict_val(info));
+        if let Some(announce) = &self.announce {
+            dict.insert("announce".as_bytes(), str_val(announce.as_bytes()));
+        }
+        if let Some(comment) = &self.comment {
+            dict.insert("comment".as_bytes(), str_val(comment.as_bytes()));
+        }
+
+        Ok(bencode::encode(&dict_val(dict)))
+    }
+}
+
+/// Picks a power of two piece length that keeps the number of pieces near
+/// `TARGET_NUM_PIECES`.
+fn auto_piece_length(total_length: u64) -> u64 {
+    let mut piece_length = MIN_PIECE_LENGTH;
+    while piece_length < MAX_AUTO_PIECE_LENGTH
+        && total_length / piece_length > TARGET_NUM_PIECES
+    {
+        piece_length <<= 1;
+    }
+
+    piece_length
+}
+
+fn collect_files(
+    dir: &Path,
+    components: &mut Vec<String>,
+    files: &mut Vec<SourceFile>,
+) -> std::io::Result<()> {
+    let mut entries = vec![];
+    for entry in fs::read_dir(dir)? {
+        entries.push(entry?);
+    }
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        components.push(entry.file_name().to_string_lossy().into_owned());
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            collect_files(&entry.path(), components, files)?;
+        } else {
+            files.push(SourceFile {
+                path: entry.path(),
+                components: components.clone(),
+                length: metadata.len(),
+            });
+        }
+        components.pop();
+    }
+
+    Ok(())
+}
+
+/// Reads the files back to back and returns the concatenated SHA-1 of every
+/// piece. Pieces may span file boundaries.
+fn hash_pieces(
+    files: &Vec<SourceFile>,
+    piece_length: u64,
+) -> std::io::Result<Vec<u8>> {
+    let mut pieces = vec![];
+    let mut buffer = Vec::with_capacity(piece_length as usize);
+    for file in files {
+        let mut f = fs::File::open(&file.path)?;
+        loop {
+            let want = piece_length as usize - buffer.len();
+            let mut chunk = vec![0u8; want];
+            let n = f.read(&mut chunk)?;
+            if n == 0 {
+                break;
+            }
+
+            buffer.extend(&chunk[..n]);
+            if buffer.len() == piece_length as usize {
+                pieces.extend(&Sha1::digest(&buffer));
+                buffer.clear();
+            }
+        }
+    }
+
+    if !buffer.is_empty() {
+        pieces.extend(&Sha1::digest(&buffer));
+    }
+
+    Ok(pieces)
+}
@@ -1,36 +1,754 @@
+use crate::bitfield::Bitfield;
+use crate::blocklist::Blocklist;
+use crate::clock::{Clock, SystemClock};
+use crate::error;
+use crate::extension::{self, Extensions};
+use crate::metainfo::Info;
+use crate::mse::{self, PeerStream};
+use crate::pex::{self, PexState};
+use crate::resume;
+use crate::Error;
+use rand::seq::SliceRandom;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 use std::cmp::{self, Ordering};
-use std::collections::VecDeque;
-use std::fs::OpenOptions;
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::net::{SocketAddr, TcpStream};
-use std::panic;
-use std::sync::mpsc::{self, Receiver, Sender};
+use std::collections::{HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpStream};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{self, AtomicBool, AtomicUsize};
+use std::sync::mpsc::{
+    self, Receiver, RecvTimeoutError, SendError, Sender, TryRecvError,
+};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-#[derive(Copy, Clone)]
+/// Peers are told apart by address alone, as the id isn't always known.
+#[derive(Copy, Clone, Debug)]
 pub struct Peer {
-    pub id: [u8; 20],
+    /// The peer's id, if known. Compact tracker responses leave it out.
+    pub id: Option<[u8; 20]>,
     pub socket: SocketAddr,
 }
 
+impl PartialEq for Peer {
+    fn eq(&self, other: &Peer) -> bool {
+        self.socket == other.socket
+    }
+}
+
+impl Eq for Peer {}
+
+impl Hash for Peer {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.socket.hash(state);
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.socket)
+    }
+}
+
 enum WorkerMsg {
+    /// The handshake succeeded. Always the worker's first message if so.
+    Connected {
+        socket: SocketAddr,
+        /// Handle on the connection, for the manager to shut it down.
+        stream: TcpStream,
+        handshake: Handshake,
+    },
+    /// The connection or handshake failed, and the worker has stopped.
+    HandshakeFailed {
+        socket: SocketAddr,
+        reason: String,
+        /// It failed for want of a file descriptor rather than because of
+        /// the peer.
+        out_of_files: bool,
+    },
+    /// Pieces the peer has, either its bitfield or, for a `have` message,
+    /// just the one piece. Later ones add to what's known of the peer.
     Bitfield {
         socket: SocketAddr,
-        bitfield: Vec<u8>,
+        bitfield: Bitfield,
     },
     Piece {
         socket: SocketAddr,
         index: u32,
         buffer: Vec<u8>,
     },
+    /// The connection was lost, handing back the pieces the worker was
+    /// downloading along with the blocks it had of them.
+    Disconnected {
+        socket: SocketAddr,
+        jobs: Vec<PartialPiece>,
+    },
+    /// The piece was downloaded but didn't match its hash.
+    HashFailed { socket: SocketAddr, index: u32 },
+    /// The peer choked or unchoked us.
+    Choke { socket: SocketAddr, choking: bool },
+    /// Peers learned about through peer exchange, with their flags.
+    Pex {
+        added: Vec<(SocketAddr, u8)>,
+        dropped: Vec<SocketAddr>,
+    },
+}
+
+/// The blocks downloaded so far of a piece that was not finished.
+struct PartialPiece {
+    index: u32,
+    blocks: Vec<bool>,
+    buffer: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DownloadConfig {
+    /// Local address outbound peer connections are bound to. When `None`
+    /// the operating system picks the interface.
+    pub bind_addr: Option<IpAddr>,
+    /// Peers whose address falls in the blocklist are never connected to.
+    pub blocklist: Blocklist,
+    pub encryption: Encryption,
+    /// Give up on the download after this long.
+    pub timeout: Option<Duration>,
+    /// Port announced to trackers in place of the local listening port, for
+    /// when a NAT forwards a different external port to us.
+    pub announce_port: Option<u16>,
+    /// Times a failed announce is retried before moving on to the next
+    /// tracker. `None` means `tracker::ANNOUNCE_RETRIES`.
+    pub announce_retries: Option<u32>,
+    /// Don't listen for incoming connections at all.
+    pub disable_listener: bool,
+    /// Most peers to be connected to at once. Peers beyond this wait until
+    /// a connection frees up. `None` means no limit.
+    pub max_peers: Option<usize>,
+    /// Most connection attempts to have in flight at once. `None` means
+    /// `MAX_CONNECTING`.
+    pub max_connecting: Option<usize>,
+    /// Cap on connections open at once, which unlike `max_peers` can be
+    /// shared between downloads. `None` means no limit.
+    pub connection_limit: Option<ConnectionLimit>,
+    /// Don't exchange peers with the peers we're connected to. Must be set
+    /// for private torrents, whose peers may only come from the tracker.
+    pub disable_pex: bool,
+    /// Leave a few pieces out of the bitfield sent to peers and announce
+    /// them with `have` messages straight after, so that peers which refuse
+    /// to connect to seeds can't tell we are one from the bitfield alone.
+    pub lazy_bitfield: bool,
+    /// Don't advertise the fast extension, for peers that mishandle it.
+    pub disable_fast: bool,
+    /// How long to wait for a peer's bitfield before taking it to have no
+    /// pieces but those in its `have` messages. `None` means
+    /// `BITFIELD_GRACE`.
+    pub bitfield_grace: Option<Duration>,
+    pub queue_depth: QueueDepth,
+    pub output: Output,
+    pub peer_filter: Option<PeerFilter>,
+    pub piece_order: PieceOrder,
+    /// Receives the DHT nodes peers advertise in `port` messages, as the
+    /// peer's IP with the advertised port. Setting it advertises DHT support
+    /// in the handshake, which is what gets peers to send them.
+    pub dht_nodes: Option<Sender<SocketAddr>>,
+    /// How often `Progress` statistics such as the download rate are
+    /// refreshed. `None` means `STATS_INTERVAL`.
+    pub stats_interval: Option<Duration>,
+    /// How long pieces can go without any connected peer having them
+    /// before `reannounce` is asked for more peers. `None` means
+    /// `UNAVAILABLE_TIMEOUT`.
+    pub unavailable_timeout: Option<Duration>,
+    /// Asked for a fresh announce when pieces have had no peers for
+    /// `unavailable_timeout`, with the peers it finds sent to `new_peers`.
+    /// Once `max_reannounces` announces in a row turn up nobody with them,
+    /// the download gives up on those pieces. Without it the download waits
+    /// on `new_peers` for as long as it's open.
+    pub reannounce: Option<Sender<()>>,
+    /// `None` means `MAX_REANNOUNCES`.
+    pub max_reannounces: Option<u32>,
+    /// Time source for `timeout`, `stats_interval` and
+    /// `unavailable_timeout`. `None` means `SystemClock`.
+    pub clock: Option<Arc<Clock>>,
+    /// Sidecar file kept up to date with the pieces written to `output`,
+    /// for `resume::check_pieces` to trust when the download is restarted.
+    pub bitfield_file: Option<PathBuf>,
+    pub sync_policy: SyncPolicy,
+    /// The torrent's files as `(path, length)`, in order, for
+    /// `file_completed`.
+    pub files: Vec<(String, u64)>,
+    /// The torrent's number of pieces, for when `pieces` are only some of
+    /// them. When `None` it's one more than the highest index in `pieces`.
+    pub num_pieces: Option<usize>,
+    /// The torrent's total length in bytes, for when `pieces` are only some
+    /// of its pieces. When `None` it's the length of `pieces`.
+    pub total_length: Option<u64>,
+    /// Told about each of `files` once every piece overlapping it has been
+    /// verified and written. Files already complete when the download starts
+    /// aren't reported.
+    pub file_completed: Option<Sender<FileCompleted>>,
+    /// Told how each connection attempt went, as soon as its handshake
+    /// succeeds or fails.
+    pub handshakes: Option<Sender<HandshakeResult>>,
+}
+
+/// What was agreed with a peer in a successful handshake.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Handshake {
+    pub peer_id: [u8; 20],
+    /// Both sides support the extension protocol, which peer exchange runs
+    /// over.
+    pub extensions: bool,
+    /// Both sides support the fast extension.
+    pub fast: bool,
+    pub encrypted: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HandshakeResult {
+    pub socket: SocketAddr,
+    /// The handshake if it succeeded, or why connecting failed.
+    pub outcome: Result<Handshake, String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileCompleted {
+    /// The file's position in `DownloadConfig::files`.
+    pub index: usize,
+    pub path: String,
+}
+
+/// How many pieces each file is still waiting on.
+struct FileProgress {
+    /// The pieces overlapping each file, as `first..end`.
+    ranges: Vec<(u32, u32)>,
+    remaining: Vec<usize>,
+}
+
+impl FileProgress {
+    fn new(
+        files: &[(String, u64)],
+        piece_len: u64,
+        pieces: &Vec<Piece>,
+    ) -> FileProgress {
+        let done: HashSet<u32> = pieces
+            .iter()
+            .filter(|x| x.job_state == JobState::Done)
+            .map(|x| x.index)
+            .collect();
+        let mut ranges = vec![];
+        let mut remaining = vec![];
+        let mut offset = 0;
+        for (_, length) in files {
+            let first = (offset / piece_len) as u32;
+            let end = if *length == 0 {
+                first
+            } else {
+                ((offset + length - 1) / piece_len) as u32 + 1
+            };
+            ranges.push((first, end));
+            remaining.push((first..end).filter(|x| !done.contains(x)).count());
+            offset += length;
+        }
+
+        FileProgress { ranges, remaining }
+    }
+
+    /// Marks piece `index` done, returning the files that completes.
+    fn piece_done(&mut self, index: u32) -> Vec<usize> {
+        let mut completed = vec![];
+        for (i, &(first, end)) in self.ranges.iter().enumerate() {
+            if first <= index && index < end && self.remaining[i] > 0 {
+                self.remaining[i] -= 1;
+                if self.remaining[i] == 0 {
+                    completed.push(i);
+                }
+            }
+        }
+
+        completed
+    }
+}
+
+/// Picks which of a batch of peers to connect to, and in what order, e.g. to
+/// prefer some subnets or only use IPv4. Runs on the initial peer list and
+/// on each batch of peers found later. Peers left out of the returned list
+/// are never connected to, so returning an empty list means no connections.
+/// The blocklist still applies to the peers it returns.
+#[derive(Clone)]
+pub struct PeerFilter(Arc<Fn(Vec<Peer>) -> Vec<Peer> + Send + Sync>);
+
+impl PeerFilter {
+    pub fn new<F>(filter: F) -> PeerFilter
+    where
+        F: Fn(Vec<Peer>) -> Vec<Peer> + Send + Sync + 'static,
+    {
+        PeerFilter(Arc::new(filter))
+    }
+}
+
+impl fmt::Debug for PeerFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PeerFilter")
+    }
+}
+
+/// Whether `socket` is a peer we're connected or connecting to, or queued
+/// to connect to.
+fn is_known(
+    workers: &[Worker],
+    pending_peers: &VecDeque<Peer>,
+    socket: SocketAddr,
+) -> bool {
+    workers.iter().any(|x| x.peer.socket == socket)
+        || pending_peers.iter().any(|x| x.socket == socket)
+}
+
+fn select_peers(config: &DownloadConfig, peers: Vec<Peer>) -> Vec<Peer> {
+    match &config.peer_filter {
+        Some(PeerFilter(filter)) => filter(peers),
+        None => peers,
+    }
+}
+
+/// The order pieces are downloaded in.
+#[derive(Clone, Debug)]
+pub enum PieceOrder {
+    /// Pieces the fewest peers have first, so they're fetched before the
+    /// peers that have them leave.
+    RarestFirst,
+    /// Lowest index first, for playing media while it downloads.
+    Sequential,
+    /// Highest priority first, with priorities by piece index. Pieces
+    /// without one have priority 0. The caller can change the priorities
+    /// while the download runs, e.g. to favour the pieces after a seek.
+    Priorities(Arc<Mutex<Vec<u32>>>),
+}
+
+impl Default for PieceOrder {
+    fn default() -> PieceOrder {
+        PieceOrder::RarestFirst
+    }
+}
+
+/// A cap on peer connections open at once, whether still connecting or
+/// connected. Clones share the same count, so one limit can cover every
+/// download in the process and keep them within the file descriptor limit.
+#[derive(Clone, Debug)]
+pub struct ConnectionLimit {
+    max: usize,
+    open: Arc<AtomicUsize>,
+}
+
+impl ConnectionLimit {
+    pub fn new(max: usize) -> ConnectionLimit {
+        ConnectionLimit {
+            max,
+            open: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Connections currently holding a slot.
+    pub fn open(&self) -> usize {
+        self.open.load(atomic::Ordering::SeqCst)
+    }
+
+    /// Takes a slot for a new connection, if there's one free. The slot is
+    /// given back when the returned guard is dropped.
+    pub fn try_acquire(&self) -> Option<ConnectionSlot> {
+        let mut open = self.open();
+        while open < self.max {
+            match self.open.compare_exchange(
+                open,
+                open + 1,
+                atomic::Ordering::SeqCst,
+                atomic::Ordering::SeqCst,
+            ) {
+                Ok(_) => {
+                    return Some(ConnectionSlot {
+                        open: self.open.clone(),
+                    })
+                }
+                Err(x) => open = x,
+            }
+        }
+
+        None
+    }
+}
+
+/// A connection's place in a `ConnectionLimit`.
+#[derive(Debug)]
+pub struct ConnectionSlot {
+    open: Arc<AtomicUsize>,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, atomic::Ordering::SeqCst);
+    }
+}
+
+/// Where downloaded data is written.
+#[derive(Clone, Debug)]
+pub enum Output {
+    /// A file, created if it doesn't exist.
+    File(PathBuf),
+    /// A multi-file torrent's files as `(path, length)`, in order, with the
+    /// data running through them end to end. Files and their directories
+    /// are created as they're first written to.
+    Files(Vec<(PathBuf, u64)>),
+    /// A shared buffer, grown as pieces arrive.
+    Memory(Arc<Mutex<Vec<u8>>>),
+}
+
+impl Default for Output {
+    fn default() -> Output {
+        Output::File(PathBuf::from("part"))
+    }
+}
+
+impl Output {
+    /// Where the torrent `info` describes goes under `dir`: a single-file
+    /// torrent is a file named after the torrent, and the files of a
+    /// multi-file torrent go in a directory named after it. Names and paths
+    /// that would lead outside `dir` are refused.
+    pub fn for_torrent(info: &Info, dir: &Path) -> Result<Output, Error> {
+        let root = dir.join(relative_path(info.name)?);
+        Ok(match &info.files {
+            Some(files) => Output::Files(
+                files
+                    .iter()
+                    .map(|file| {
+                        Ok((root.join(relative_path(&file.path)?), file.length))
+                    })
+                    .collect::<Result<_, Error>>()?,
+            ),
+            None => Output::File(root),
+        })
+    }
+
+    /// Creates the empty files among `Files`, along with their
+    /// directories. Nothing is ever written to them, so they wouldn't
+    /// otherwise show up on disk.
+    pub fn create_empty_files(&self) -> io::Result<()> {
+        if let Output::Files(files) = self {
+            for (path, _) in files.iter().filter(|(_, length)| *length == 0) {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                File::create(path)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// `path` as a path that stays below whatever it's joined onto: one or
+/// more plain names, without `..`, a root or a prefix.
+fn relative_path(path: &str) -> Result<&Path, Error> {
+    let plain = |component| match component {
+        Component::Normal(_) => true,
+        _ => false,
+    };
+    let relative = Path::new(path);
+    if path.is_empty() || !relative.components().all(plain) {
+        return Err(From::from(format!("unsafe path {:?}", path)));
+    }
+    Ok(relative)
+}
+
+/// Something downloaded data can be written into at arbitrary offsets.
+pub trait OutputSink {
+    fn write_at(&mut self, offset: u64, bytes: &[u8]) -> std::io::Result<()>;
 }
 
+impl OutputSink for File {
+    fn write_at(&mut self, offset: u64, bytes: &[u8]) -> std::io::Result<()> {
+        self.seek(SeekFrom::Start(offset))?;
+        self.write_all(bytes)
+    }
+}
+
+impl OutputSink for Vec<u8> {
+    fn write_at(&mut self, offset: u64, bytes: &[u8]) -> std::io::Result<()> {
+        let start = usize::try_from(offset).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "offset is too large for memory output",
+            )
+        })?;
+        let end = start + bytes.len();
+        if self.len() < end {
+            self.resize(end, 0);
+        }
+        self[start..end].copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+/// Bounds on the number of block requests kept outstanding with each peer.
+/// Within them, the depth follows how fast the peer delivers.
+#[derive(Clone, Copy, Debug)]
+pub struct QueueDepth {
+    pub min: usize,
+    pub max: usize,
+}
+
+impl Default for QueueDepth {
+    fn default() -> QueueDepth {
+        QueueDepth { min: 2, max: 64 }
+    }
+}
+
+/// When the output is synced to disk. Syncing makes sure data survives a
+/// crash or power loss, but each sync waits on the disk, which on a large
+/// download with a slow disk costs a lot of throughput.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyncPolicy {
+    /// Sync before every save of the `bitfield_file`, and when the download
+    /// ends, so the saved bitfield never claims pieces that aren't on disk.
+    Always,
+    /// Only sync when the download ends. The `bitfield_file` is saved then
+    /// too and not before, so a crash loses the record of everything
+    /// downloaded so far, though not the data itself.
+    OnCompletion,
+    /// Leave it to the operating system to write data out. The
+    /// `bitfield_file` is saved as usual, so after a power loss it can
+    /// claim pieces that never reached the disk.
+    Never,
+}
+
+impl Default for SyncPolicy {
+    fn default() -> SyncPolicy {
+        SyncPolicy::Always
+    }
+}
+
+/// Whether peer connections use Message Stream Encryption.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encryption {
+    /// Only use the plaintext protocol.
+    Disabled,
+    /// Try an encrypted handshake first and fall back to plaintext if the
+    /// peer doesn't support it.
+    Preferred,
+    /// Drop peers that won't encrypt the connection.
+    Required,
+}
+
+impl Default for Encryption {
+    fn default() -> Encryption {
+        Encryption::Disabled
+    }
+}
+
+/// Size of the blocks pieces are requested in, and tracked by.
+const BLOCK_SIZE: u64 = 1 << 14;
+/// Aim to have this much of a peer's transfer time requested ahead.
+const QUEUE_TIME: Duration = Duration::from_secs(3);
+/// How often a peer's rate is measured and its queue depth adjusted.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+/// Default limit on connection attempts in flight at once.
+pub const MAX_CONNECTING: usize = 20;
+/// How long to hold off new connections after running out of file
+/// descriptors, giving others time to close.
+const OUT_OF_FILES_BACKOFF: Duration = Duration::from_secs(5);
+/// How often `Progress` statistics are refreshed by default.
+pub const STATS_INTERVAL: Duration = Duration::from_secs(1);
+/// Default wait for a peer with a piece nobody has before re-announcing.
+pub const UNAVAILABLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Default number of re-announces before pieces nobody has are given up on.
+pub const MAX_REANNOUNCES: u32 = 3;
+/// Default wait for a peer's bitfield, which peers without pieces may skip.
+pub const BITFIELD_GRACE: Duration = Duration::from_secs(5);
+/// How long a worker blocks reading from its peer before checking whether
+/// the manager has a job for it.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+/// How long a worker waits for its next job before going back to reading
+/// from its peer, which leaves it idle until a job turns up.
+const JOB_WAIT: Duration = Duration::from_secs(1);
+/// Wait before racing a peer's next address against those already being
+/// connected to, as RFC 8305 recommends.
+const CONNECT_DELAY: Duration = Duration::from_millis(250);
+/// How often the `bitfield_file` is saved. Each save syncs the output
+/// first under `SyncPolicy::Always`, so it isn't done for every piece.
+const BITFIELD_INTERVAL: Duration = Duration::from_secs(10);
+/// A piece failing its hash check this many times is given up on, as the
+/// swarm evidently has no good copy of it.
+const MAX_HASH_FAILURES: u32 = 5;
+/// Pieces a lazy bitfield leaves out, to be sent as `have` messages.
+const LAZY_PIECES: usize = 4;
+/// Longest message accepted from a peer. Far more than a block needs, and
+/// enough for the bitfield of a torrent with 8 million pieces.
+const MAX_MSG_LEN: u32 = 1 << 20;
+
 struct Job {
     index: u32,
     length: u64,
     downloaded_length: u64,
     hash: [u8; 20],
+    /// Running hash of the blocks received so far. Blocks can arrive out of
+    /// order, so it only covers the received prefix of the piece.
+    hasher: Sha1,
+    hashed_length: u64,
+    /// Which blocks have been received.
+    blocks: Vec<bool>,
+    /// Which blocks have been received or requested.
+    requested: Vec<bool>,
+    buffer: Vec<u8>,
+}
+
+impl Job {
+    /// A job for a piece, resuming from the blocks in `blocks` already
+    /// downloaded into `buffer`, if any.
+    fn new(
+        index: u32,
+        length: u64,
+        hash: [u8; 20],
+        blocks: Vec<bool>,
+        buffer: Vec<u8>,
+    ) -> Job {
+        let mut job = Job {
+            index,
+            length,
+            downloaded_length: 0,
+            hash,
+            hasher: Sha1::new(),
+            hashed_length: 0,
+            blocks: vec![false; num_blocks(length)],
+            requested: vec![],
+            buffer,
+        };
+
+        if blocks.len() == job.blocks.len()
+            && job.buffer.len() == length as usize
+        {
+            job.blocks = blocks;
+        } else {
+            job.buffer = vec![0; length as usize];
+        }
+        for (block_index, _) in
+            job.blocks.iter().enumerate().filter(|&(_, &x)| x)
+        {
+            job.downloaded_length += block_length(length, block_index);
+        }
+        job.requested = job.blocks.clone();
+        job.hash_prefix();
+
+        job
+    }
+
+    /// Stores a block and returns whether the piece is now complete.
+    ///
+    /// Blocks have to be whole, as requested: a short one would be hashed
+    /// along with the bytes it left out, and any other length would keep
+    /// the piece from ever adding up. One that isn't is asked for again.
+    fn add_block(&mut self, begin: u64, block: &[u8]) -> bool {
+        let block_index = (begin / BLOCK_SIZE) as usize;
+        if begin % BLOCK_SIZE != 0
+            || self.blocks.get(block_index) != Some(&false)
+        {
+            return false;
+        }
+        if block.len() as u64 != block_length(self.length, block_index) {
+            self.requested[block_index] = false;
+            return false;
+        }
+        let end = begin + block.len() as u64;
+
+        self.buffer[begin as usize..end as usize].copy_from_slice(block);
+        self.blocks[block_index] = true;
+        self.requested[block_index] = true;
+        self.downloaded_length += block.len() as u64;
+        self.hash_prefix();
+
+        self.downloaded_length == self.length
+    }
+
+    /// Feeds the hasher every received block following the hashed prefix.
+    fn hash_prefix(&mut self) {
+        while self.hashed_length < self.length
+            && self.blocks[(self.hashed_length / BLOCK_SIZE) as usize]
+        {
+            let end = cmp::min(self.hashed_length + BLOCK_SIZE, self.length);
+            self.hasher.update(
+                &self.buffer[self.hashed_length as usize..end as usize],
+            );
+            self.hashed_length = end;
+        }
+    }
+}
+
+fn num_blocks(length: u64) -> usize {
+    ((length + BLOCK_SIZE - 1) / BLOCK_SIZE) as usize
+}
+
+fn block_length(length: u64, block_index: usize) -> u64 {
+    cmp::min(BLOCK_SIZE, length - block_index as u64 * BLOCK_SIZE)
+}
+
+/// The block requests outstanding with one peer.
+struct RequestQueue {
+    bounds: QueueDepth,
+    depth: usize,
+    outstanding: usize,
+    window_start: Instant,
+    window_bytes: u64,
+    /// Whether we've told the peer we're interested.
+    am_interested: bool,
+    /// Whether the peer is choking us. Peers start out choking, and ignore
+    /// requests until they unchoke.
+    peer_choking: bool,
+    /// Whether the fast extension is in use. If so, the peer rejects each
+    /// request it won't answer, rather than dropping them all when it
+    /// chokes us.
+    fast: bool,
+}
+
+impl RequestQueue {
+    fn new(bounds: QueueDepth) -> RequestQueue {
+        RequestQueue {
+            bounds,
+            depth: bounds.min,
+            outstanding: 0,
+            window_start: Instant::now(),
+            window_bytes: 0,
+            am_interested: false,
+            peer_choking: true,
+            fast: false,
+        }
+    }
+
+    /// Whether requests can be sent to the peer.
+    fn can_request(&self) -> bool {
+        self.am_interested && !self.peer_choking
+    }
+
+    /// Records a block arriving, and every `RATE_WINDOW` resizes the queue
+    /// to hold `QUEUE_TIME` worth of blocks at the peer's current rate.
+    fn received(&mut self, len: usize) {
+        self.outstanding = self.outstanding.saturating_sub(1);
+        self.window_bytes += len as u64;
+
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= RATE_WINDOW {
+            let rate = self.window_bytes as f64 / elapsed.as_secs_f64();
+            let depth = (rate * QUEUE_TIME.as_secs_f64() / BLOCK_SIZE as f64)
+                .ceil() as usize;
+            self.depth =
+                cmp::max(self.bounds.min, cmp::min(depth, self.bounds.max));
+            self.window_start = Instant::now();
+            self.window_bytes = 0;
+        }
+    }
 }
 
 enum ManagerMsg {
@@ -38,6 +756,10 @@ enum ManagerMsg {
         index: u32,
         length: u64,
         hash: [u8; 20],
+        /// Blocks of the piece already downloaded, and the buffer holding
+        /// them, for resuming it.
+        blocks: Vec<bool>,
+        buffer: Vec<u8>,
     },
     Done,
 }
@@ -45,47 +767,226 @@ enum ManagerMsg {
 struct Worker {
     peer: Peer,
     sender: Sender<ManagerMsg>,
+    /// Handle on the worker's connection once it's connected, used to
+    /// unblock it when the download is stopped early.
+    socket: Option<TcpStream>,
+    /// The pieces the peer has, as sent in its bitfield message.
+    bitfield: Bitfield,
+    /// Whether the peer is choking us.
+    peer_choking: bool,
     thread: thread::JoinHandle<()>,
 }
 
+impl Worker {
+    fn stop(&self) {
+        let _ = self.sender.send(ManagerMsg::Done);
+        if let Some(socket) = &self.socket {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        self.socket.is_some()
+    }
+}
+
 struct PeerMsg {
     id: u8,
     payload: Option<Vec<u8>>,
 }
 
+/// What a worker needs to answer the peer's requests for blocks.
+struct Uploads {
+    /// Which pieces have been written to `output`, and so can be read back
+    /// to serve. Shared with the manager, which marks pieces as it writes
+    /// them.
+    written: Arc<Mutex<Vec<bool>>>,
+    output: Output,
+    piece_len: u64,
+    total_len: u64,
+    /// Whether we're choking the peer. Peers start out choked, and are
+    /// unchoked once they're interested and we have pieces to give them.
+    am_choking: bool,
+    /// Whether the fast extension is in use, so requests we won't serve
+    /// have to be rejected.
+    fast: bool,
+}
+
+impl Uploads {
+    /// The block a `request` payload asks for, as `(index, begin, length)`,
+    /// if it's one we can serve. Anything else is an error, so a peer can't
+    /// have us read outside the piece or send more than a block.
+    fn check_request(&self, payload: &[u8]) -> Result<(u32, u32, u32), String> {
+        if payload.len() != 12 {
+            return Err(format!(
+                "request should be 12 bytes, got {}",
+                payload.len()
+            ));
+        }
+        let be_u32 = |i: usize| {
+            u32::from_be_bytes([
+                payload[i],
+                payload[i + 1],
+                payload[i + 2],
+                payload[i + 3],
+            ])
+        };
+        let (index, begin, length) = (be_u32(0), be_u32(4), be_u32(8));
+
+        if length == 0 || length as u64 > BLOCK_SIZE {
+            return Err(format!("block length {} is out of range", length));
+        }
+        let written = self.written.lock().unwrap();
+        if written.get(index as usize) != Some(&true) {
+            return Err(format!("we don't have piece {}", index));
+        }
+        let start = index as u64 * self.piece_len;
+        let piece_len = cmp::min(self.piece_len, self.total_len - start);
+        if begin as u64 + length as u64 > piece_len {
+            return Err(format!(
+                "block {}+{} is outside piece {} of {} bytes",
+                begin, length, index, piece_len
+            ));
+        }
+
+        Ok((index, begin, length))
+    }
+}
+
 impl Worker {
+    /// Starts a worker that connects to `peer` in the background. It
+    /// reports how that went with `WorkerMsg::Connected` or
+    /// `WorkerMsg::HandshakeFailed`. `slot` is held until the worker's
+    /// thread ends, and with it the connection. `alternatives` are other
+    /// addresses of the same peer, raced against its own.
     pub fn new(
         peer: Peer,
+        alternatives: Vec<SocketAddr>,
         to_manager: Sender<WorkerMsg>,
         info_hash: [u8; 20],
-    ) -> Option<Worker> {
+        config: &DownloadConfig,
+        mut uploads: Uploads,
+        swarm: Option<Arc<Mutex<Vec<SocketAddr>>>>,
+        slot: Option<ConnectionSlot>,
+    ) -> Worker {
         let (to_me, from_manager) = mpsc::channel();
-
-        let (handshake_tx, handshake_rx) = mpsc::channel();
+        let bind_addr = config.bind_addr;
+        let encryption = config.encryption;
+        let queue_depth = config.queue_depth;
+        let dht_nodes = config.dht_nodes.clone();
+        let lazy_bitfield = config.lazy_bitfield;
+        let bitfield_grace = config.bitfield_grace.unwrap_or(BITFIELD_GRACE);
+        let reserved = reserved_bytes(config);
 
         let thread = thread::spawn(move || {
-            let mut stream = match handshake(&peer, &info_hash) {
-                Ok(stream) => stream,
+            let _slot = slot;
+            let mut sockets = vec![peer.socket];
+            sockets.extend(alternatives);
+            let handshake = handshake(
+                &peer, &sockets, &info_hash, bind_addr, encryption, reserved,
+            )
+            .and_then(|(stream, handshake)| {
+                let socket = stream.try_clone_socket()?;
+                Ok((stream, handshake, socket))
+            });
+            let (mut stream, handshake) = match handshake {
+                Ok((stream, handshake, socket)) => {
+                    let connected = WorkerMsg::Connected {
+                        socket: peer.socket,
+                        stream: socket,
+                        handshake,
+                    };
+                    // The download may have ended while we were connecting.
+                    if to_manager.send(connected).is_err() {
+                        return;
+                    }
+                    (stream, handshake)
+                }
                 Err(e) => {
                     println!("Handshake failed: {}", e);
-                    handshake_tx.send(false).unwrap();
+                    let _ = to_manager.send(WorkerMsg::HandshakeFailed {
+                        socket: peer.socket,
+                        reason: e.to_string(),
+                        out_of_files: e.is_out_of_files(),
+                    });
                     return;
                 }
             };
-            handshake_tx.send(true).unwrap();
+
+            uploads.fast = handshake.fast;
+            let buffer = have_msgs(
+                &uploads.written.lock().unwrap(),
+                handshake.fast,
+                lazy_bitfield,
+            );
+            if let Err(e) = stream.write_all(&buffer) {
+                println!("Failed to send bitfield: {}", e);
+            }
+
+            let mut extensions = Extensions::new(if swarm.is_some() {
+                &[pex::NAME]
+            } else {
+                &[]
+            });
+            if handshake.extensions {
+                if let Err(e) = stream.write_all(&extensions.handshake_msg()) {
+                    println!("Failed to send extension handshake: {}", e);
+                }
+            }
 
             let mut job_queue = VecDeque::new();
-            let mut piece_buffer = vec![];
+            let mut pex_state = PexState::new();
+            let mut request_queue = RequestQueue::new(queue_depth);
+            request_queue.fast = handshake.fast;
+
+            // Reads give up now and then so that jobs are picked up while
+            // the peer is quiet.
+            if let Err(e) = stream
+                .try_clone_socket()
+                .and_then(|x| x.set_read_timeout(Some(POLL_INTERVAL)))
+            {
+                println!("Failed to set read timeout: {}", e);
+            }
+            let connected_at = Instant::now();
+            let mut announced = false;
 
             loop {
+                if !announced && connected_at.elapsed() >= bitfield_grace {
+                    announced = true;
+                    let empty = WorkerMsg::Bitfield {
+                        socket: peer.socket,
+                        bitfield: Bitfield::default(),
+                    };
+                    if to_manager.send(empty).is_err() {
+                        break;
+                    }
+                }
+                if job_queue.is_empty()
+                    && next_job(
+                        &mut stream,
+                        &from_manager,
+                        Duration::from_secs(0),
+                        &mut job_queue,
+                        &mut request_queue,
+                    ) == ThreadState::Dead
+                {
+                    break;
+                }
+
                 let peer_msg = match read_msg(&mut stream) {
                     Ok(Some(peer_msg)) => peer_msg,
                     Ok(None) => continue,
                     Err(e) => {
-                        println!("{}", e);
+                        println!("Lost connection to {}: {}", peer.socket, e);
+                        if handle_disconnect(&peer, &to_manager, &mut job_queue)
+                            == ThreadState::Dead
+                        {
+                            break;
+                        }
                         continue;
                     }
                 };
+                announced |= [4, 5, 0x0E, 0x0F].contains(&peer_msg.id);
 
                 if handle_peer_msg(
                     &mut stream,
@@ -94,44 +995,78 @@ impl Worker {
                     &to_manager,
                     &from_manager,
                     &mut job_queue,
-                    &mut piece_buffer,
+                    &mut extensions,
+                    &mut request_queue,
+                    &mut uploads,
+                    &dht_nodes,
                 ) == ThreadState::Dead
                 {
                     break;
                 }
+
+                if let (Some(swarm), Some(_)) =
+                    (&swarm, extensions.remote_id(pex::NAME))
+                {
+                    let swarm: Vec<SocketAddr> = swarm
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .filter(|&&x| x != peer.socket)
+                        .cloned()
+                        .collect();
+                    let msg = pex_state
+                        .next_msg(&swarm)
+                        .and_then(|x| extensions.msg(pex::NAME, &x));
+                    if let Some(msg) = msg {
+                        if let Err(e) = stream.write_all(&msg) {
+                            println!("Failed to send peers: {}", e);
+                        }
+                    }
+                }
             }
         });
 
-        if handshake_rx.recv().unwrap() == false {
-            return None;
-        }
-
-        Some(Worker {
+        Worker {
             peer,
             sender: to_me,
+            socket: None,
+            bitfield: Bitfield::default(),
+            peer_choking: true,
             thread,
-        })
+        }
     }
 }
 
-#[derive(PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum JobState {
     Done,
     Downloading,
     Available,
+    /// Failed its hash check `MAX_HASH_FAILURES` times, or couldn't be
+    /// written, so it won't be downloaded again.
+    Failed,
 }
 
 pub struct Piece {
     pub index: u32,
-    pub peers: Vec<SocketAddr>,
+    /// Number of connected peers that have this piece.
+    pub availability: usize,
     pub job_state: JobState,
     pub length: u64,
     pub hash: [u8; 20],
+    /// Number of times a download of this piece failed its hash check.
+    pub hash_failures: u32,
+    /// Which of the piece's 16 KiB blocks have been downloaded.
+    pub blocks: Vec<bool>,
+    /// The downloaded blocks of an unfinished piece, kept so it can be
+    /// resumed by another peer.
+    buffer: Vec<u8>,
 }
 
 impl Ord for Piece {
     fn cmp(&self, other: &Piece) -> Ordering {
-        self.peers.len().cmp(&other.peers.len())
+        self.availability.cmp(&other.availability)
     }
 }
 
@@ -143,7 +1078,7 @@ impl PartialOrd for Piece {
 
 impl PartialEq for Piece {
     fn eq(&self, other: &Piece) -> bool {
-        self.peers.len() == other.peers.len()
+        self.availability == other.availability
     }
 }
 
@@ -153,52 +1088,558 @@ impl Piece {
     pub fn new(index: u32, length: u64, hash: [u8; 20]) -> Piece {
         Piece {
             index,
-            peers: vec![],
+            availability: 0,
             job_state: JobState::Available,
             length,
             hash,
+            hash_failures: 0,
+            blocks: vec![false; num_blocks(length)],
+            buffer: vec![],
+        }
+    }
+
+    /// Whether every block of the piece has been downloaded.
+    pub fn is_complete(&self) -> bool {
+        self.blocks.iter().all(|&x| x)
+    }
+
+    fn set_done(&mut self) {
+        self.job_state = JobState::Done;
+        self.blocks = vec![true; self.blocks.len()];
+        self.buffer = vec![];
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Progress {
+    pub completed_pieces: usize,
+    pub total_pieces: usize,
+    pub downloaded_bytes: u64,
+    pub total_bytes: u64,
+    /// Set while every peer is idle because none of them has any of the
+    /// remaining pieces.
+    pub stalled: bool,
+    /// Peers we've connected and handshaken with so far.
+    pub handshakes_succeeded: usize,
+    /// Peers we failed to connect or handshake with so far.
+    pub handshakes_failed: usize,
+    /// Bytes downloaded per second. This and the rest of the fields below
+    /// are refreshed every `DownloadConfig::stats_interval`, and rates are
+    /// over the last interval.
+    pub download_rate: f64,
+    /// Pieces completed per second.
+    pub piece_rate: f64,
+    pub connected_peers: usize,
+    /// Connected peers that are choking us.
+    pub choked_peers: usize,
+    /// Time left at the current download rate. `None` while nothing is
+    /// being downloaded.
+    pub eta: Option<Duration>,
+    /// How many connected peers have the rarest of the remaining pieces.
+    /// `None` once nothing remains, and 0 means a piece can't be had from
+    /// anyone connected.
+    pub min_availability: Option<usize>,
+}
+
+impl Progress {
+    pub fn new(pieces: &Vec<Piece>) -> Progress {
+        let mut progress = Progress::default();
+        for piece in pieces {
+            progress.total_pieces += 1;
+            progress.total_bytes += piece.length;
+            if piece.job_state == JobState::Done {
+                progress.completed_pieces += 1;
+                progress.downloaded_bytes += piece.length;
+            }
+        }
+
+        progress
+    }
+
+    pub fn percentage(&self) -> f64 {
+        if self.total_bytes == 0 {
+            return 100.0;
+        }
+
+        self.downloaded_bytes as f64 * 100.0 / self.total_bytes as f64
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.completed_pieces == self.total_pieces
+    }
+}
+
+/// What `download_from` achieved by the time it returned.
+#[derive(Debug)]
+pub struct DownloadSummary {
+    /// Indices of the pieces that were not downloaded, in ascending order.
+    pub incomplete_pieces: Vec<u32>,
+    /// The incomplete pieces that no connected peer had when the download
+    /// ended, in ascending order. If these are all of `incomplete_pieces`,
+    /// finishing the download needs more peers, not more time.
+    pub unavailable_pieces: Vec<u32>,
+    /// The incomplete pieces that repeatedly failed their hash check, in
+    /// ascending order, for which no peer in the swarm seems to have a good
+    /// copy, along with any that couldn't be written.
+    pub failed_pieces: Vec<u32>,
+    /// Where the download got to, for continuing it with `resume_from`.
+    pub state: DownloadState,
+}
+
+impl DownloadSummary {
+    pub fn is_complete(&self) -> bool {
+        self.incomplete_pieces.is_empty()
+    }
+}
+
+/// A snapshot of a download's pieces, for continuing it later, after a
+/// fresh announce or in another process. Pieces that were partly downloaded
+/// start over.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DownloadState {
+    /// The state of each piece, by index. A piece being downloaded counts
+    /// as available.
+    pub pieces: Vec<JobState>,
+    /// The number of times each piece failed its hash check, by index.
+    pub hash_failures: Vec<u32>,
+    pub downloaded_bytes: u64,
+    pub total_bytes: u64,
+}
+
+impl DownloadState {
+    pub fn new(pieces: &Vec<Piece>) -> DownloadState {
+        let len = pieces.iter().map(|x| x.index as usize + 1).max();
+        let mut state = DownloadState {
+            pieces: vec![JobState::Available; len.unwrap_or(0)],
+            hash_failures: vec![0; len.unwrap_or(0)],
+            ..Default::default()
+        };
+        for piece in pieces {
+            let index = piece.index as usize;
+            state.pieces[index] = match piece.job_state {
+                JobState::Downloading => JobState::Available,
+                job_state => job_state,
+            };
+            state.hash_failures[index] = piece.hash_failures;
+            state.total_bytes += piece.length;
+            if piece.job_state == JobState::Done {
+                state.downloaded_bytes += piece.length;
+            }
+        }
+
+        state
+    }
+
+    /// Puts `pieces` back the way they were when the snapshot was taken.
+    /// Pieces the snapshot doesn't cover are left alone.
+    pub fn restore(&self, pieces: &mut Vec<Piece>) {
+        for piece in pieces.iter_mut() {
+            let index = piece.index as usize;
+            match self.pieces.get(index) {
+                Some(JobState::Done) => piece.set_done(),
+                Some(&job_state) => {
+                    piece.job_state = job_state;
+                    piece.blocks = vec![false; piece.blocks.len()];
+                    piece.buffer = vec![];
+                }
+                None => continue,
+            }
+            piece.hash_failures =
+                self.hash_failures.get(index).cloned().unwrap_or(0);
         }
     }
 }
 
+/// Continues a download from `state`, as returned in an earlier
+/// `DownloadSummary`. Otherwise the same as `download_from`.
+pub fn resume_from(
+    state: &DownloadState,
+    pieces: &mut Vec<Piece>,
+    peers: Vec<Peer>,
+    new_peers: Receiver<Peer>,
+    info_hash: [u8; 20],
+    piece_len: u64,
+    progress: Arc<Mutex<Progress>>,
+    config: &DownloadConfig,
+    cancel: Arc<AtomicBool>,
+) -> DownloadSummary {
+    state.restore(pieces);
+    download_from(
+        pieces,
+        peers,
+        new_peers,
+        info_hash,
+        piece_len,
+        progress,
+        config,
+        &[],
+        cancel,
+    )
+}
+
+/// Downloads `pieces` from `peers`, plus any peers that turn up on
+/// `new_peers` while the download runs. Discovery mechanisms such as tracker
+/// re-announces feed the download by holding a clone of the matching
+/// `Sender`. Once every sender is dropped, the download ends when it runs
+/// out of connected peers.
 pub fn download_from(
     pieces: &mut Vec<Piece>,
     peers: Vec<Peer>,
+    new_peers: Receiver<Peer>,
     info_hash: [u8; 20],
     piece_len: u64,
-) {
-    let (to_me, from_worker) = mpsc::channel();
-    let mut workers = vec![];
-    for peer in peers {
-        let to_me = to_me.clone();
-        if let Some(worker) = Worker::new(peer, to_me, info_hash) {
-            workers.push(worker);
+    progress: Arc<Mutex<Progress>>,
+    config: &DownloadConfig,
+    have: &[bool],
+    cancel: Arc<AtomicBool>,
+) -> DownloadSummary {
+    for piece in pieces.iter_mut() {
+        if have.get(piece.index as usize) == Some(&true) {
+            piece.set_done();
         }
     }
+    *progress.lock().unwrap() = Progress::new(pieces);
+
+    let (to_me, from_worker) = mpsc::channel();
+    let mut workers: Vec<Worker> = vec![];
+    // Workers connect in the background, so slow peers don't hold up the
+    // rest.
+    let max_connecting = config.max_connecting.unwrap_or(MAX_CONNECTING);
+    let mut pending_peers: VecDeque<Peer> =
+        select_peers(config, peers).into_iter().collect();
+    let mut new_peers_open = true;
+    // Set after running out of file descriptors, until which no new
+    // connections are attempted.
+    let mut connect_paused_until = None;
+    // Every connected peer, shared with the workers for peer exchange.
+    let swarm = Arc::new(Mutex::new(vec![]));
+
+    let mut bitfield_sockets = vec![];
+    let mut jobs_given_out = false;
+    let mut writer = OutputWriter::new(&config.output);
+    let mut file_progress = FileProgress::new(&config.files, piece_len, pieces);
+    // Pieces finished before the download started are already written.
+    // It's indexed by piece index, and `pieces` may be only some of them.
+    let num_pieces = config.num_pieces.unwrap_or_else(|| {
+        pieces
+            .iter()
+            .map(|x| x.index as usize + 1)
+            .max()
+            .unwrap_or(0)
+    });
+    let mut written = vec![false; num_pieces];
+    for piece in pieces.iter().filter(|x| x.job_state == JobState::Done) {
+        written[piece.index as usize] = true;
+    }
+    let written = Arc::new(Mutex::new(written));
+    let total_len = config
+        .total_length
+        .unwrap_or_else(|| pieces.iter().map(|x| x.length).sum());
+    let mut idle_workers = vec![];
+    let unavailable_timeout =
+        config.unavailable_timeout.unwrap_or(UNAVAILABLE_TIMEOUT);
+    let max_reannounces = config.max_reannounces.unwrap_or(MAX_REANNOUNCES);
+    // When some piece was first seen with no peer to download it from, and
+    // how many announces have failed to find one since.
+    let mut unavailable_since = None;
+    let mut reannounces = 0;
+    let clock = config
+        .clock
+        .clone()
+        .unwrap_or_else(|| Arc::new(SystemClock));
+    let deadline = config.timeout.map(|timeout| clock.now() + timeout);
+    let stats_interval = config.stats_interval.unwrap_or(STATS_INTERVAL);
+    let mut stats_start = clock.now();
+    let mut bitfield_saved = clock.now();
+    let (mut stats_bytes, mut stats_pieces) = {
+        let progress = progress.lock().unwrap();
+        (progress.downloaded_bytes, progress.completed_pieces)
+    };
+    loop {
+        if clock.now() - bitfield_saved >= BITFIELD_INTERVAL
+            && config.sync_policy != SyncPolicy::OnCompletion
+        {
+            save_bitfield(config, &writer, &info_hash, &written);
+            bitfield_saved = clock.now();
+        }
+
+        let elapsed = clock.now() - stats_start;
+        if elapsed >= stats_interval {
+            let mut progress = progress.lock().unwrap();
+            let secs = elapsed.as_secs_f64();
+            progress.download_rate =
+                (progress.downloaded_bytes - stats_bytes) as f64 / secs;
+            progress.piece_rate =
+                (progress.completed_pieces - stats_pieces) as f64 / secs;
+            progress.connected_peers = connected_count(&workers);
+            progress.choked_peers = workers
+                .iter()
+                .filter(|x| x.is_connected() && x.peer_choking)
+                .count();
+            let remaining = progress.total_bytes - progress.downloaded_bytes;
+            progress.eta = if progress.download_rate > 0.0 {
+                Some(Duration::from_secs_f64(
+                    remaining as f64 / progress.download_rate,
+                ))
+            } else {
+                None
+            };
+            progress.min_availability = pieces
+                .iter()
+                .filter(|x| x.job_state != JobState::Done)
+                .map(|x| x.availability)
+                .min();
+
+            stats_start = clock.now();
+            stats_bytes = progress.downloaded_bytes;
+            stats_pieces = progress.completed_pieces;
+        }
+
+        // Availability is only known once every connected peer has sent its
+        // bitfield.
+        let unavailable = bitfield_sockets.len() == connected_count(&workers)
+            && pieces.iter().any(|x| {
+                x.availability == 0
+                    && x.job_state != JobState::Done
+                    && x.job_state != JobState::Failed
+            });
+        let since = if unavailable {
+            *unavailable_since.get_or_insert_with(|| clock.now())
+        } else {
+            unavailable_since = None;
+            reannounces = 0;
+            clock.now()
+        };
+        if unavailable && clock.now() - since >= unavailable_timeout {
+            match &config.reannounce {
+                Some(reannounce) if reannounces < max_reannounces => {
+                    println!("no peer has some pieces, announcing again");
+                    let _ = reannounce.send(());
+                    reannounces += 1;
+                    unavailable_since = Some(clock.now());
+                }
+                // Peers still downloading other pieces are left to finish.
+                Some(_) if idle_workers.len() == connected_count(&workers) => {
+                    println!("giving up on pieces no peer has");
+                    for worker in &workers {
+                        worker.stop();
+                    }
+                    break;
+                }
+                _ => {}
+            }
+        }
+
+        if cancel.load(atomic::Ordering::SeqCst) {
+            println!("download cancelled");
+            for worker in &workers {
+                worker.stop();
+            }
+            break;
+        }
+
+        if deadline.map_or(false, |deadline| clock.now() >= deadline) {
+            println!("download timed out");
+            for worker in &workers {
+                worker.stop();
+            }
+            break;
+        }
+
+        let mut received = vec![];
+        loop {
+            match new_peers.try_recv() {
+                Ok(peer) => received.push(peer),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    new_peers_open = false;
+                    break;
+                }
+            }
+        }
+        // Announces list the peers we're already connected to again. Their
+        // connections are kept rather than made again.
+        for peer in select_peers(config, received) {
+            if !is_known(&workers, &pending_peers, peer.socket) {
+                pending_peers.push_back(peer);
+            }
+        }
+        let connect_paused =
+            connect_paused_until.map_or(false, |until| clock.now() < until);
+        while !connect_paused
+            && workers.iter().filter(|x| !x.is_connected()).count()
+                < max_connecting
+            && config.max_peers.map_or(true, |max| workers.len() < max)
+        {
+            let peer = match pending_peers.pop_front() {
+                Some(peer) => peer,
+                None => break,
+            };
+            if config.blocklist.contains(&peer.socket.ip()) {
+                println!("Skipping blocked peer {}", peer.socket);
+                continue;
+            }
+            if workers.iter().any(|x| x.peer.socket == peer.socket) {
+                continue;
+            }
+            // Trackers list peers under each of their addresses, which all
+            // go to the one worker to be raced.
+            if peer.id.is_some() && workers.iter().any(|x| x.peer.id == peer.id)
+            {
+                continue;
+            }
+            let alternatives: Vec<SocketAddr> = match peer.id {
+                Some(_) => pending_peers
+                    .iter()
+                    .filter(|x| x.id == peer.id)
+                    .map(|x| x.socket)
+                    .filter(|x| !config.blocklist.contains(&x.ip()))
+                    .collect(),
+                None => vec![],
+            };
+            let slot = match &config.connection_limit {
+                Some(limit) => match limit.try_acquire() {
+                    Some(slot) => Some(slot),
+                    None => {
+                        pending_peers.push_front(peer);
+                        break;
+                    }
+                },
+                None => None,
+            };
+
+            if !alternatives.is_empty() {
+                pending_peers.retain(|x| x.id != peer.id);
+            }
+            workers.push(Worker::new(
+                peer,
+                alternatives,
+                to_me.clone(),
+                info_hash,
+                config,
+                Uploads {
+                    written: written.clone(),
+                    output: config.output.clone(),
+                    piece_len,
+                    total_len,
+                    am_choking: true,
+                    fast: false,
+                },
+                if config.disable_pex {
+                    None
+                } else {
+                    Some(swarm.clone())
+                },
+                slot,
+            ));
+        }
+
+        if workers.is_empty() && pending_peers.is_empty() && !new_peers_open {
+            println!("no peers left to download from");
+            break;
+        }
+
+        let msg = match from_worker.recv_timeout(Duration::from_millis(100)) {
+            Ok(msg) => msg,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
 
-    let mut num_bitfields = 0;
-    let mut pieces_table = vec![];
-    let mut size = 0;
-    let mut workers_done = 0;
-    for msg in from_worker {
         match msg {
-            WorkerMsg::Bitfield { socket, bitfield } => {
-                for (i, byte) in bitfield.iter().enumerate() {
-                    for j in 0..8 {
-                        if ((0b1000_0000 >> j) & byte) != 0 {
-                            pieces[i * 8 + j].peers.push(socket);
-                        }
+            WorkerMsg::Connected {
+                socket,
+                stream,
+                handshake,
+            } => {
+                progress.lock().unwrap().handshakes_succeeded += 1;
+                if let Some(sender) = &config.handshakes {
+                    let _ = sender.send(HandshakeResult {
+                        socket,
+                        outcome: Ok(handshake),
+                    });
+                }
+                if let Some(worker) =
+                    workers.iter_mut().find(|x| x.peer.socket == socket)
+                {
+                    worker.socket = Some(stream);
+                    swarm.lock().unwrap().push(socket);
+                }
+            }
+            WorkerMsg::HandshakeFailed {
+                socket,
+                reason,
+                out_of_files,
+            } => {
+                progress.lock().unwrap().handshakes_failed += 1;
+                if let Some(sender) = &config.handshakes {
+                    let _ = sender.send(HandshakeResult {
+                        socket,
+                        outcome: Err(reason),
+                    });
+                }
+                if let Some(position) =
+                    workers.iter().position(|x| x.peer.socket == socket)
+                {
+                    let worker = workers.remove(position);
+                    let _ = worker.thread.join();
+                    // The peer wasn't at fault, so it's tried again later.
+                    if out_of_files {
+                        println!(
+                            "out of file descriptors, pausing new connections"
+                        );
+                        connect_paused_until =
+                            Some(clock.now() + OUT_OF_FILES_BACKOFF);
+                        pending_peers.push_front(worker.peer);
                     }
                 }
+            }
+            WorkerMsg::Choke { socket, choking } => {
+                if let Some(worker) =
+                    workers.iter_mut().find(|x| x.peer.socket == socket)
+                {
+                    worker.peer_choking = choking;
+                }
+            }
+            WorkerMsg::Bitfield { socket, bitfield } => {
+                if let Some(worker) =
+                    workers.iter_mut().find(|x| x.peer.socket == socket)
+                {
+                    add_pieces(pieces, worker, &bitfield);
+                }
 
-                num_bitfields += 1;
-                if num_bitfields == workers.len() {
-                    pieces.sort();
-                    for worker in &workers {
-                        if !give_out_job(pieces, worker) {
-                            panic!("no more jobs. impossible");
+                if bitfield_sockets.contains(&socket) {
+                    // New pieces may be what an idle peer was waiting for.
+                    if idle_workers.contains(&socket) {
+                        let worker = workers
+                            .iter()
+                            .find(|&x| x.peer.socket == socket)
+                            .unwrap();
+                        if give_out_job(pieces, worker, &config.piece_order) {
+                            idle_workers.retain(|&x| x != socket);
                         }
                     }
+                } else if jobs_given_out {
+                    // A peer that joined after the first round of jobs.
+                    bitfield_sockets.push(socket);
+                    let worker = workers
+                        .iter()
+                        .find(|&x| x.peer.socket == socket)
+                        .unwrap();
+                    if !give_out_job(pieces, worker, &config.piece_order) {
+                        idle_workers.push(socket);
+                    }
+                } else {
+                    bitfield_sockets.push(socket);
+                    if bitfield_sockets.len() == connected_count(&workers) {
+                        give_out_first_jobs(
+                            pieces,
+                            &workers,
+                            &mut idle_workers,
+                            &config.piece_order,
+                        );
+                        jobs_given_out = true;
+                    }
                 }
             }
             WorkerMsg::Piece {
@@ -206,79 +1647,443 @@ pub fn download_from(
                 index,
                 buffer,
             } => {
-                size += buffer.len();
-                pieces_table.push((index, buffer));
-                if size > (2 << 30) {
-                    pieces_table.sort();
-                    match write_pieces_table_to_file(&pieces_table, piece_len) {
-                        Ok(_) => println!("wrote to file"),
-                        _ => println!("failed to write to file"),
+                let piece =
+                    pieces.iter_mut().find(|x| x.index == index).unwrap();
+                // Pieces are independent, so each is written as soon as it
+                // arrives, and only counts as done once it's on disk, so
+                // that completed files are there by the time anyone hears
+                // about them.
+                match writer.write_at(index as u64 * piece_len, &buffer) {
+                    Ok(_) => {
+                        piece.set_done();
+                        {
+                            let mut progress = progress.lock().unwrap();
+                            progress.completed_pieces += 1;
+                            progress.downloaded_bytes += piece.length;
+                        }
+                        written.lock().unwrap()[index as usize] = true;
+                        let completed_files = file_progress.piece_done(index);
+                        if let Some(sender) = &config.file_completed {
+                            for i in completed_files {
+                                let _ = sender.send(FileCompleted {
+                                    index: i,
+                                    path: config.files[i].0.clone(),
+                                });
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        println!("failed to write piece {}: {}", index, e);
+                        piece.blocks = vec![false; piece.blocks.len()];
+                        // Fewer connections leave descriptors for the output,
+                        // after which the piece can be downloaded again.
+                        // Other errors won't go away by themselves.
+                        if error::out_of_files(&e) {
+                            connect_paused_until =
+                                Some(clock.now() + OUT_OF_FILES_BACKOFF);
+                            piece.job_state = JobState::Available;
+                        } else {
+                            piece.job_state = JobState::Failed;
+                        }
+                    }
+                }
+                if let Some(worker) =
+                    workers.iter().find(|x| x.peer.socket == socket)
+                {
+                    if !give_out_job(pieces, worker, &config.piece_order) {
+                        idle_workers.push(socket);
                     }
-                    pieces_table.clear();
                 }
-                let worker =
-                    workers.iter().find(|&x| x.peer.socket == socket).unwrap();
-                let mut piece =
+            }
+            WorkerMsg::HashFailed { socket, index } => {
+                let piece =
                     pieces.iter_mut().find(|x| x.index == index).unwrap();
-                piece.job_state = JobState::Done;
-                if !give_out_job(pieces, worker) {
-                    workers_done += 1;
-                    if workers_done == workers.len() {
-                        for worker in &workers {
-                            worker.sender.send(ManagerMsg::Done).unwrap();
-                        }
-                        break;
+                piece.hash_failures += 1;
+                // Some block was bad, and there's no telling which, so start
+                // the piece over.
+                piece.blocks = vec![false; piece.blocks.len()];
+                println!(
+                    "piece {} from {} failed its hash check",
+                    index, socket
+                );
+                if piece.hash_failures >= MAX_HASH_FAILURES {
+                    println!("giving up on piece {}", index);
+                    piece.job_state = JobState::Failed;
+                } else {
+                    piece.job_state = JobState::Available;
+                }
+                if let Some(worker) =
+                    workers.iter().find(|x| x.peer.socket == socket)
+                {
+                    if !give_out_job(pieces, worker, &config.piece_order) {
+                        idle_workers.push(socket);
                     }
                 }
             }
+            WorkerMsg::Pex { added, dropped } => {
+                pending_peers.retain(|x| !dropped.contains(&x.socket));
+                let peers = added
+                    .iter()
+                    .map(|&(socket, _)| Peer { id: None, socket })
+                    .collect();
+                for peer in select_peers(config, peers) {
+                    let socket = peer.socket;
+                    if is_known(&workers, &pending_peers, socket) {
+                        continue;
+                    }
+
+                    // Seeds have every piece, so try them first.
+                    let flags =
+                        added.iter().find(|x| x.0 == socket).map_or(0, |x| x.1);
+                    if flags & pex::FLAG_SEED != 0 {
+                        pending_peers.push_front(peer);
+                    } else {
+                        pending_peers.push_back(peer);
+                    }
+                }
+            }
+            WorkerMsg::Disconnected { socket, jobs } => {
+                let bitfield = workers
+                    .iter()
+                    .find(|x| x.peer.socket == socket)
+                    .map(|x| x.bitfield.clone())
+                    .unwrap_or_default();
+                for piece in pieces.iter_mut() {
+                    if bitfield.get(piece.index as usize) {
+                        piece.availability -= 1;
+                    }
+                    if piece.job_state != JobState::Downloading {
+                        continue;
+                    }
+                    if let Some(job) =
+                        jobs.iter().find(|x| x.index == piece.index)
+                    {
+                        piece.job_state = JobState::Available;
+                        piece.blocks = job.blocks.clone();
+                        piece.buffer = job.buffer.clone();
+                    }
+                }
+
+                if let Some(position) =
+                    workers.iter().position(|x| x.peer.socket == socket)
+                {
+                    let worker = workers.remove(position);
+                    let _ = worker.thread.join();
+                }
+                swarm.lock().unwrap().retain(|&x| x != socket);
+                bitfield_sockets.retain(|&x| x != socket);
+                idle_workers.retain(|&x| x != socket);
+
+                if workers.is_empty() {
+                    continue;
+                }
+
+                if !jobs_given_out {
+                    if bitfield_sockets.len() == connected_count(&workers) {
+                        give_out_first_jobs(
+                            pieces,
+                            &workers,
+                            &mut idle_workers,
+                            &config.piece_order,
+                        );
+                        jobs_given_out = true;
+                    }
+                    continue;
+                }
+
+                idle_workers.retain(|&socket| {
+                    let worker = workers
+                        .iter()
+                        .find(|&x| x.peer.socket == socket)
+                        .unwrap();
+                    !give_out_job(pieces, worker, &config.piece_order)
+                });
+            }
+        }
+
+        // With every peer idle, more peers are the only way to make
+        // progress, so keep waiting while some might still arrive.
+        let finished = pieces.iter().all(|x| {
+            x.job_state == JobState::Done || x.job_state == JobState::Failed
+        });
+        let out_of_peers = !new_peers_open
+            && pending_peers.is_empty()
+            && connected_count(&workers) == workers.len();
+        let all_idle = jobs_given_out
+            && connected_count(&workers) > 0
+            && idle_workers.len() == connected_count(&workers);
+        {
+            let mut progress = progress.lock().unwrap();
+            let stalled = all_idle && !finished;
+            if stalled && !progress.stalled {
+                println!(
+                    "no connected peer has any of the {} remaining pieces",
+                    progress.total_pieces - progress.completed_pieces
+                );
+            }
+            progress.stalled = stalled;
+        }
+        if all_idle && (finished || out_of_peers) {
+            for worker in &workers {
+                let _ = worker.sender.send(ManagerMsg::Done);
+            }
+            break;
+        }
+    }
+
+    if config.bitfield_file.is_some() {
+        save_bitfield(config, &writer, &info_hash, &written);
+    } else if config.sync_policy != SyncPolicy::Never {
+        if let Err(e) = writer.sync() {
+            println!("failed to sync output: {}", e);
+        }
+    }
+
+    // Workers still connecting can take a while to notice the download has
+    // ended, so they're left to finish on their own.
+    for worker in workers.into_iter().filter(|x| x.is_connected()) {
+        let _ = worker.thread.join();
+    }
+
+    let mut incomplete_pieces: Vec<u32> = pieces
+        .iter()
+        .filter(|piece| piece.job_state != JobState::Done)
+        .map(|piece| piece.index)
+        .collect();
+    incomplete_pieces.sort();
+    let unavailable_pieces = incomplete_pieces
+        .iter()
+        .cloned()
+        .filter(|&index| {
+            pieces.iter().any(|piece| {
+                piece.index == index
+                    && piece.availability == 0
+                    && piece.job_state != JobState::Failed
+            })
+        })
+        .collect();
+    let mut failed_pieces: Vec<u32> = pieces
+        .iter()
+        .filter(|piece| piece.job_state == JobState::Failed)
+        .map(|piece| piece.index)
+        .collect();
+    failed_pieces.sort();
+    DownloadSummary {
+        incomplete_pieces,
+        unavailable_pieces,
+        failed_pieces,
+        state: DownloadState::new(pieces),
+    }
+}
+
+/// Saves which pieces are written to the `bitfield_file`, if there is one,
+/// once they're safely on disk or, under `SyncPolicy::Never`, once they've
+/// been handed to the operating system.
+fn save_bitfield(
+    config: &DownloadConfig,
+    writer: &OutputWriter,
+    info_hash: &[u8; 20],
+    written: &Mutex<Vec<bool>>,
+) {
+    let path = match &config.bitfield_file {
+        Some(path) => path,
+        None => return,
+    };
+    // Pieces written after the snapshot are left for the next save.
+    let written = written.lock().unwrap().clone();
+    let synced = match config.sync_policy {
+        SyncPolicy::Never => Ok(()),
+        _ => writer.sync(),
+    };
+    let saved =
+        synced.and_then(|_| resume::save_bitfield(path, info_hash, &written));
+    if let Err(e) = saved {
+        println!("failed to save {}: {}", path.display(), e);
+    }
+}
+
+/// Writes pieces to an `Output` as they arrive. Each file is opened on its
+/// first write and kept open, so pieces spanning several files don't
+/// reopen any of them.
+struct OutputWriter<'a> {
+    output: &'a Output,
+    /// Handles on the output's files, by index.
+    files: Vec<Option<File>>,
+}
+
+impl<'a> OutputWriter<'a> {
+    fn new(output: &'a Output) -> OutputWriter<'a> {
+        OutputWriter {
+            output,
+            files: vec![],
+        }
+    }
+
+    fn write_at(&mut self, offset: u64, bytes: &[u8]) -> std::io::Result<()> {
+        let output = self.output;
+        match output {
+            Output::File(path) => self.file(0, path)?.write_at(offset, bytes),
+            Output::Files(files) => {
+                let spans = file_spans(files, offset, bytes.len())?;
+                for (i, file_offset, start, end) in spans {
+                    self.file(i, &files[i].0)?
+                        .write_at(file_offset, &bytes[start..end])?;
+                }
+                Ok(())
+            }
+            Output::Memory(buffer) => {
+                buffer.lock().unwrap().write_at(offset, bytes)
+            }
+        }
+    }
+
+    /// The handle on file `index` of the output, which is at `path`,
+    /// opening it if this is its first write.
+    fn file(
+        &mut self,
+        index: usize,
+        path: &Path,
+    ) -> std::io::Result<&mut File> {
+        if self.files.len() <= index {
+            self.files.resize_with(index + 1, || None);
+        }
+        if self.files[index].is_none() {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            self.files[index] =
+                Some(OpenOptions::new().write(true).create(true).open(path)?);
         }
+
+        Ok(self.files[index].as_mut().unwrap())
     }
 
-    match write_pieces_table_to_file(&pieces_table, piece_len) {
-        Ok(_) => println!("wrote to file"),
-        _ => println!("failed to write to file"),
+    /// Makes sure everything written is on disk.
+    fn sync(&self) -> std::io::Result<()> {
+        for file in self.files.iter().flatten() {
+            file.sync_all()?;
+        }
+
+        Ok(())
     }
-    pieces_table.clear();
+}
 
-    for worker in workers {
-        let _ = worker.thread.join();
+/// The parts of `files` that the `length` bytes at `offset` fall in, as
+/// `(file index, offset in file, start, end)` with `start..end` the range
+/// of the bytes that goes in that file. An error if the bytes run past the
+/// last file.
+fn file_spans(
+    files: &[(PathBuf, u64)],
+    offset: u64,
+    length: usize,
+) -> std::io::Result<Vec<(usize, u64, usize, usize)>> {
+    let end = offset + length as u64;
+    let mut spans = vec![];
+    let mut file_start = 0;
+    for (i, (_, file_len)) in files.iter().enumerate() {
+        let file_end = file_start + file_len;
+        let span_start = cmp::max(offset, file_start);
+        let span_end = cmp::min(end, file_end);
+        if span_start < span_end {
+            spans.push((
+                i,
+                span_start - file_start,
+                (span_start - offset) as usize,
+                (span_end - offset) as usize,
+            ));
+        }
+        file_start = file_end;
+    }
+
+    if end > file_start {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "block is past the end of the output",
+        ));
     }
+    Ok(spans)
 }
 
-fn write_pieces_table_to_file(
-    pieces_table: &Vec<(u32, Vec<u8>)>,
-    piece_length: u64,
-) -> std::io::Result<()> {
-    let mut f = OpenOptions::new().write(true).create(true).open("part")?;
-    for (index, buffer) in pieces_table {
-        let index: u64 = *index as u64;
-        f.seek(SeekFrom::Start(index * piece_length))?;
-        f.write_all(&buffer)?;
+pub(crate) fn read_block(
+    output: &Output,
+    offset: u64,
+    length: usize,
+) -> std::io::Result<Vec<u8>> {
+    let mut block = vec![0u8; length];
+    match output {
+        Output::File(path) => {
+            let mut f = File::open(path)?;
+            f.seek(SeekFrom::Start(offset))?;
+            f.read_exact(&mut block)?;
+        }
+        Output::Files(files) => {
+            for (i, file_offset, start, end) in
+                file_spans(files, offset, length)?
+            {
+                let mut f = File::open(&files[i].0)?;
+                f.seek(SeekFrom::Start(file_offset))?;
+                f.read_exact(&mut block[start..end])?;
+            }
+        }
+        Output::Memory(buffer) => {
+            let buffer = buffer.lock().unwrap();
+            let range = usize::try_from(offset)
+                .ok()
+                .and_then(|start| buffer.get(start..start + length));
+            match range {
+                Some(range) => block.copy_from_slice(range),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "block is past the end of the output",
+                    ))
+                }
+            }
+        }
     }
 
-    f.sync_all()?;
-    Ok(())
+    Ok(block)
 }
 
-fn read_msg(
-    stream: &mut TcpStream,
-) -> Result<Option<PeerMsg>, Box<std::error::Error>> {
+/// Reads the next message, or `None` for a keep-alive or a read that timed
+/// out before a message started.
+fn read_msg(stream: &mut PeerStream) -> Result<Option<PeerMsg>, Error> {
     let mut len = [0u8; 4];
-    let mut len_bytes_read = 0;
+    let mut len_bytes_read = match stream.read(&mut len) {
+        Err(ref e) if timed_out(e) => return Ok(None),
+        result => result?,
+    };
+    if len_bytes_read == 0 {
+        return Err(From::from("reached eof while reading message length"));
+    }
     while len_bytes_read < 4 {
-        len_bytes_read += stream.read(&mut len[len_bytes_read..])?;
+        let bytes_read = read_some(stream, &mut len[len_bytes_read..])?;
+        if bytes_read == 0 {
+            return Err(From::from("reached eof while reading message length"));
+        }
+        len_bytes_read += bytes_read;
     }
     let len = unsafe { std::mem::transmute::<[u8; 4], u32>(len) }.to_be();
     if len == 0 {
         return Ok(None);
     }
+    if len > MAX_MSG_LEN {
+        return Err(From::from(format!(
+            "message of {} bytes is too long",
+            len
+        )));
+    }
 
     let len = len - 1;
 
     let mut id = [0u8];
     let mut id_bytes_read = 0;
-    while id_bytes_read != 1 {
-        id_bytes_read = stream.read(&mut id)?;
+    while id_bytes_read < 1 {
+        let bytes_read = read_some(stream, &mut id[id_bytes_read..])?;
+        if bytes_read == 0 {
+            return Err(From::from("reached eof while reading message id"));
+        }
+        id_bytes_read += bytes_read;
     }
     let id = id[0];
 
@@ -287,8 +2092,12 @@ fn read_msg(
         let mut buffer = vec![0u8; len as usize];
         let mut payload_bytes_read: usize = 0;
         while payload_bytes_read < len as usize {
-            payload_bytes_read +=
-                stream.read(&mut buffer[payload_bytes_read..])?;
+            let bytes_read =
+                read_some(stream, &mut buffer[payload_bytes_read..])?;
+            if bytes_read == 0 {
+                return Err(From::from("reached eof while reading payload"));
+            }
+            payload_bytes_read += bytes_read;
         }
         payload = Some(buffer);
     }
@@ -296,15 +2105,134 @@ fn read_msg(
     Ok(Some(PeerMsg { id, payload }))
 }
 
+/// Reads what the peer has sent so far. Once a message has started, timing
+/// out only means the rest is slow to arrive, so the read is retried.
+fn read_some(stream: &mut PeerStream, buffer: &mut [u8]) -> io::Result<usize> {
+    loop {
+        match stream.read(buffer) {
+            Err(ref e) if timed_out(e) => continue,
+            result => return result,
+        }
+    }
+}
+
+fn timed_out(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
+
+fn connect(
+    socket: &SocketAddr,
+    bind_addr: Option<IpAddr>,
+) -> std::io::Result<TcpStream> {
+    let timeout = Duration::from_secs(17);
+    let bind_addr = match bind_addr {
+        Some(bind_addr) => bind_addr,
+        None => return TcpStream::connect_timeout(socket, timeout),
+    };
+
+    let domain = match socket {
+        SocketAddr::V4(_) => Domain::ipv4(),
+        SocketAddr::V6(_) => Domain::ipv6(),
+    };
+    let s = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+    s.bind(&SockAddr::from(SocketAddr::new(bind_addr, 0)))?;
+    s.connect_timeout(&SockAddr::from(*socket), timeout)?;
+    Ok(s.into_tcp_stream())
+}
+
+/// Connects to whichever of `sockets`, all addresses of one peer, answers
+/// first, returning the connection and the address it's to. Attempts start
+/// `CONNECT_DELAY` apart, or as soon as the one before fails, alternating
+/// between IPv6 and IPv4 so that a broken network on one doesn't hold up
+/// the connection (RFC 8305's happy eyeballs).
+fn connect_any(
+    sockets: &[SocketAddr],
+    bind_addr: Option<IpAddr>,
+) -> std::io::Result<(TcpStream, SocketAddr)> {
+    if let [socket] = sockets {
+        return Ok((connect(socket, bind_addr)?, *socket));
+    }
+
+    let (v6, v4): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        sockets.iter().partition(|x| x.is_ipv6());
+    let mut order = vec![];
+    for i in 0..cmp::max(v6.len(), v4.len()) {
+        order.extend(v6.get(i));
+        order.extend(v4.get(i));
+    }
+
+    let (results, attempts) = mpsc::channel();
+    let mut order = order.into_iter().peekable();
+    let mut running = 0;
+    let mut last_error = None;
+    loop {
+        if let Some(socket) = order.next() {
+            let results = results.clone();
+            // Connections that lose the race are closed when dropped.
+            thread::spawn(move || {
+                let _ = results.send((connect(&socket, bind_addr), socket));
+            });
+            running += 1;
+        }
+        if running == 0 {
+            break;
+        }
+
+        // Every attempt sends its result, so waiting can't outlast them.
+        let result = match order.peek() {
+            Some(_) => attempts.recv_timeout(CONNECT_DELAY).ok(),
+            None => attempts.recv().ok(),
+        };
+        match result {
+            Some((Ok(stream), socket)) => return Ok((stream, socket)),
+            Some((Err(e), _)) => {
+                running -= 1;
+                last_error = Some(e);
+            }
+            None => {}
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "no addresses to connect to",
+        )
+    }))
+}
+
+/// Connects to `peer`, at whichever of `sockets` answers first, and
+/// exchanges handshakes.
 fn handshake(
     peer: &Peer,
+    sockets: &[SocketAddr],
     info_hash: &[u8; 20],
-) -> Result<TcpStream, Box<std::error::Error>> {
-    let mut stream =
-        TcpStream::connect_timeout(&peer.socket, Duration::from_secs(17))?;
+    bind_addr: Option<IpAddr>,
+    encryption: Encryption,
+    reserved: [u8; 8],
+) -> Result<(PeerStream, Handshake), Error> {
+    let (stream, socket) = connect_any(sockets, bind_addr)?;
+    let mut stream = match encryption {
+        Encryption::Disabled => PeerStream::plaintext(stream),
+        Encryption::Required => mse::handshake(stream, info_hash, true)?,
+        Encryption::Preferred => {
+            match mse::handshake(stream, info_hash, false) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    // The peer has most likely dropped the connection, so
+                    // start over with a fresh one.
+                    println!(
+                        "Encrypted handshake with {} failed, retrying in \
+                         plaintext: {}",
+                        peer.socket, e
+                    );
+                    PeerStream::plaintext(connect(&socket, bind_addr)?)
+                }
+            }
+        }
+    };
     let pstr = "BitTorrent protocol".as_bytes();
     let pstrlen = pstr.len() as u8;
-    let reserved = [0u8; 8];
     let id = "01234567890123456789".as_bytes();
 
     let mut buffer = vec![];
@@ -329,46 +2257,117 @@ fn handshake(
         n += bytes_read;
 
         if n == buffer.len() {
-            let response_peer_id =
-                &response[id_offset..id_offset + peer.id.len()];
-            if response_peer_id != peer.id {
-                return Err(From::from("ids don't match"));
+            let response_peer_id = &response[id_offset..id_offset + 20];
+            if let Some(id) = peer.id {
+                if response_peer_id != id {
+                    return Err(From::from("ids don't match"));
+                }
             }
 
             println!("Completed handshake with {}", peer.socket.to_string());
-            return Ok(stream);
+            let mut peer_id = [0u8; 20];
+            peer_id.copy_from_slice(response_peer_id);
+            // An extension is only in use if both sides set its bit.
+            let theirs = &response[pstrlen as usize + 1..];
+            let handshake = Handshake {
+                peer_id,
+                extensions: reserved[5] & theirs[5] & 0x10 != 0,
+                fast: reserved[7] & theirs[7] & 0x04 != 0,
+                encrypted: stream.is_encrypted(),
+            };
+            return Ok((stream, handshake));
         }
     }
 }
 
 fn handle_peer_msg(
-    stream: &mut TcpStream,
+    stream: &mut PeerStream,
     peer: &Peer,
     peer_msg: PeerMsg,
     to_manager: &Sender<WorkerMsg>,
     from_manager: &Receiver<ManagerMsg>,
     job_queue: &mut VecDeque<Job>,
-    piece_buffer: &mut Vec<u8>,
+    extensions: &mut Extensions,
+    request_queue: &mut RequestQueue,
+    uploads: &mut Uploads,
+    dht_nodes: &Option<Sender<SocketAddr>>,
 ) -> ThreadState {
     match peer_msg.id {
-        1 => request_block(stream, job_queue),
-        5 => handle_bitfield_msg(
-            stream,
-            peer,
-            peer_msg,
-            to_manager,
-            from_manager,
-            job_queue,
-        ),
+        0 => {
+            to_manager
+                .send(WorkerMsg::Choke {
+                    socket: peer.socket,
+                    choking: true,
+                })
+                .unwrap();
+            handle_choke_msg(job_queue, request_queue)
+        }
+        1 => {
+            to_manager
+                .send(WorkerMsg::Choke {
+                    socket: peer.socket,
+                    choking: false,
+                })
+                .unwrap();
+            request_queue.peer_choking = false;
+            request_blocks(stream, job_queue, request_queue)
+        }
+        2 => handle_interested_msg(stream, peer, uploads),
+        // Requests are answered as they arrive, so there's nothing queued
+        // to cancel.
+        3 | 8 => ThreadState::Alive,
+        4 => {
+            let num_pieces = uploads.written.lock().unwrap().len();
+            handle_have_msg(peer, peer_msg, to_manager, num_pieces)
+        }
+        5 => {
+            let num_pieces = uploads.written.lock().unwrap().len();
+            let payload = peer_msg.payload.unwrap_or_default();
+            handle_bitfield_msg(
+                stream,
+                peer,
+                Bitfield::from_bytes(&payload, num_pieces),
+                to_manager,
+                from_manager,
+                job_queue,
+                request_queue,
+            )
+        }
+        6 => handle_request_msg(stream, peer, peer_msg, uploads),
         7 => handle_piece_msg(
             stream,
             peer,
             peer_msg,
             job_queue,
-            piece_buffer,
             from_manager,
             to_manager,
+            request_queue,
         ),
+        9 => handle_port_msg(peer, peer_msg, dht_nodes),
+        // Suggestions and allowed fast pieces are only hints.
+        0x0D | 0x11 => ThreadState::Alive,
+        // have all, have none
+        0x0E | 0x0F => {
+            let num_pieces = uploads.written.lock().unwrap().len();
+            let bitfield = if peer_msg.id == 0x0E {
+                Bitfield::full(num_pieces)
+            } else {
+                Bitfield::new(num_pieces)
+            };
+            handle_bitfield_msg(
+                stream,
+                peer,
+                bitfield,
+                to_manager,
+                from_manager,
+                job_queue,
+                request_queue,
+            )
+        }
+        0x10 => handle_reject_msg(stream, peer_msg, job_queue, request_queue),
+        extension::EXTENDED_MSG_ID => {
+            handle_extended_msg(peer, peer_msg, to_manager, extensions)
+        }
         id => {
             println!("unhandled request {}", id);
             return ThreadState::Alive;
@@ -376,76 +2375,362 @@ fn handle_peer_msg(
     }
 }
 
-fn request_block(
-    stream: &mut TcpStream,
+/// A choking peer discards the requests it hasn't answered, so the blocks
+/// they were for have to be requested again once it unchokes. With the fast
+/// extension they're rejected one by one instead.
+fn handle_choke_msg(
     job_queue: &mut VecDeque<Job>,
+    request_queue: &mut RequestQueue,
 ) -> ThreadState {
-    if let Some(Job {
-        index,
-        length,
-        downloaded_length,
-        hash: _,
-    }) = job_queue.front()
-    {
-        let mut buffer = vec![];
-        let length_left = length - downloaded_length;
-        let req_len: u32 = cmp::min(length_left as u32, 2 << 14);
-        let begin = *downloaded_length as u32;
+    request_queue.peer_choking = true;
+    if request_queue.fast {
+        return ThreadState::Alive;
+    }
+    request_queue.outstanding = 0;
+    for job in job_queue.iter_mut() {
+        job.requested = job.blocks.clone();
+    }
+
+    ThreadState::Alive
+}
+
+/// The peer won't answer one of our requests, so its block is requested
+/// again, once the peer unchokes us if it's choking.
+fn handle_reject_msg(
+    stream: &mut PeerStream,
+    peer_msg: PeerMsg,
+    job_queue: &mut VecDeque<Job>,
+    request_queue: &mut RequestQueue,
+) -> ThreadState {
+    let payload = match peer_msg.payload {
+        Some(ref payload) if payload.len() == 12 => payload,
+        _ => return ThreadState::Alive,
+    };
+    let index =
+        u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+    let begin =
+        u32::from_be_bytes([payload[4], payload[5], payload[6], payload[7]]);
+    let block_index = (begin as u64 / BLOCK_SIZE) as usize;
 
-        let len: [u8; 4] = unsafe { std::mem::transmute(13_u32.to_be()) };
-        let index: [u8; 4] = unsafe { std::mem::transmute(index.to_be()) };
-        let begin: [u8; 4] = unsafe { std::mem::transmute(begin.to_be()) };
-        let req_len: [u8; 4] = unsafe { std::mem::transmute(req_len.to_be()) };
+    if let Some(job) = job_queue.iter_mut().find(|x| x.index == index) {
+        if job.requested.get(block_index) == Some(&true)
+            && !job.blocks[block_index]
+        {
+            job.requested[block_index] = false;
+            request_queue.outstanding =
+                request_queue.outstanding.saturating_sub(1);
+        }
+    }
+
+    request_blocks(stream, job_queue, request_queue)
+}
+
+/// Tops the peer's request queue up with blocks of the current job, as long
+/// as we're interested and the peer isn't choking us.
+fn request_blocks(
+    stream: &mut PeerStream,
+    job_queue: &mut VecDeque<Job>,
+    request_queue: &mut RequestQueue,
+) -> ThreadState {
+    if !request_queue.can_request() {
+        return ThreadState::Alive;
+    }
+    if let Some(job) = job_queue.front_mut() {
+        while request_queue.outstanding < request_queue.depth {
+            let block_index = match job.requested.iter().position(|&x| !x) {
+                Some(block_index) => block_index,
+                None => break,
+            };
+            // Parsed torrents keep pieces within 32 bits, but a `Piece` can
+            // be made with any length.
+            let begin = match u32::try_from(block_index as u64 * BLOCK_SIZE) {
+                Ok(begin) => begin,
+                Err(_) => {
+                    println!("Piece {} is too long to request", job.index);
+                    break;
+                }
+            };
+            // At most `BLOCK_SIZE`, so always fits.
+            let req_len = block_length(job.length, block_index) as u32;
+
+            let mut buffer = vec![];
+            buffer.extend(&13u32.to_be_bytes());
+            buffer.push(6u8);
+            buffer.extend(&job.index.to_be_bytes());
+            buffer.extend(&begin.to_be_bytes());
+            buffer.extend(&req_len.to_be_bytes());
+            if let Err(e) = stream.write_all(&buffer) {
+                println!("Failed to request block: {}", e);
+                break;
+            }
 
-        buffer.extend(&len);
-        buffer.push(6u8);
-        buffer.extend(&index);
-        buffer.extend(&begin);
-        buffer.extend(&req_len);
-        stream.write(&buffer).unwrap();
+            job.requested[block_index] = true;
+            request_queue.outstanding += 1;
+        }
     }
 
     ThreadState::Alive
 }
 
 fn handle_bitfield_msg(
-    stream: &mut TcpStream,
+    stream: &mut PeerStream,
+    peer: &Peer,
+    bitfield: Bitfield,
+    to_manager: &Sender<WorkerMsg>,
+    from_manager: &Receiver<ManagerMsg>,
+    job_queue: &mut VecDeque<Job>,
+    request_queue: &mut RequestQueue,
+) -> ThreadState {
+    to_manager
+        .send(WorkerMsg::Bitfield {
+            socket: peer.socket,
+            bitfield,
+        })
+        .unwrap();
+    // A bitfield after `have` messages may come with a job underway.
+    if job_queue.is_empty() {
+        return next_job(
+            stream,
+            from_manager,
+            JOB_WAIT,
+            job_queue,
+            request_queue,
+        );
+    }
+
+    ThreadState::Alive
+}
+
+/// Passes a piece the peer now has on to the manager, as a bitfield with
+/// only that piece set.
+fn handle_have_msg(
     peer: &Peer,
     peer_msg: PeerMsg,
     to_manager: &Sender<WorkerMsg>,
+    num_pieces: usize,
+) -> ThreadState {
+    let payload = match peer_msg.payload {
+        Some(ref payload) if payload.len() == 4 => payload,
+        _ => return ThreadState::Alive,
+    };
+    let index =
+        u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]])
+            as usize;
+    if index >= num_pieces {
+        println!("{} has piece {}, which doesn't exist", peer.socket, index);
+        return ThreadState::Alive;
+    }
+
+    let mut bitfield = Bitfield::new(num_pieces);
+    bitfield.set(index);
+    to_manager
+        .send(WorkerMsg::Bitfield {
+            socket: peer.socket,
+            bitfield,
+        })
+        .unwrap();
+
+    ThreadState::Alive
+}
+
+/// Takes the next job from the manager, waiting up to `wait` for it, and
+/// starts requesting its blocks. Without one the worker stays idle, and
+/// checks again between messages from the peer.
+fn next_job(
+    stream: &mut PeerStream,
     from_manager: &Receiver<ManagerMsg>,
+    wait: Duration,
     job_queue: &mut VecDeque<Job>,
+    request_queue: &mut RequestQueue,
 ) -> ThreadState {
-    if let Some(payload) = peer_msg.payload {
-        to_manager
-            .send(WorkerMsg::Bitfield {
-                socket: peer.socket,
-                bitfield: payload,
-            })
-            .unwrap();
-        match from_manager.recv() {
-            Ok(ManagerMsg::JobMsg {
-                index,
-                length,
-                hash,
-            }) => {
-                job_queue.push_back(Job {
-                    index,
-                    length,
-                    downloaded_length: 0,
-                    hash,
-                });
-            }
-            _ => panic!("impossible"),
+    match from_manager.recv_timeout(wait) {
+        Ok(ManagerMsg::JobMsg {
+            index,
+            length,
+            hash,
+            blocks,
+            buffer,
+        }) => {
+            job_queue.push_back(Job::new(index, length, hash, blocks, buffer));
         }
+        Ok(ManagerMsg::Done) | Err(RecvTimeoutError::Disconnected) => {
+            return ThreadState::Dead;
+        }
+        Err(RecvTimeoutError::Timeout) => return ThreadState::Alive,
+    }
 
+    if !request_queue.am_interested {
         let interested = vec![0u8, 0u8, 0u8, 1u8, 2u8];
-        stream.write(&interested).unwrap();
+        if let Err(e) = stream.write_all(&interested) {
+            println!("Failed to send interested: {}", e);
+            return ThreadState::Alive;
+        }
+        request_queue.am_interested = true;
+    }
+    // The peer may have unchoked us already.
+    request_blocks(stream, job_queue, request_queue)
+}
+
+/// Unchokes an interested peer, as long as we have something to give it.
+fn handle_interested_msg(
+    stream: &mut PeerStream,
+    peer: &Peer,
+    uploads: &mut Uploads,
+) -> ThreadState {
+    if !uploads.am_choking
+        || !uploads.written.lock().unwrap().iter().any(|&x| x)
+    {
+        return ThreadState::Alive;
+    }
+
+    let unchoke = vec![0u8, 0u8, 0u8, 1u8, 1u8];
+    if let Err(e) = stream.write_all(&unchoke) {
+        println!("Failed to unchoke {}: {}", peer.socket, e);
+        return ThreadState::Alive;
+    }
+    uploads.am_choking = false;
+
+    ThreadState::Alive
+}
+
+/// Serves a block the peer requested, once it's checked to be within a
+/// piece we have. Bad requests are logged and dropped.
+fn handle_request_msg(
+    stream: &mut PeerStream,
+    peer: &Peer,
+    peer_msg: PeerMsg,
+    uploads: &mut Uploads,
+) -> ThreadState {
+    // Choked peers' requests are ignored, or rejected with the fast
+    // extension.
+    let payload = peer_msg.payload.unwrap_or_default();
+    if uploads.am_choking {
+        return reject_request(stream, peer, &payload, uploads);
+    }
+    let (index, begin, length) = match uploads.check_request(&payload) {
+        Ok(request) => request,
+        Err(e) => {
+            println!("Rejecting request from {}: {}", peer.socket, e);
+            return reject_request(stream, peer, &payload, uploads);
+        }
+    };
+
+    let offset = index as u64 * uploads.piece_len + begin as u64;
+    let block = match read_block(&uploads.output, offset, length as usize) {
+        Ok(block) => block,
+        Err(e) => {
+            println!("Failed to read block for {}: {}", peer.socket, e);
+            return ThreadState::Alive;
+        }
+    };
+    let mut buffer = vec![];
+    buffer.extend(&(block.len() as u32 + 9).to_be_bytes());
+    buffer.push(7u8);
+    buffer.extend(&index.to_be_bytes());
+    buffer.extend(&begin.to_be_bytes());
+    buffer.extend(&block);
+    if let Err(e) = stream.write_all(&buffer) {
+        println!("Failed to send block to {}: {}", peer.socket, e);
+    }
+
+    ThreadState::Alive
+}
+
+/// Sends a `reject request` for `payload` if the fast extension is in use.
+/// Otherwise the request is dropped silently.
+fn reject_request(
+    stream: &mut PeerStream,
+    peer: &Peer,
+    payload: &[u8],
+    uploads: &Uploads,
+) -> ThreadState {
+    // Only a well-formed request can be echoed back.
+    if !uploads.fast || payload.len() != 12 {
+        return ThreadState::Alive;
+    }
+
+    let mut buffer = vec![];
+    buffer.extend(&13u32.to_be_bytes());
+    buffer.push(0x10);
+    buffer.extend(payload);
+    if let Err(e) = stream.write_all(&buffer) {
+        println!("Failed to reject request from {}: {}", peer.socket, e);
+    }
+
+    ThreadState::Alive
+}
+
+/// Passes on the DHT node a peer advertises in a `port` message.
+fn handle_port_msg(
+    peer: &Peer,
+    peer_msg: PeerMsg,
+    dht_nodes: &Option<Sender<SocketAddr>>,
+) -> ThreadState {
+    let port = match peer_msg.payload {
+        Some(ref payload) if payload.len() == 2 => {
+            u16::from_be_bytes([payload[0], payload[1]])
+        }
+        _ => return ThreadState::Alive,
+    };
+    if let Some(dht_nodes) = dht_nodes {
+        // Nobody listening for nodes isn't a reason to drop the peer.
+        let _ = dht_nodes.send(SocketAddr::new(peer.socket.ip(), port));
+    }
+
+    ThreadState::Alive
+}
+
+fn handle_extended_msg(
+    peer: &Peer,
+    peer_msg: PeerMsg,
+    to_manager: &Sender<WorkerMsg>,
+    extensions: &mut Extensions,
+) -> ThreadState {
+    let payload = match peer_msg.payload {
+        Some(ref payload) => payload,
+        None => return ThreadState::Alive,
+    };
+
+    match extensions.dispatch(payload) {
+        Ok(None) => {}
+        Ok(Some((pex::NAME, payload))) => match pex::parse(payload) {
+            Ok(msg) => {
+                let _ = to_manager.send(WorkerMsg::Pex {
+                    added: msg.added,
+                    dropped: msg.dropped,
+                });
+            }
+            Err(e) => println!("Bad peers from {}: {}", peer.socket, e),
+        },
+        Ok(Some((name, _))) => println!("unhandled extended message {}", name),
+        Err(e) => println!("Bad extended message from {}: {}", peer.socket, e),
     }
 
     ThreadState::Alive
 }
 
+fn handle_disconnect(
+    peer: &Peer,
+    to_manager: &Sender<WorkerMsg>,
+    job_queue: &mut VecDeque<Job>,
+) -> ThreadState {
+    // The manager may already be gone if the download finished, so a failed
+    // send is not an error here.
+    let _ = to_manager.send(WorkerMsg::Disconnected {
+        socket: peer.socket,
+        jobs: job_queue
+            .drain(..)
+            .map(|job| PartialPiece {
+                index: job.index,
+                blocks: job.blocks,
+                buffer: job.buffer,
+            })
+            .collect(),
+    });
+
+    ThreadState::Dead
+}
+
 #[derive(PartialEq)]
 enum ThreadState {
     Dead,
@@ -453,86 +2738,205 @@ enum ThreadState {
 }
 
 fn handle_piece_msg(
-    stream: &mut TcpStream,
+    stream: &mut PeerStream,
     peer: &Peer,
     peer_msg: PeerMsg,
     job_queue: &mut VecDeque<Job>,
-    piece_buffer: &mut Vec<u8>,
     from_manager: &Receiver<ManagerMsg>,
     to_manager: &Sender<WorkerMsg>,
+    request_queue: &mut RequestQueue,
 ) -> ThreadState {
-    if let Some(payload) = peer_msg.payload {
-        let payload = &payload[8..];
-        piece_buffer.extend(payload);
-        if let Some(Job {
-            index,
-            length,
-            downloaded_length,
-            hash,
-        }) = job_queue.front_mut()
-        {
-            *downloaded_length = *downloaded_length + payload.len() as u64;
-            let length_left = *length - *downloaded_length;
-            if length_left != 0 {
-                request_block(stream, job_queue);
-            } else {
-                if *hash == sha1::Sha1::from(&piece_buffer).digest().bytes() {
-                    to_manager
-                        .send(WorkerMsg::Piece {
-                            socket: peer.socket,
-                            index: *index,
-                            buffer: piece_buffer.to_vec(),
-                        })
-                        .unwrap();
-                    piece_buffer.clear();
-                    match from_manager.recv() {
-                        Ok(ManagerMsg::JobMsg {
-                            index,
-                            length,
-                            hash,
-                        }) => {
-                            job_queue.push_back(Job {
-                                index,
-                                length,
-                                downloaded_length: 0,
-                                hash,
-                            });
-                        }
-                        Ok(ManagerMsg::Done) => {
-                            return ThreadState::Dead;
-                        }
-                        _ => panic!("impossible"),
-                    }
-                    job_queue.pop_front();
-                    request_block(stream, job_queue);
-                } else {
-                    *downloaded_length = 0;
-                    request_block(stream, job_queue);
-                }
-            }
-        }
+    let payload = match peer_msg.payload {
+        Some(ref payload) if payload.len() >= 8 => payload,
+        _ => return ThreadState::Alive,
+    };
+    let index =
+        u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+    let begin =
+        u32::from_be_bytes([payload[4], payload[5], payload[6], payload[7]]);
+    let block = &payload[8..];
+    request_queue.received(block.len());
+
+    let job = match job_queue.front_mut() {
+        Some(job) if job.index == index => job,
+        // A block of a piece we're no longer downloading.
+        _ => return ThreadState::Alive,
+    };
+    if !job.add_block(begin as u64, block) {
+        return request_blocks(stream, job_queue, request_queue);
     }
 
-    ThreadState::Alive
+    // Either way the manager decides what to download next, since a piece
+    // that keeps failing may be given up on.
+    let digest = mem::replace(&mut job.hasher, Sha1::new()).finalize();
+    if job.hash[..] == digest[..] {
+        to_manager
+            .send(WorkerMsg::Piece {
+                socket: peer.socket,
+                index,
+                buffer: mem::replace(&mut job.buffer, vec![]),
+            })
+            .unwrap();
+    } else {
+        to_manager
+            .send(WorkerMsg::HashFailed {
+                socket: peer.socket,
+                index,
+            })
+            .unwrap();
+    }
+    job_queue.pop_front();
+
+    next_job(stream, from_manager, JOB_WAIT, job_queue, request_queue)
+}
+
+fn give_out_first_jobs(
+    pieces: &mut Vec<Piece>,
+    workers: &Vec<Worker>,
+    idle_workers: &mut Vec<SocketAddr>,
+    order: &PieceOrder,
+) {
+    pieces.sort();
+    for worker in workers.iter().filter(|x| x.is_connected()) {
+        if !give_out_job(pieces, worker, order) {
+            idle_workers.push(worker.peer.socket);
+        }
+    }
 }
 
-fn give_out_job(pieces: &mut Vec<Piece>, worker: &Worker) -> bool {
+/// Adds the pieces in `bitfield` to those `worker`'s peer is known to have,
+/// counting each towards its availability once.
+fn add_pieces(
+    pieces: &mut Vec<Piece>,
+    worker: &mut Worker,
+    bitfield: &Bitfield,
+) {
     for piece in pieces.iter_mut() {
-        if piece.job_state == JobState::Available {
-            if piece.peers.iter().any(|&x| x == worker.peer.socket) {
-                worker
-                    .sender
-                    .send(ManagerMsg::JobMsg {
-                        index: piece.index,
-                        length: piece.length,
-                        hash: piece.hash,
-                    })
-                    .unwrap();
-                piece.job_state = JobState::Downloading;
-                return true;
-            }
+        let index = piece.index as usize;
+        if bitfield.get(index) && !worker.bitfield.get(index) {
+            piece.availability += 1;
+        }
+    }
+    worker.bitfield.union(bitfield);
+}
+
+fn connected_count(workers: &Vec<Worker>) -> usize {
+    workers.iter().filter(|x| x.is_connected()).count()
+}
+
+/// The handshake's reserved bytes, with a bit set for each extension
+/// `config` enables.
+fn reserved_bytes(config: &DownloadConfig) -> [u8; 8] {
+    let mut reserved = [0u8; 8];
+    // The extension protocol only carries peer exchange so far.
+    if !config.disable_pex {
+        reserved[5] |= 0x10;
+    }
+    if config.dht_nodes.is_some() {
+        reserved[7] |= 0x01;
+    }
+    if !config.disable_fast {
+        reserved[7] |= 0x04;
+    }
+
+    reserved
+}
+
+/// The messages telling a newly connected peer which pieces we have. With the
+/// fast extension that's `have all` or `have none` where they say it all.
+/// Otherwise it's a bitfield, which is optional and so left out when empty.
+/// A lazy bitfield leaves out up to `LAZY_PIECES` random pieces, which are
+/// sent as `have` messages after it.
+fn have_msgs(written: &[bool], fast: bool, lazy: bool) -> Vec<u8> {
+    let mut have: Vec<usize> =
+        (0..written.len()).filter(|&x| written[x]).collect();
+    let mut buffer = vec![];
+    if have.is_empty() {
+        if fast {
+            buffer.extend(&1u32.to_be_bytes());
+            buffer.push(0x0F);
+        }
+        return buffer;
+    }
+    if fast && !lazy && have.len() == written.len() {
+        buffer.extend(&1u32.to_be_bytes());
+        buffer.push(0x0E);
+        return buffer;
+    }
+
+    let mut written = written.to_vec();
+    let mut held_back = vec![];
+    if lazy {
+        have.shuffle(&mut rand::thread_rng());
+        held_back = have.into_iter().take(LAZY_PIECES).collect();
+        for &index in &held_back {
+            written[index] = false;
         }
     }
 
-    false
+    let bitfield = Bitfield::from_pieces(&written);
+    let bitfield = bitfield.as_bytes();
+    buffer.extend(&(bitfield.len() as u32 + 1).to_be_bytes());
+    buffer.push(5u8);
+    buffer.extend(bitfield);
+    for index in held_back {
+        buffer.extend(&5u32.to_be_bytes());
+        buffer.push(4u8);
+        buffer.extend(&(index as u32).to_be_bytes());
+    }
+
+    buffer
+}
+
+fn give_out_job(
+    pieces: &mut Vec<Piece>,
+    worker: &Worker,
+    order: &PieceOrder,
+) -> bool {
+    let candidates = pieces.iter().enumerate().filter(|(_, piece)| {
+        piece.job_state == JobState::Available
+            && worker.bitfield.get(piece.index as usize)
+    });
+    // Availability changes as peers come and go, so the rarest is found
+    // now. `min_by_key` keeps the first of equal pieces, so ties in the
+    // other orders go to what was rarest when the download started.
+    let position = match order {
+        PieceOrder::RarestFirst => candidates
+            .min_by_key(|(_, piece)| piece.availability)
+            .map(|(i, _)| i),
+        PieceOrder::Sequential => candidates
+            .min_by_key(|(_, piece)| piece.index)
+            .map(|(i, _)| i),
+        PieceOrder::Priorities(priorities) => {
+            let priorities = priorities.lock().unwrap();
+            candidates
+                .min_by_key(|(_, piece)| {
+                    let priority = priorities.get(piece.index as usize);
+                    cmp::Reverse(priority.cloned().unwrap_or(0))
+                })
+                .map(|(i, _)| i)
+        }
+    };
+
+    let piece = match position {
+        Some(position) => &mut pieces[position],
+        None => return false,
+    };
+    let sent = worker.sender.send(ManagerMsg::JobMsg {
+        index: piece.index,
+        length: piece.length,
+        hash: piece.hash,
+        blocks: piece.blocks.clone(),
+        buffer: mem::replace(&mut piece.buffer, vec![]),
+    });
+    // The worker has gone, and will be cleaned up once its disconnect
+    // arrives. The piece stays available for someone else.
+    if let Err(SendError(msg)) = sent {
+        if let ManagerMsg::JobMsg { buffer, .. } = msg {
+            piece.buffer = buffer;
+        }
+        return false;
+    }
+    piece.job_state = JobState::Downloading;
+    true
 }
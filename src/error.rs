@@ -0,0 +1,143 @@
+use crate::bencode::DecodeError;
+use std::error;
+use std::fmt;
+use std::io;
+use std::net::AddrParseError;
+use std::num::ParseIntError;
+use std::str::Utf8Error;
+
+/// Every error the crate returns.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Http(reqwest::Error),
+    Url(url::ParseError),
+    Utf8(Utf8Error),
+    AddrParse(AddrParseError),
+    ParseInt(ParseIntError),
+    Bencode(DecodeError),
+    /// The `failure reason` a tracker refused an announce with.
+    TrackerFailure(String),
+    /// Malformed input or a protocol violation, described by the message.
+    Msg(String),
+}
+
+impl Error {
+    /// Whether the error is a network operation timing out, such as an
+    /// announce to a tracker that didn't answer in time.
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            Error::Io(e) => e.kind() == io::ErrorKind::TimedOut,
+            Error::Http(e) => e.is_timeout(),
+            _ => false,
+        }
+    }
+
+    /// Whether the error is the process or the system running out of file
+    /// descriptors, which clears up as other connections and files close.
+    pub fn is_out_of_files(&self) -> bool {
+        match self {
+            Error::Io(e) => out_of_files(e),
+            _ => false,
+        }
+    }
+}
+
+/// `EMFILE` or `ENFILE`, which have the same numbers on every Unix.
+#[cfg(unix)]
+pub(crate) fn out_of_files(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(24) || e.raw_os_error() == Some(23)
+}
+
+/// `WSAEMFILE`.
+#[cfg(windows)]
+pub(crate) fn out_of_files(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(10024)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Http(e) => write!(f, "{}", e),
+            Error::Url(e) => write!(f, "{}", e),
+            Error::Utf8(e) => write!(f, "{}", e),
+            Error::AddrParse(e) => write!(f, "{}", e),
+            Error::ParseInt(e) => write!(f, "{}", e),
+            Error::Bencode(e) => write!(f, "{}", e),
+            Error::TrackerFailure(reason) => {
+                write!(f, "tracker failed: {}", reason)
+            }
+            Error::Msg(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Http(e) => Some(e),
+            Error::Url(e) => Some(e),
+            Error::Utf8(e) => Some(e),
+            Error::AddrParse(e) => Some(e),
+            Error::ParseInt(e) => Some(e),
+            Error::Bencode(e) => Some(e),
+            Error::TrackerFailure(_) | Error::Msg(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Error {
+        Error::Http(e)
+    }
+}
+
+impl From<url::ParseError> for Error {
+    fn from(e: url::ParseError) -> Error {
+        Error::Url(e)
+    }
+}
+
+impl From<Utf8Error> for Error {
+    fn from(e: Utf8Error) -> Error {
+        Error::Utf8(e)
+    }
+}
+
+impl From<AddrParseError> for Error {
+    fn from(e: AddrParseError) -> Error {
+        Error::AddrParse(e)
+    }
+}
+
+impl From<ParseIntError> for Error {
+    fn from(e: ParseIntError) -> Error {
+        Error::ParseInt(e)
+    }
+}
+
+impl From<DecodeError> for Error {
+    fn from(e: DecodeError) -> Error {
+        Error::Bencode(e)
+    }
+}
+
+impl From<String> for Error {
+    fn from(msg: String) -> Error {
+        Error::Msg(msg)
+    }
+}
+
+impl<'a> From<&'a str> for Error {
+    fn from(msg: &'a str) -> Error {
+        Error::Msg(msg.to_string())
+    }
+}
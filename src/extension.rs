@@ -0,0 +1,123 @@
+//! The extension protocol (BEP 10), which extensions such as peer exchange
+//! are carried over. Each side lists the extensions it supports in its
+//! extension handshake, along with the id it wants their messages sent
+//! with, so the ids differ between directions and between peers.
+
+use crate::bencode::{self, dict_val, int_val, BencodeVal};
+use crate::Error;
+use std::collections::{BTreeMap, HashMap};
+use std::str;
+
+/// Peer wire message id shared by every extension message.
+pub const EXTENDED_MSG_ID: u8 = 20;
+/// Extended message id of the extension handshake.
+pub const HANDSHAKE_ID: u8 = 0;
+
+/// The extensions negotiated with one peer.
+pub struct Extensions {
+    /// The extensions we support, in the order of the ids we give them,
+    /// starting from 1.
+    local: Vec<&'static str>,
+    /// The ids the peer wants messages of each extension it supports sent
+    /// with.
+    remote: HashMap<String, u8>,
+}
+
+impl Extensions {
+    pub fn new(local: &[&'static str]) -> Extensions {
+        Extensions {
+            local: local.to_vec(),
+            remote: HashMap::new(),
+        }
+    }
+
+    /// Our extension handshake as a full peer wire message.
+    pub fn handshake_msg(&self) -> Vec<u8> {
+        let mut m = BTreeMap::new();
+        for (i, name) in self.local.iter().enumerate() {
+            m.insert(name.as_bytes(), int_val(i as i64 + 1));
+        }
+        let mut dict = BTreeMap::new();
+        dict.insert("m".as_bytes(), dict_val(m));
+
+        msg(HANDSHAKE_ID, &bencode::encode(&dict_val(dict)))
+    }
+
+    /// Takes in the ids from the peer's extension handshake, replacing any
+    /// from an earlier one.
+    pub fn handle_handshake(&mut self, payload: &[u8]) -> Result<(), Error> {
+        let val = bencode::decode(payload)?;
+        let m = match val.path(&[b"m"]) {
+            Some(BencodeVal::Dict {
+                index: _,
+                size: _,
+                dict,
+            }) => dict,
+            Some(_) => return Err(From::from("m should be a dict")),
+            None => return Ok(()),
+        };
+
+        self.remote.clear();
+        for (name, id) in m {
+            // An id of 0 means the peer has turned the extension off.
+            if let (
+                Ok(name),
+                BencodeVal::Int {
+                    index: _,
+                    size: _,
+                    int,
+                },
+            ) = (str::from_utf8(name), id)
+            {
+                if *int > 0 && *int <= 255 {
+                    self.remote.insert(name.to_string(), *int as u8);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The id to send messages of extension `name` with, if the peer
+    /// supports it.
+    pub fn remote_id(&self, name: &str) -> Option<u8> {
+        self.remote.get(name).cloned()
+    }
+
+    /// A message of extension `name` as a full peer wire message, if the
+    /// peer supports the extension.
+    pub fn msg(&self, name: &str, payload: &[u8]) -> Option<Vec<u8>> {
+        Some(msg(self.remote_id(name)?, payload))
+    }
+
+    /// Works out which extension the payload of an extended message is
+    /// for, returning its name and the message within. A handshake is taken
+    /// in, and gives `None`.
+    pub fn dispatch<'a>(
+        &mut self,
+        payload: &'a [u8],
+    ) -> Result<Option<(&'static str, &'a [u8])>, Error> {
+        let (&id, rest) = payload
+            .split_first()
+            .ok_or("extended message should have an id")?;
+        if id == HANDSHAKE_ID {
+            self.handle_handshake(rest)?;
+            return Ok(None);
+        }
+
+        // Peers only use the ids we gave out in our handshake.
+        match self.local.get(id as usize - 1) {
+            Some(name) => Ok(Some((name, rest))),
+            None => Err(From::from(format!("unknown extension id {}", id))),
+        }
+    }
+}
+
+/// An extended message with id `id` as a full peer wire message.
+fn msg(id: u8, payload: &[u8]) -> Vec<u8> {
+    let mut msg = (payload.len() as u32 + 2).to_be_bytes().to_vec();
+    msg.push(EXTENDED_MSG_ID);
+    msg.push(id);
+    msg.extend(payload);
+    msg
+}
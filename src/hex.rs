@@ -0,0 +1,144 @@
+//! Serializes hashes as lowercase hex strings.
+
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::Serializer;
+
+pub fn serialize<S: Serializer>(
+    hash: &[u8; 20],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&encode(hash))
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<[u8; 20], D::Error> {
+    let s = String::deserialize(deserializer)?;
+    let mut hash = [0u8; 20];
+    decode(&s, &mut hash).map_err(de::Error::custom)?;
+    Ok(hash)
+}
+
+/// Concatenated SHA-1 hashes, as a list of hex hashes.
+pub mod concatenated {
+    use super::{decode, encode};
+    use serde::de::{self, Deserialize, Deserializer};
+    use serde::ser::{SerializeSeq, Serializer};
+    use std::borrow::Cow;
+
+    pub fn serialize<S: Serializer>(
+        hashes: &Cow<[u8]>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(hashes.len() / 20))?;
+        for hash in hashes.chunks(20) {
+            seq.serialize_element(&encode(hash))?;
+        }
+        seq.end()
+    }
+
+    pub fn deserialize<'de, 'a, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Cow<'a, [u8]>, D::Error> {
+        let strings = Vec::<String>::deserialize(deserializer)?;
+        let mut hashes = vec![0u8; strings.len() * 20];
+        for (s, hash) in strings.iter().zip(hashes.chunks_mut(20)) {
+            decode(s, hash).map_err(de::Error::custom)?;
+        }
+        Ok(Cow::Owned(hashes))
+    }
+}
+
+/// SHA-256 piece layers, as a map from hex root to a list of hex leaves.
+pub mod layers {
+    use super::{decode, encode};
+    use serde::de::{self, Deserialize, Deserializer};
+    use serde::ser::{SerializeMap, Serializer};
+    use std::collections::HashMap;
+
+    pub fn serialize<S: Serializer>(
+        layers: &HashMap<[u8; 32], Vec<[u8; 32]>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(layers.len()))?;
+        for (root, hashes) in layers {
+            let hashes: Vec<String> =
+                hashes.iter().map(|hash| encode(hash)).collect();
+            map.serialize_entry(&encode(root), &hashes)?;
+        }
+        map.end()
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<HashMap<[u8; 32], Vec<[u8; 32]>>, D::Error> {
+        let strings =
+            HashMap::<String, Vec<String>>::deserialize(deserializer)?;
+        let mut layers = HashMap::with_capacity(strings.len());
+        for (root, leaves) in strings {
+            let mut hashes = Vec::with_capacity(leaves.len());
+            for s in leaves {
+                let mut hash = [0u8; 32];
+                decode(&s, &mut hash).map_err(de::Error::custom)?;
+                hashes.push(hash);
+            }
+            let mut hash = [0u8; 32];
+            decode(&root, &mut hash).map_err(de::Error::custom)?;
+            layers.insert(hash, hashes);
+        }
+        Ok(layers)
+    }
+}
+
+/// An optional SHA-256 hash, such as a file's `pieces root`, in hex.
+pub mod optional_root {
+    use super::{decode, encode};
+    use serde::de::{self, Deserialize, Deserializer};
+    use serde::ser::Serializer;
+
+    pub fn serialize<S: Serializer>(
+        root: &Option<[u8; 32]>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match root {
+            Some(root) => serializer.serialize_some(&encode(root)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<[u8; 32]>, D::Error> {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(s) => {
+                let mut root = [0u8; 32];
+                decode(&s, &mut root).map_err(de::Error::custom)?;
+                Ok(Some(root))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+fn encode(hash: &[u8]) -> String {
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode(s: &str, hash: &mut [u8]) -> Result<(), String> {
+    if s.len() != hash.len() * 2 || !s.is_ascii() {
+        return Err(format!(
+            "expected {} hex digits, got {:?}",
+            hash.len() * 2,
+            s
+        ));
+    }
+
+    for (i, byte) in hash.iter_mut().enumerate() {
+        *byte = match u8::from_str_radix(&s[i * 2..i * 2 + 2], 16) {
+            Ok(byte) => byte,
+            Err(_) => return Err(format!("invalid hex digits in {:?}", s)),
+        };
+    }
+
+    Ok(())
+}
@@ -1,215 +1,652 @@
-extern crate reqwest;
+#[cfg(feature = "progress-bar")]
+extern crate indicatif;
+extern crate socket2;
 extern crate torrent;
 
-mod bencode;
-
-use std::net::{IpAddr, SocketAddr, TcpListener};
-use std::str::{self, FromStr};
+#[cfg(feature = "progress-bar")]
+use indicatif::{HumanBytes, HumanDuration, ProgressBar, ProgressStyle};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{
+    IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, UdpSocket,
+};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
+#[cfg(feature = "progress-bar")]
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 use std::{env, fs, process};
-use torrent::download::{self, Peer};
-use torrent::TorrentMetaInfo;
-use url::percent_encoding::{percent_encode, DEFAULT_ENCODE_SET};
-use url::Url;
+use torrent::blocklist::Blocklist;
+use torrent::download;
+use torrent::resume;
+use torrent::session::Session;
+use torrent::tracker;
+use torrent::{TorrentMetaInfo, MAX_PIECES};
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
+    if args.len() < 2 {
         println!("Please pass a torrent filename");
         process::exit(1);
     }
+    if args[1] == "--daemon" {
+        let socket = match args.get(2) {
+            Some(socket) => PathBuf::from(socket),
+            None => {
+                println!("Please pass a socket path to listen on");
+                process::exit(1);
+            }
+        };
+        let dir = args.get(3).map(PathBuf::from).unwrap_or_default();
+        if let Err(e) = daemon(&socket, dir) {
+            println!("{}", e);
+        }
+        return;
+    }
+
+    let mut config = download::DownloadConfig::default();
+    let mut output_dir = None;
+    let mut strict = false;
+    let mut max_pieces = MAX_PIECES;
+    let mut part_files = true;
+    let mut i = 2;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--bind" if i + 1 < args.len() => {
+                match IpAddr::from_str(&args[i + 1]) {
+                    Ok(addr) => config.bind_addr = Some(addr),
+                    Err(e) => {
+                        println!("Invalid bind address: {}", e);
+                        process::exit(1);
+                    }
+                }
+                i += 2;
+            }
+            "--encryption" if i + 1 < args.len() => {
+                config.encryption = match args[i + 1].as_str() {
+                    "disabled" => download::Encryption::Disabled,
+                    "preferred" => download::Encryption::Preferred,
+                    "required" => download::Encryption::Required,
+                    mode => {
+                        println!("Unknown encryption mode: {}", mode);
+                        process::exit(1);
+                    }
+                };
+                i += 2;
+            }
+            "--sync" if i + 1 < args.len() => {
+                config.sync_policy = match args[i + 1].as_str() {
+                    "always" => download::SyncPolicy::Always,
+                    "on-completion" => download::SyncPolicy::OnCompletion,
+                    "never" => download::SyncPolicy::Never,
+                    policy => {
+                        println!("Unknown sync policy: {}", policy);
+                        process::exit(1);
+                    }
+                };
+                i += 2;
+            }
+            "--timeout" if i + 1 < args.len() => {
+                match args[i + 1].parse() {
+                    Ok(secs) => {
+                        config.timeout = Some(Duration::from_secs(secs))
+                    }
+                    Err(e) => {
+                        println!("Invalid timeout: {}", e);
+                        process::exit(1);
+                    }
+                }
+                i += 2;
+            }
+            "--announce-port" if i + 1 < args.len() => {
+                match args[i + 1].parse() {
+                    Ok(port) => config.announce_port = Some(port),
+                    Err(e) => {
+                        println!("Invalid announce port: {}", e);
+                        process::exit(1);
+                    }
+                }
+                i += 2;
+            }
+            "--announce-retries" if i + 1 < args.len() => {
+                match args[i + 1].parse() {
+                    Ok(retries) => config.announce_retries = Some(retries),
+                    Err(e) => {
+                        println!("Invalid announce retries: {}", e);
+                        process::exit(1);
+                    }
+                }
+                i += 2;
+            }
+            "--max-peers" if i + 1 < args.len() => {
+                match args[i + 1].parse() {
+                    Ok(max) => config.max_peers = Some(max),
+                    Err(e) => {
+                        println!("Invalid max peers: {}", e);
+                        process::exit(1);
+                    }
+                }
+                i += 2;
+            }
+            "--max-connections" if i + 1 < args.len() => {
+                match args[i + 1].parse() {
+                    Ok(max) => {
+                        config.connection_limit =
+                            Some(download::ConnectionLimit::new(max))
+                    }
+                    Err(e) => {
+                        println!("Invalid max connections: {}", e);
+                        process::exit(1);
+                    }
+                }
+                i += 2;
+            }
+            "--max-pieces" if i + 1 < args.len() => {
+                match args[i + 1].parse() {
+                    Ok(max) => max_pieces = max,
+                    Err(e) => {
+                        println!("Invalid max pieces: {}", e);
+                        process::exit(1);
+                    }
+                }
+                i += 2;
+            }
+            "-o" | "--output" if i + 1 < args.len() => {
+                output_dir = Some(PathBuf::from(&args[i + 1]));
+                i += 2;
+            }
+            "--strict" => {
+                strict = true;
+                i += 1;
+            }
+            "--no-listen" => {
+                config.disable_listener = true;
+                i += 1;
+            }
+            "--no-part-files" => {
+                part_files = false;
+                i += 1;
+            }
+            "--lazy-bitfield" => {
+                config.lazy_bitfield = true;
+                i += 1;
+            }
+            "--blocklist" if i + 1 < args.len() => {
+                let blocklist = fs::read_to_string(&args[i + 1])
+                    .map_err(|e| e.to_string())
+                    .and_then(|x| {
+                        Blocklist::parse(&x).map_err(|e| e.to_string())
+                    });
+                match blocklist {
+                    Ok(blocklist) => config.blocklist = blocklist,
+                    Err(e) => {
+                        println!("Invalid blocklist: {}", e);
+                        process::exit(1);
+                    }
+                }
+                i += 2;
+            }
+            arg => {
+                println!("Unknown argument: {}", arg);
+                process::exit(1);
+            }
+        }
+    }
 
-    if let Err(e) = run(&args[1]) {
+    if let Err(e) = run(
+        &args[1],
+        &config,
+        output_dir.as_ref(),
+        strict,
+        max_pieces,
+        part_files,
+    ) {
         println!("{}", e);
     }
 }
 
-fn run(s: &str) -> std::io::Result<()> {
+/// Downloads the torrents added to a `Session` until killed, taking the
+/// commands of `Session::command` a line at a time over the Unix socket at
+/// `socket`. Torrents download into `dir`.
+#[cfg(unix)]
+fn daemon(socket: &Path, dir: PathBuf) -> std::io::Result<()> {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixListener;
+
+    let listener = UnixListener::bind(socket)?;
+    println!("listening for commands on {}", socket.display());
+    let session = Arc::new(Mutex::new(Session::new(
+        dir,
+        download::DownloadConfig::default(),
+    )));
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                println!("control connection failed: {}", e);
+                continue;
+            }
+        };
+        let session = session.clone();
+        thread::spawn(move || {
+            let lines = match stream.try_clone() {
+                Ok(reader) => BufReader::new(reader).lines(),
+                Err(_) => return,
+            };
+            for line in lines {
+                let reply = match line {
+                    Ok(line) => session.lock().unwrap().command(&line),
+                    Err(_) => return,
+                };
+                if stream.write_all(reply.as_bytes()).is_err() {
+                    return;
+                }
+            }
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn daemon(_: &Path, _: PathBuf) -> std::io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "daemon mode needs Unix sockets",
+    ))
+}
+
+fn run(
+    s: &str,
+    config: &download::DownloadConfig,
+    output_dir: Option<&PathBuf>,
+    strict: bool,
+    max_pieces: usize,
+    part_files: bool,
+) -> std::io::Result<()> {
     let contents = fs::read(s)?;
-    let metainfo = match TorrentMetaInfo::new(&contents) {
+    let metainfo = match TorrentMetaInfo::with_max_pieces(&contents, max_pieces)
+    {
         Ok(m) => m,
         Err(e) => {
             println!("{}", e);
             return Ok(());
         }
     };
+    if strict {
+        if let Err(e) = metainfo.info.validate_strict() {
+            println!("{}", e);
+            return Ok(());
+        }
+    }
+    for warning in metainfo.info.validate() {
+        println!("warning: {}", warning);
+    }
 
+    // Pieces run across file boundaries, so they're laid out over the
+    // concatenation of every file. Zero-length files take up no pieces.
+    let total_length = metainfo.info.total_length();
     let mut pieces = vec![];
-    let mut total_length = 0;
-    if let Some(length) = metainfo.info.length {
-        total_length = length;
-        let mut index = 0;
-        for _ in 0..total_length / metainfo.info.piece_length {
-            pieces.push(download::Piece::new(
-                index,
-                metainfo.info.piece_length,
-                metainfo.info.pieces[index as usize],
-            ));
-            index += 1;
-        }
-        let remainder = total_length % metainfo.info.piece_length;
-        if remainder != 0 {
-            pieces.push(download::Piece::new(
-                index,
-                remainder,
-                metainfo.info.pieces[index as usize],
-            ));
+    let mut index = 0;
+    for _ in 0..total_length / metainfo.info.piece_length {
+        pieces.push(download::Piece::new(
+            index,
+            metainfo.info.piece_length,
+            metainfo.info.piece_hash(index).unwrap(),
+        ));
+        index += 1;
+    }
+    let remainder = total_length % metainfo.info.piece_length;
+    if remainder != 0 {
+        pieces.push(download::Piece::new(
+            index,
+            remainder,
+            metainfo.info.piece_hash(index).unwrap(),
+        ));
+    }
+    // Without an output directory, data goes to the working directory as
+    // before. Single-file torrents are named after the torrent, and the
+    // files of multi-file torrents go in a directory named after it.
+    let mut config = config.clone();
+    let dir = match output_dir {
+        Some(dir) => dir.clone(),
+        None => PathBuf::new(),
+    };
+    let layout = match download::Output::for_torrent(&metainfo.info, &dir) {
+        Ok(layout) => layout,
+        Err(e) => {
+            println!("{}", e);
+            return Ok(());
         }
-    } else if let Some(files) = metainfo.info.files {
-        for file in files {
-            total_length += file.length;
-            let mut index = 0;
-            for _ in 0..file.length / metainfo.info.piece_length {
-                pieces.push(download::Piece::new(
-                    index,
-                    metainfo.info.piece_length,
-                    metainfo.info.pieces[index as usize],
-                ));
-                index += 1;
-            }
-            let remainder = file.length % metainfo.info.piece_length;
-            if remainder != 0 {
-                pieces.push(download::Piece::new(
-                    index,
-                    remainder,
-                    metainfo.info.pieces[index as usize],
-                ));
-            }
+    };
+    if let Some(dir) = output_dir {
+        fs::create_dir_all(dir).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!(
+                    "can't create output directory {}: {}",
+                    dir.display(),
+                    e
+                ),
+            )
+        })?;
+    }
+    layout.create_empty_files()?;
+    config.output = layout;
+
+    // A previous run's sidecar says which pieces are already written;
+    // without one, whatever is in the output is hashed to find out.
+    let mut have = vec![];
+    let output_path = match &config.output {
+        download::Output::File(path) => Some(path.clone()),
+        download::Output::Files(_) => Some(dir.join(metainfo.info.name)),
+        download::Output::Memory(_) => None,
+    };
+    // Files are only given their own names once they're complete.
+    let finished_output = config.output.clone();
+    if part_files {
+        config.output = resume::part_output(&finished_output);
+    }
+    if let Some(path) = &output_path {
+        let sidecar = resume::sidecar_path(path);
+        let known = resume::load_bitfield(
+            &sidecar,
+            &metainfo.info.hash,
+            metainfo.info.num_pieces(),
+        );
+        if known.is_none() && path.exists() {
+            println!("no usable {}, checking existing data", sidecar.display());
         }
+        have = resume::check_pieces(
+            &pieces,
+            metainfo.info.num_pieces(),
+            metainfo.info.piece_length,
+            &config.output,
+            &known.unwrap_or_default(),
+        );
+        config.bitfield_file = Some(sidecar);
     }
     println!("piece length: {}", metainfo.info.piece_length);
     println!("total_length: {}", total_length);
 
-    let addrs: Vec<SocketAddr> = (6881..6889)
-        .into_iter()
-        .map(|x| SocketAddr::from(([127, 0, 0, 1], x)))
-        .collect();
-    let listener = TcpListener::bind(&addrs[..])?;
-    let port = listener.local_addr().unwrap().port();
-
-    let info_hash = format!(
-        "info_hash={}",
-        percent_encode(&metainfo.info.hash, DEFAULT_ENCODE_SET).to_string()
-    );
-    let peer_id = format!("peer_id={}", "01234567890123456789");
-    let port = format!("port={}", port.to_string());
-    let uploaded = format!("uploaded={}", "0");
-    let downloaded = format!("downloaded={}", "0");
-    let left = format!("left={}", total_length.to_string());
-    let compact = "compact=1".to_string();
-
-    let params = vec![
-        info_hash, peer_id, port, uploaded, downloaded, left, compact,
-    ];
-    let params = params.join("&");
-
-    let mut url = Url::parse(metainfo.announce).unwrap();
-    url.set_query(Some(&params));
-
-    let mut body = reqwest::get(url).unwrap();
-    let mut body_buf: Vec<u8> = vec![];
-    body.copy_to(&mut body_buf).unwrap();
+    // Without a bind address, listen on every address, IPv4 and IPv6 where
+    // the host has it.
+    let mut listeners = vec![];
+    if !config.disable_listener {
+        match config.bind_addr {
+            Some(ip) => listeners.push(listen(ip, None)?),
+            None => {
+                let v4 = listen(IpAddr::from(Ipv4Addr::UNSPECIFIED), None)?;
+                let port = v4.local_addr()?.port();
+                listeners.push(v4);
+                match listen(IpAddr::from(Ipv6Addr::UNSPECIFIED), Some(port)) {
+                    Ok(v6) => listeners.push(v6),
+                    Err(e) => println!("Not listening on IPv6: {}", e),
+                }
+            }
+        }
+    }
+    let listen_addrs: Vec<SocketAddr> = listeners
+        .iter()
+        .map(|x| x.local_addr())
+        .collect::<Result<_, _>>()?;
+    for addr in &listen_addrs {
+        println!("listening on {}", addr);
+    }
+    let port = listen_addrs
+        .first()
+        .map(|addr| config.announce_port.unwrap_or(addr.port()));
+    // The tracker sees whichever address we announce from, so our IPv6
+    // address has to be sent explicitly, and only helps if it's routable.
+    // Listening on every address, it's the one the host reaches out from.
+    let ipv6 = listen_addrs
+        .iter()
+        .find_map(|addr| match addr.ip() {
+            IpAddr::V6(ip) if ip.is_unspecified() => outgoing_ipv6()
+                .map(|ip| SocketAddr::new(IpAddr::V6(ip), addr.port())),
+            IpAddr::V6(_) => Some(*addr),
+            IpAddr::V4(_) => None,
+        })
+        .filter(|addr| match addr.ip() {
+            IpAddr::V6(ip) => is_routable(ip),
+            IpAddr::V4(_) => false,
+        });
 
-    let peers = match parse_response(&body_buf) {
-        Ok(peers) => peers,
+    let client = match tracker::HttpTrackerClient::new() {
+        Ok(client) => client,
         Err(e) => {
             println!("{}", e);
             return Ok(());
         }
     };
+    let mut trackers = tracker::TrackerList::new(&metainfo)
+        .retries(config.announce_retries.unwrap_or(tracker::ANNOUNCE_RETRIES));
+    let mut announce_state = tracker::AnnounceState::new();
+    let announce_config = tracker::AnnounceConfig {
+        port,
+        ipv6,
+        event: announce_state.next_event(false),
+        ..Default::default()
+    };
+    let response =
+        match trackers.announce_with(&client, &metainfo, &announce_config) {
+            Ok(response) => response,
+            Err(e) => {
+                println!("{}", e);
+                return Ok(());
+            }
+        };
+    if let Some(warning) = &response.warning_message {
+        println!("tracker warning: {}", warning);
+    }
 
-    torrent::download::download_from(
+    // Only worth reporting when there's more than one file.
+    let (file_completed, completed_files) =
+        mpsc::channel::<download::FileCompleted>();
+    if metainfo.info.files.is_some() {
+        thread::spawn(move || {
+            for file in completed_files {
+                println!("finished {}", file.path);
+            }
+        });
+    }
+    let (reannounce, reannounce_requests) = mpsc::channel();
+    // Private torrents must not learn about peers from other peers.
+    let config = download::DownloadConfig {
+        disable_pex: config.disable_pex || metainfo.info.private,
+        files: metainfo
+            .info
+            .file_offsets()
+            .map(|(path, length, _)| (path.to_string(), length))
+            .collect(),
+        file_completed: Some(file_completed),
+        num_pieces: Some(metainfo.info.num_pieces()),
+        total_length: Some(metainfo.info.total_length()),
+        reannounce: Some(reannounce),
+        ..config
+    };
+    let progress = Arc::new(Mutex::new(download::Progress::default()));
+    // The announcing thread parses its own copy of the torrent, as the one
+    // here borrows `contents`. It takes over the trackers, which know when
+    // each of them is due another announce.
+    let (peer_sender, new_peers) = mpsc::channel();
+    {
+        let contents = contents.clone();
+        let client = client.clone();
+        let progress = progress.clone();
+        thread::spawn(move || {
+            let metainfo =
+                TorrentMetaInfo::with_max_pieces(&contents, max_pieces)
+                    .unwrap();
+            trackers.keep_announcing(
+                &client,
+                &metainfo,
+                &reannounce_requests,
+                &peer_sender,
+                || tracker::AnnounceConfig {
+                    port,
+                    ipv6,
+                    downloaded: progress.lock().unwrap().downloaded_bytes,
+                    ..Default::default()
+                },
+            );
+        });
+    }
+    #[cfg(feature = "progress-bar")]
+    let (bar_done, bar) = {
+        let done = Arc::new(AtomicBool::new(false));
+        let progress = progress.clone();
+        let bar_done = done.clone();
+        (
+            done,
+            thread::spawn(move || draw_progress(&progress, &bar_done)),
+        )
+    };
+    let summary = torrent::download::download_from(
         &mut pieces,
-        peers,
+        response.peers,
+        new_peers,
         metainfo.info.hash,
         metainfo.info.piece_length,
+        progress.clone(),
+        &config,
+        &have,
+        Arc::new(AtomicBool::new(false)),
     );
-
-    Ok(())
-}
-
-fn parse_response(
-    response: &[u8],
-) -> Result<Vec<Peer>, Box<std::error::Error>> {
-    let dict = bencode::decode(response)?;
-    let dict = match dict {
-        bencode::BencodeVal::Dict {
-            index: _,
-            size: _,
-            dict,
-        } => dict,
-        _ => return Err(From::from("response should be a dictionary")),
-    };
-
-    if let Some(reason) = dict.get("failure reason".as_bytes()) {
-        match reason {
-            bencode::BencodeVal::Str {
-                index: _,
-                size: _,
-                byte_str,
-            } => {
-                let failure_reason = str::from_utf8(byte_str).unwrap();
-                return Err(From::from(format!("failed: {}", failure_reason)));
-            }
-            _ => return Err(From::from("failure reason should be a byte str")),
+    #[cfg(feature = "progress-bar")]
+    {
+        bar_done.store(true, Ordering::SeqCst);
+        let _ = bar.join();
+    }
+    if !summary.is_complete() {
+        println!(
+            "{} pieces could not be downloaded: {:?}",
+            summary.incomplete_pieces.len(),
+            summary.incomplete_pieces
+        );
+        if summary.unavailable_pieces == summary.incomplete_pieces {
+            println!("none of the peers had them");
+        }
+        if !summary.failed_pieces.is_empty() {
+            println!(
+                "{} pieces could not be verified or written: {:?}",
+                summary.failed_pieces.len(),
+                summary.failed_pieces
+            );
         }
     }
 
-    let peers_list = match dict.get("peers".as_bytes()) {
-        Some(bencode::BencodeVal::List {
-            index: _,
-            size: _,
-            list,
-        }) => list,
-        _ => return Err(From::from("should be a list")),
-    };
+    // The trackers went to the announcing thread, so the completed and
+    // stopped events go through a fresh list. `announce_with` sends events
+    // whatever a tracker's `min interval`, so the schedules the old list
+    // kept aren't missed.
+    let mut trackers = tracker::TrackerList::new(&metainfo)
+        .retries(config.announce_retries.unwrap_or(tracker::ANNOUNCE_RETRIES));
+    if summary.is_complete() && part_files {
+        if let Err(e) = resume::finish_parts(&finished_output, &config.output) {
+            println!("failed to rename finished files: {}", e);
+        }
+    }
 
-    let mut peers: Vec<Peer> = Vec::new();
-    for peer_dict in peers_list {
-        if let bencode::BencodeVal::Dict {
-            index: _,
-            size: _,
-            dict,
-        } = peer_dict
+    let downloaded = progress.lock().unwrap().downloaded_bytes;
+    let events = vec![
+        announce_state.next_event(summary.is_complete()),
+        announce_state.stop(),
+    ];
+    for event in events.into_iter().filter_map(|x| x) {
+        let announce_config = tracker::AnnounceConfig {
+            port,
+            ipv6,
+            downloaded,
+            event: Some(event),
+            ..Default::default()
+        };
+        if let Err(e) =
+            trackers.announce_with(&client, &metainfo, &announce_config)
         {
-            let id = match dict.get("peer id".as_bytes()) {
-                Some(&bencode::BencodeVal::Str {
-                    index: _,
-                    size: _,
-                    byte_str,
-                }) => {
-                    let mut array = [0u8; 20];
-                    array.clone_from_slice(&byte_str[0..20]);
-                    array
-                }
-                _ => return Err(From::from("peer id should be a byte string")),
-            };
+            println!("{}", e);
+        }
+    }
 
-            let ip = match dict.get("ip".as_bytes()) {
-                Some(bencode::BencodeVal::Str {
-                    index: _,
-                    size: _,
-                    byte_str,
-                }) => {
-                    IpAddr::from_str(str::from_utf8(byte_str).unwrap()).unwrap()
-                }
-                _ => return Err(From::from("peer id should be a byte string")),
-            };
+    Ok(())
+}
 
-            let port = match dict.get("port".as_bytes()) {
-                Some(bencode::BencodeVal::Int {
-                    index: _,
-                    size: _,
-                    int,
-                }) => *int as u16,
-                _ => return Err(From::from("port should be an integer")),
-            };
+/// Keeps a progress bar up to date with `progress` until `done` is set,
+/// then leaves it showing how far the download got.
+#[cfg(feature = "progress-bar")]
+fn draw_progress(progress: &Mutex<download::Progress>, done: &AtomicBool) {
+    let bar = ProgressBar::new(0);
+    bar.set_style(
+        ProgressStyle::default_bar().template("{bar:40} {percent:>3}% {msg}"),
+    );
+    loop {
+        let progress = *progress.lock().unwrap();
+        bar.set_length(progress.total_bytes);
+        bar.set_position(progress.downloaded_bytes);
+        let eta = match progress.eta {
+            Some(eta) => HumanDuration(eta).to_string(),
+            None => "unknown".to_string(),
+        };
+        bar.set_message(&format!(
+            "{}/s, {} peers, ETA {}",
+            HumanBytes(progress.download_rate as u64),
+            progress.connected_peers,
+            eta
+        ));
+
+        if done.load(Ordering::SeqCst) {
+            break;
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+    bar.abandon();
+}
 
-            peers.push(Peer {
-                id: id,
-                socket: SocketAddr::new(ip, port),
-            });
+/// Binds a listener on one of the usual BitTorrent ports, trying `port`
+/// first if given. IPv6 listeners take only IPv6 connections, so that an
+/// IPv4 one can share their port.
+fn listen(ip: IpAddr, port: Option<u16>) -> std::io::Result<TcpListener> {
+    let mut last_error = None;
+    for port in port.into_iter().chain(6881..6889) {
+        let domain = match ip {
+            IpAddr::V4(_) => Domain::ipv4(),
+            IpAddr::V6(_) => Domain::ipv6(),
+        };
+        let socket =
+            Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+        if ip.is_ipv6() {
+            socket.set_only_v6(true)?;
+        }
+        let addr = SocketAddr::new(ip, port).into();
+        match socket.bind(&addr).and_then(|_| socket.listen(128)) {
+            Ok(()) => return Ok(socket.into_tcp_listener()),
+            Err(e) => last_error = Some(e),
         }
     }
+    Err(last_error.unwrap())
+}
+
+/// The IPv6 address the host would reach the internet from, if it has one.
+/// Connecting a UDP socket only picks the route, so nothing is sent.
+fn outgoing_ipv6() -> Option<Ipv6Addr> {
+    let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect("[2001:4860:4860::8888]:53").ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V6(ip) => Some(ip),
+        IpAddr::V4(_) => None,
+    }
+}
 
-    Ok(peers)
+/// Whether peers elsewhere could reach `ip`: it isn't loopback,
+/// unspecified, link-local (fe80::/10) or unique local (fc00::/7).
+fn is_routable(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !ip.is_loopback()
+        && !ip.is_unspecified()
+        && first & 0xffc0 != 0xfe80
+        && first & 0xfe00 != 0xfc00
 }
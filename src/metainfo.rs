@@ -0,0 +1,1000 @@
+//! Parsing of metainfo (`.torrent`) files.
+
+#[cfg(feature = "serde")]
+use crate::hex;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::bencode::{self, dict_val, int_val, list_val, str_val};
+use crate::Error;
+use sha1::{Digest, Sha1};
+use std::borrow::Cow;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str;
+use url::percent_encoding::{percent_encode, SIMPLE_ENCODE_SET};
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct File {
+    pub length: u64,
+    pub path: String,
+}
+
+impl fmt::Debug for File {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "length: {}", self.length)?;
+        write!(f, "path: {}", self.path)
+    }
+}
+
+/// A file in the `file tree` of a v2 or hybrid torrent (BEP 52).
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug)]
+pub struct TreeFile {
+    pub length: u64,
+    pub path: String,
+    /// The root of the file's merkle tree, which empty files don't have.
+    #[cfg_attr(feature = "serde", serde(with = "hex::optional_root"))]
+    pub pieces_root: Option<[u8; 32]>,
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Info<'a> {
+    pub name: &'a str,
+    pub piece_length: u64,
+    /// The `pieces` byte string as it appears in the torrent, every piece's
+    /// SHA-1 hash concatenated. Borrowed from the torrent rather than split
+    /// up front, since it can run to megabytes; read the hashes with
+    /// `piece_hashes` or `piece_hash`. Serialized as a list of hex hashes.
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "pieces", with = "hex::concatenated")
+    )]
+    pub raw_pieces: Cow<'a, [u8]>,
+    pub length: Option<u64>,
+    pub files: Option<Vec<File>>,
+    /// Tag some private trackers add so that identical content hashes
+    /// differently per tracker.
+    pub source: Option<String>,
+    /// Random value added by cross-seeding tools for the same purpose as
+    /// `source`.
+    pub cross_seed: Option<String>,
+    /// Set for private torrents, whose peers should only come from their
+    /// trackers.
+    pub private: bool,
+    #[cfg_attr(feature = "serde", serde(with = "hex"))]
+    pub hash: [u8; 20],
+}
+
+/// The range of piece lengths real torrents use. Lengths outside it are
+/// allowed, but `Info::validate` warns about them.
+pub const MIN_PIECE_LENGTH: u64 = 1 << 14;
+pub const MAX_PIECE_LENGTH: u64 = 1 << 26;
+/// Default cap on a torrent's pieces, for `TorrentMetaInfo::new`. Enough
+/// for 256 GiB in 64 KiB pieces, which is far more than real torrents have.
+pub const MAX_PIECES: usize = 1 << 22;
+
+impl<'a> Info<'a> {
+    pub fn num_pieces(&self) -> usize {
+        self.raw_pieces.len() / 20
+    }
+
+    /// The SHA-1 hash of piece `index`, if there's such a piece.
+    pub fn piece_hash(&self, index: u32) -> Option<[u8; 20]> {
+        let start = index as usize * 20;
+        let bytes = self.raw_pieces.get(start..start + 20)?;
+        let mut hash = [0u8; 20];
+        hash.copy_from_slice(bytes);
+        Some(hash)
+    }
+
+    /// Every piece's SHA-1 hash, in order.
+    pub fn piece_hashes(&self) -> impl Iterator<Item = [u8; 20]> + '_ {
+        self.raw_pieces.chunks_exact(20).map(|bytes| {
+            let mut hash = [0u8; 20];
+            hash.copy_from_slice(bytes);
+            hash
+        })
+    }
+
+    /// Ways the torrent departs from conventions that almost every torrent
+    /// follows, and that some clients insist on. None of them stop it from
+    /// being downloaded, though an absurd piece length makes for huge
+    /// allocations.
+    pub fn validate(&self) -> Vec<String> {
+        let mut warnings = vec![];
+        if !self.piece_length.is_power_of_two() {
+            warnings.push(format!(
+                "piece length {} is not a power of two",
+                self.piece_length
+            ));
+        }
+        if self.piece_length < MIN_PIECE_LENGTH
+            || self.piece_length > MAX_PIECE_LENGTH
+        {
+            warnings.push(format!(
+                "piece length {} is outside {} to {}",
+                self.piece_length, MIN_PIECE_LENGTH, MAX_PIECE_LENGTH
+            ));
+        }
+
+        warnings
+    }
+
+    /// Like `validate`, but treats any departure as an error, for rejecting
+    /// non-conformant torrents outright.
+    pub fn validate_strict(&self) -> Result<(), Error> {
+        match self.validate().into_iter().next() {
+            Some(warning) => Err(From::from(warning)),
+            None => Ok(()),
+        }
+    }
+
+    /// Index of the piece containing the byte at `offset`, counting from the
+    /// start of the first file. `offset` should be within the torrent, whose
+    /// pieces are checked to be numbered within a `u32` when it's parsed.
+    pub fn piece_for_offset(&self, offset: u64) -> u32 {
+        (offset / self.piece_length) as u32
+    }
+
+    /// Byte offset of the start of piece `index`.
+    pub fn offset_for_piece(&self, index: u32) -> u64 {
+        index as u64 * self.piece_length
+    }
+
+    /// The bytes piece `index` covers, as `(start, end)` offsets counting
+    /// from the start of the first file. The last piece ends with the last
+    /// file.
+    pub fn piece_byte_range(&self, index: u32) -> (u64, u64) {
+        let start = self.offset_for_piece(index);
+        let end = cmp::min(start + self.piece_length, self.total_length());
+        (start, cmp::max(start, end))
+    }
+
+    /// The parts of files covered by piece `index`, as
+    /// `(file index, offset in file, length)`. Single-file torrents report
+    /// their file as index 0.
+    pub fn files_for_piece(&self, index: u32) -> Vec<(usize, u64, u64)> {
+        let (start, end) = self.piece_byte_range(index);
+        self.files_for_range(start, end)
+    }
+
+    /// The parts of files covered by the bytes from `start` up to `end`, in
+    /// the same form as `files_for_piece`. Empty files are never included.
+    pub fn files_for_range(
+        &self,
+        start: u64,
+        end: u64,
+    ) -> Vec<(usize, u64, u64)> {
+        let mut spans = vec![];
+        for (i, (_, length, file_start)) in self.file_offsets().enumerate() {
+            let file_end = file_start + length;
+            let span_start = cmp::max(start, file_start);
+            let span_end = cmp::min(end, file_end);
+            if span_start < span_end {
+                spans.push((i, span_start - file_start, span_end - span_start));
+            }
+        }
+
+        spans
+    }
+
+    /// Every file in the torrent as `(path, length)`, in order. A
+    /// single-file torrent yields one file named after the torrent.
+    pub fn file_list(&self) -> Vec<(&str, u64)> {
+        match (&self.files, &self.length) {
+            (Some(files), _) => files
+                .iter()
+                .map(|file| (file.path.as_str(), file.length))
+                .collect(),
+            (None, Some(length)) => vec![(self.name, *length)],
+            (None, None) => vec![],
+        }
+    }
+
+    /// Every file in the torrent as `(path, length, offset)`, in order,
+    /// where `offset` is where the file starts counting from the start of
+    /// the first file.
+    pub fn file_offsets(&self) -> impl Iterator<Item = (&str, u64, u64)> {
+        self.file_list()
+            .into_iter()
+            .scan(0, |offset, (path, length)| {
+                let start = *offset;
+                *offset += length;
+                Some((path, length, start))
+            })
+    }
+
+    /// The info dictionary re-encoded from the parsed fields. Keys `Info`
+    /// doesn't hold, and `private` set to anything but 1, are left out.
+    pub fn encode(&self) -> Vec<u8> {
+        let paths: Vec<Vec<&str>> = self
+            .files
+            .iter()
+            .flatten()
+            .map(|file| file.path.split('/').collect())
+            .collect();
+
+        let mut info = BTreeMap::new();
+        info.insert("name".as_bytes(), str_val(self.name.as_bytes()));
+        info.insert(
+            "piece length".as_bytes(),
+            int_val(self.piece_length as i64),
+        );
+        info.insert("pieces".as_bytes(), str_val(&self.raw_pieces));
+        if let Some(length) = self.length {
+            info.insert("length".as_bytes(), int_val(length as i64));
+        }
+        if let Some(files) = &self.files {
+            let list = files
+                .iter()
+                .zip(&paths)
+                .map(|(file, path)| {
+                    let mut dict = BTreeMap::new();
+                    dict.insert(
+                        "length".as_bytes(),
+                        int_val(file.length as i64),
+                    );
+                    dict.insert(
+                        "path".as_bytes(),
+                        list_val(
+                            path.iter()
+                                .map(|x| str_val(x.as_bytes()))
+                                .collect(),
+                        ),
+                    );
+                    dict_val(dict)
+                })
+                .collect();
+            info.insert("files".as_bytes(), list_val(list));
+        }
+        if let Some(source) = &self.source {
+            info.insert("source".as_bytes(), str_val(source.as_bytes()));
+        }
+        if let Some(cross_seed) = &self.cross_seed {
+            info.insert(
+                "x_cross_seed".as_bytes(),
+                str_val(cross_seed.as_bytes()),
+            );
+        }
+        if self.private {
+            info.insert("private".as_bytes(), int_val(1));
+        }
+
+        bencode::encode(&dict_val(info))
+    }
+
+    /// Whether hashing `encode`'s output gives `hash`. It won't for
+    /// torrents with info keys `Info` doesn't hold, which `encode` leaves
+    /// out.
+    pub fn verify_hash(&self) -> bool {
+        let hash: [u8; 20] = Sha1::digest(&self.encode()).into();
+        hash == self.hash
+    }
+
+    /// Size in bytes of all the torrent's files together.
+    pub fn total_length(&self) -> u64 {
+        self.file_list().iter().map(|&(_, length)| length).sum()
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TorrentMetaInfo<'a> {
+    /// Tracker URL. Announce URLs that are not valid UTF-8 have their
+    /// non-ASCII bytes percent-encoded so the original bytes still reach the
+    /// tracker. Trackerless torrents have none.
+    #[cfg_attr(feature = "serde", serde(borrow))]
+    pub announce: Option<Cow<'a, str>>,
+    /// Tiers of trackers from `announce-list` (BEP 12), encoded the same
+    /// way as `announce`. When present, clients use it instead of
+    /// `announce`.
+    pub announce_list: Option<Vec<Vec<String>>>,
+    /// DHT nodes to bootstrap from, as `(host, port)`. Trackerless torrents
+    /// list these in place of `announce`.
+    pub nodes: Option<Vec<(String, u16)>>,
+    #[cfg_attr(feature = "serde", serde(borrow))]
+    pub info: Info<'a>,
+    /// The files of the info dictionary's `file tree` (BEP 52), in order.
+    /// Empty for v1 torrents.
+    pub file_tree: Vec<TreeFile>,
+    /// BEP 52 piece layers: the SHA-256 leaf hashes of each file's merkle
+    /// tree at piece granularity, keyed by the `pieces root` of the file in
+    /// `file_tree` they belong to. Each has a hash per piece of its file.
+    /// Files of a piece or less have none, as their root is their only
+    /// leaf. Empty for v1 torrents.
+    #[cfg_attr(feature = "serde", serde(with = "hex::layers"))]
+    pub piece_layers: HashMap<[u8; 32], Vec<[u8; 32]>>,
+}
+
+impl<'a> TorrentMetaInfo<'a> {
+    pub fn new(contents: &'a [u8]) -> Result<TorrentMetaInfo, Error> {
+        TorrentMetaInfo::with_max_pieces(contents, MAX_PIECES)
+    }
+
+    /// Like `new`, but rejects torrents with more than `max_pieces` pieces,
+    /// going by their piece hashes or their total length, whichever needs
+    /// more.
+    pub fn with_max_pieces(
+        contents: &'a [u8],
+        max_pieces: usize,
+    ) -> Result<TorrentMetaInfo, Error> {
+        // Checked first, as the wrong kind of file would otherwise fail
+        // with whatever bencode error its first bytes happen to cause.
+        if contents.first() != Some(&b'd') {
+            return Err(From::from(
+                "not a valid torrent file: expected a bencode dictionary",
+            ));
+        }
+        let dict = match bencode::decode(&contents)? {
+            bencode::BencodeVal::Dict {
+                index: _,
+                size,
+                dict,
+            } => {
+                // Anything after the root dictionary means the file is
+                // corrupt or has been tampered with.
+                if size != contents.len() {
+                    return Err(From::from(format!(
+                        "{} trailing bytes after metainfo dictionary",
+                        contents.len() - size
+                    )));
+                }
+                dict
+            }
+            _ => {
+                return Err(From::from("should be a dictionary"));
+            }
+        };
+
+        let announce = match dict.get("announce".as_bytes()) {
+            Some(v) => match v {
+                bencode::BencodeVal::Str {
+                    index: _,
+                    size: _,
+                    byte_str,
+                } => Some(match str::from_utf8(byte_str) {
+                    Ok(announce) => Cow::Borrowed(announce),
+                    Err(_) => Cow::Owned(
+                        percent_encode(byte_str, SIMPLE_ENCODE_SET).collect(),
+                    ),
+                }),
+                _ => {
+                    return Err(From::from("announce should be a byte string"))
+                }
+            },
+            None => None,
+        };
+
+        let announce_list = match dict.get("announce-list".as_bytes()) {
+            Some(bencode::BencodeVal::List {
+                index: _,
+                size: _,
+                list,
+            }) => Some(parse_announce_list(list)?),
+            Some(_) => {
+                return Err(From::from("announce-list should be a list"))
+            }
+            None => None,
+        };
+
+        let nodes = match dict.get("nodes".as_bytes()) {
+            Some(bencode::BencodeVal::List {
+                index: _,
+                size: _,
+                list,
+            }) => Some(parse_nodes(list)?),
+            Some(_) => return Err(From::from("nodes should be a list")),
+            None => None,
+        };
+
+        let piece_layers = match dict.get("piece layers".as_bytes()) {
+            Some(bencode::BencodeVal::Dict {
+                index: _,
+                size: _,
+                dict,
+            }) => parse_piece_layers(dict)?,
+            Some(_) => {
+                return Err(From::from("piece layers should be a dictionary"))
+            }
+            None => HashMap::new(),
+        };
+
+        let (&info_dict_index, info_dict_size, info_dict) =
+            match dict.get("info".as_bytes()) {
+                Some(v) => match v {
+                    bencode::BencodeVal::Dict { index, dict, size } => {
+                        (index, size, dict)
+                    }
+                    _ => return Err(From::from("info should be a dictionary")),
+                },
+                None => {
+                    return Err(From::from(
+                        "info dict not found in metainfo dictionary",
+                    ))
+                }
+            };
+
+        let info_hash: [u8; 20] = Sha1::digest(
+            &contents[info_dict_index..info_dict_index + info_dict_size],
+        )
+        .into();
+
+        let name = match info_dict.get("name".as_bytes()) {
+            Some(v) => match v {
+                bencode::BencodeVal::Str {
+                    index: _,
+                    byte_str,
+                    size: _,
+                } => str::from_utf8(byte_str)?,
+                _ => {
+                    return Err(From::from(
+                        "name should be a UTF-8 encoded string",
+                    ))
+                }
+            },
+            None => {
+                return Err(From::from("name not found in info_dict dictionary"))
+            }
+        };
+        check_path_component(name)
+            .map_err(|e| Error::from(format!("bad name: {}", e)))?;
+
+        let piece_length = match info_dict.get("piece length".as_bytes()) {
+            Some(v) => match v {
+                bencode::BencodeVal::Int {
+                    index: _,
+                    int,
+                    size: _,
+                } => *int,
+                _ => {
+                    return Err(From::from("piece length should be an integer"))
+                }
+            },
+            None => {
+                return Err(From::from(
+                    "piece length not found in info_dict dictionary",
+                ))
+            }
+        };
+
+        // Offsets within a piece are 32 bits on the wire.
+        if piece_length <= 0 || piece_length > u32::max_value() as i64 {
+            return Err(From::from(format!(
+                "piece length should be between 1 and {}",
+                u32::max_value()
+            )));
+        }
+        let piece_length = piece_length as u64;
+
+        let pieces_byte_string = match info_dict.get("pieces".as_bytes()) {
+            Some(v) => match v {
+                bencode::BencodeVal::Str {
+                    index: _,
+                    byte_str,
+                    size: _,
+                } => byte_str,
+                _ => return Err(From::from("pieces should be a byte string")),
+            },
+            None => {
+                return Err(From::from(
+                    "pieces not found in info_dict dictionary",
+                ))
+            }
+        };
+
+        if pieces_byte_string.len() % 20 != 0 {
+            return Err(From::from("pieces should be a multiple of 20 bytes"));
+        }
+        // Piece indices are 32 bits on the wire.
+        if pieces_byte_string.len() / 20 > u32::max_value() as usize {
+            return Err(From::from("torrent has too many pieces"));
+        }
+
+        let length = if let Some(bencode::BencodeVal::Int {
+            index: _,
+            size: _,
+            int,
+        }) = info_dict.get("length".as_bytes())
+        {
+            Some(length_val(*int, "length")?)
+        } else {
+            None
+        };
+
+        let source = if let Some(bencode::BencodeVal::Str {
+            index: _,
+            size: _,
+            byte_str,
+        }) = info_dict.get("source".as_bytes())
+        {
+            Some(String::from_utf8_lossy(byte_str).into_owned())
+        } else {
+            None
+        };
+
+        let cross_seed = if let Some(bencode::BencodeVal::Str {
+            index: _,
+            size: _,
+            byte_str,
+        }) = info_dict.get("x_cross_seed".as_bytes())
+        {
+            Some(String::from_utf8_lossy(byte_str).into_owned())
+        } else {
+            None
+        };
+
+        let private = match info_dict.get("private".as_bytes()) {
+            Some(bencode::BencodeVal::Int {
+                index: _,
+                size: _,
+                int,
+            }) => *int == 1,
+            _ => false,
+        };
+
+        let files = if let Some(bencode::BencodeVal::List {
+            index: _,
+            size: _,
+            list,
+        }) = info_dict.get("files".as_bytes())
+        {
+            let mut vector = Vec::new();
+            for elem in list {
+                if let bencode::BencodeVal::Dict {
+                    index: _,
+                    size: _,
+                    dict,
+                } = elem
+                {
+                    let file_length = match dict.get("length".as_bytes()) {
+                        Some(v) => match v {
+                            bencode::BencodeVal::Int {
+                                index: _,
+                                size: _,
+                                int,
+                            } => length_val(*int, "file length")?,
+                            _ => {
+                                return Err(From::from(
+                                    "file length should be an integer",
+                                ))
+                            }
+                        },
+                        None => {
+                            return Err(From::from(
+                                "file length not found in dictionary",
+                            ))
+                        }
+                    };
+
+                    let file_path = match dict.get("path".as_bytes()) {
+                        Some(v) => match v {
+                            bencode::BencodeVal::List {
+                                index: _,
+                                size: _,
+                                list,
+                            } => list,
+                            _ => {
+                                return Err(From::from(
+                                    "file path should be a list",
+                                ))
+                            }
+                        },
+                        None => {
+                            return Err(From::from(
+                                "file path not found in dictionary",
+                            ))
+                        }
+                    };
+
+                    if file_path.is_empty() {
+                        return Err(From::from(
+                            "file path should not be empty",
+                        ));
+                    }
+                    let mut components = vec![];
+                    for component in file_path {
+                        let component = match component {
+                            bencode::BencodeVal::Str {
+                                index: _,
+                                size: _,
+                                byte_str,
+                            } => str::from_utf8(byte_str).map_err(|_| {
+                                Error::from(
+                                    "file path component should be UTF-8",
+                                )
+                            })?,
+                            _ => {
+                                return Err(From::from(
+                                    "file path component should be a string",
+                                ))
+                            }
+                        };
+                        check_path_component(component).map_err(|e| {
+                            Error::from(format!("bad file path: {}", e))
+                        })?;
+                        components.push(component);
+                    }
+                    let file_path = components;
+
+                    vector.push(File {
+                        length: file_length,
+                        path: file_path.join("/"),
+                    });
+                }
+            }
+            Some(vector)
+        } else {
+            None
+        };
+
+        // A `files` list makes this a multi-file torrent, even if it only
+        // holds one file or a stray top-level `length` is also present.
+        let length = match (length, &files) {
+            (_, Some(_)) => None,
+            (Some(length), None) => Some(length),
+            (None, None) => {
+                return Err(From::from(
+                    "info_dict should contain either length or files",
+                ))
+            }
+        };
+
+        let lengths: Vec<u64> = match (length, &files) {
+            (Some(length), _) => vec![length],
+            (None, Some(files)) => files.iter().map(|x| x.length).collect(),
+            (None, None) => vec![],
+        };
+        let total_length = match lengths
+            .into_iter()
+            .try_fold(0u64, |total, x| total.checked_add(x))
+        {
+            Some(total_length) => total_length,
+            None => return Err(From::from("total length is too large")),
+        };
+
+        // Downloading allocates every piece up front, so a torrent claiming
+        // a huge length in tiny pieces could otherwise exhaust memory.
+        let num_hashes = pieces_byte_string.len() as u64 / 20;
+        let num_pieces = total_length / piece_length
+            + (total_length % piece_length != 0) as u64;
+        if cmp::max(num_hashes, num_pieces) > max_pieces as u64 {
+            return Err(From::from(format!(
+                "torrent has {} pieces, more than the limit of {}",
+                cmp::max(num_hashes, num_pieces),
+                max_pieces
+            )));
+        }
+        if num_hashes != num_pieces {
+            return Err(From::from(format!(
+                "torrent has {} piece hashes, but its {} bytes need {}",
+                num_hashes, total_length, num_pieces
+            )));
+        }
+
+        let mut file_tree = vec![];
+        match info_dict.get("file tree".as_bytes()) {
+            Some(bencode::BencodeVal::Dict {
+                index: _,
+                size: _,
+                dict,
+            }) => parse_file_tree(dict, &mut vec![], &mut file_tree)?,
+            Some(_) => {
+                return Err(From::from("file tree should be a dictionary"))
+            }
+            None => {}
+        }
+        check_piece_layers(&file_tree, &piece_layers, piece_length)?;
+
+        Ok(TorrentMetaInfo {
+            announce: announce,
+            announce_list,
+            nodes,
+            info: Info {
+                name,
+                piece_length,
+                raw_pieces: Cow::Borrowed(pieces_byte_string),
+                length,
+                files,
+                source,
+                cross_seed,
+                private,
+                hash: info_hash,
+            },
+            file_tree,
+            piece_layers,
+        })
+    }
+
+    /// The piece layer of the file whose merkle root is `pieces_root`.
+    pub fn piece_layer(&self, pieces_root: &[u8; 32]) -> Option<&[[u8; 32]]> {
+        self.piece_layers.get(pieces_root).map(|layer| &layer[..])
+    }
+}
+
+/// The info hash of the torrent in `contents`, hashed the same way as by
+/// `TorrentMetaInfo::new` but without parsing anything beyond finding the
+/// info dictionary. For scanning many torrents for one hash.
+pub fn info_hash_of(contents: &[u8]) -> Result<[u8; 20], Error> {
+    match bencode::dict_entry(contents, b"info")? {
+        Some(info) if info[0] == b'd' => Ok(Sha1::digest(info).into()),
+        Some(_) => Err(From::from("info should be a dictionary")),
+        None => Err(From::from("info dict not found in metainfo dictionary")),
+    }
+}
+
+/// A length from the metainfo, which can't be negative.
+fn length_val(int: i64, key: &str) -> Result<u64, Error> {
+    if int < 0 {
+        return Err(From::from(format!("{} should not be negative", key)));
+    }
+
+    Ok(int as u64)
+}
+
+fn parse_nodes(
+    list: &[bencode::BencodeVal],
+) -> Result<Vec<(String, u16)>, Error> {
+    let mut nodes = vec![];
+    for node in list {
+        let pair = match node {
+            bencode::BencodeVal::List {
+                index: _,
+                size: _,
+                list,
+            } if list.len() == 2 => list,
+            _ => return Err(From::from("node should be a [host, port] list")),
+        };
+        let host = match &pair[0] {
+            bencode::BencodeVal::Str {
+                index: _,
+                size: _,
+                byte_str,
+            } => str::from_utf8(byte_str)?.to_string(),
+            _ => return Err(From::from("node host should be a byte string")),
+        };
+        let port = match &pair[1] {
+            bencode::BencodeVal::Int {
+                index: _,
+                size: _,
+                int,
+            } if *int >= 0 && *int <= u16::max_value() as i64 => *int as u16,
+            _ => return Err(From::from("node port should be a valid port")),
+        };
+        nodes.push((host, port));
+    }
+
+    Ok(nodes)
+}
+
+fn parse_announce_list(
+    list: &Vec<bencode::BencodeVal>,
+) -> Result<Vec<Vec<String>>, Error> {
+    let mut tiers = vec![];
+    for tier in list {
+        let tier = match tier {
+            bencode::BencodeVal::List {
+                index: _,
+                size: _,
+                list,
+            } => list,
+            _ => return Err(From::from("announce-list tier should be a list")),
+        };
+
+        let mut urls = vec![];
+        for url in tier {
+            match url {
+                bencode::BencodeVal::Str {
+                    index: _,
+                    size: _,
+                    byte_str,
+                } => urls.push(match str::from_utf8(byte_str) {
+                    Ok(url) => url.to_string(),
+                    Err(_) => {
+                        percent_encode(byte_str, SIMPLE_ENCODE_SET).collect()
+                    }
+                }),
+                _ => {
+                    return Err(From::from(
+                        "announce-list url should be a byte string",
+                    ))
+                }
+            }
+        }
+        tiers.push(urls);
+    }
+
+    Ok(tiers)
+}
+
+fn parse_piece_layers(
+    dict: &BTreeMap<&[u8], bencode::BencodeVal>,
+) -> Result<HashMap<[u8; 32], Vec<[u8; 32]>>, Error> {
+    let mut piece_layers = HashMap::new();
+    for (root, layer) in dict {
+        if root.len() != 32 {
+            return Err(From::from("piece layers keys should be 32 bytes"));
+        }
+        let layer = match layer {
+            bencode::BencodeVal::Str {
+                index: _,
+                size: _,
+                byte_str,
+            } if byte_str.len() % 32 == 0 => byte_str,
+            _ => {
+                return Err(From::from(
+                    "piece layer should be a multiple of 32 bytes",
+                ))
+            }
+        };
+
+        let mut key = [0u8; 32];
+        key.copy_from_slice(root);
+        let hashes = layer
+            .chunks(32)
+            .map(|chunk| {
+                let mut hash = [0u8; 32];
+                hash.copy_from_slice(chunk);
+                hash
+            })
+            .collect();
+        piece_layers.insert(key, hashes);
+    }
+
+    Ok(piece_layers)
+}
+
+/// Adds the files under `dict`, a directory of a `file tree` whose path is
+/// `dir`, to `files`. A file is a dictionary holding its details under the
+/// empty key, and a directory is a dictionary of the files and directories
+/// in it.
+fn parse_file_tree<'a>(
+    dict: &'a BTreeMap<&[u8], bencode::BencodeVal>,
+    dir: &mut Vec<&'a str>,
+    files: &mut Vec<TreeFile>,
+) -> Result<(), Error> {
+    for (name, node) in dict {
+        let name = str::from_utf8(name)
+            .map_err(|_| Error::from("file tree names should be UTF-8"))?;
+        check_path_component(name)
+            .map_err(|e| Error::from(format!("bad file path: {}", e)))?;
+        let node = match node {
+            bencode::BencodeVal::Dict {
+                index: _,
+                size: _,
+                dict,
+            } => dict,
+            _ => {
+                return Err(From::from(
+                    "file tree entries should be dictionaries",
+                ))
+            }
+        };
+
+        dir.push(name);
+        match node.get("".as_bytes()) {
+            Some(bencode::BencodeVal::Dict {
+                index: _,
+                size: _,
+                dict: file,
+            }) => {
+                let length = match file.get("length".as_bytes()) {
+                    Some(bencode::BencodeVal::Int {
+                        index: _,
+                        size: _,
+                        int,
+                    }) => length_val(*int, "file length")?,
+                    _ => {
+                        return Err(From::from(
+                            "file tree files should have an integer length",
+                        ))
+                    }
+                };
+                let pieces_root = match file.get("pieces root".as_bytes()) {
+                    Some(bencode::BencodeVal::Str {
+                        index: _,
+                        size: _,
+                        byte_str,
+                    }) if byte_str.len() == 32 => {
+                        let mut root = [0u8; 32];
+                        root.copy_from_slice(byte_str);
+                        Some(root)
+                    }
+                    Some(_) => {
+                        return Err(From::from(
+                            "pieces root should be 32 bytes",
+                        ))
+                    }
+                    None if length > 0 => {
+                        return Err(From::from(format!(
+                            "{} has no pieces root",
+                            dir.join("/")
+                        )))
+                    }
+                    None => None,
+                };
+                files.push(TreeFile {
+                    length,
+                    path: dir.join("/"),
+                    pieces_root,
+                });
+            }
+            Some(_) => {
+                return Err(From::from(
+                    "file tree files should be dictionaries",
+                ))
+            }
+            None => parse_file_tree(node, dir, files)?,
+        }
+        dir.pop();
+    }
+
+    Ok(())
+}
+
+/// Checks that each of `piece_layers` belongs to one of `files` and has a
+/// hash for each of its pieces, and that every file longer than a piece has
+/// one.
+fn check_piece_layers(
+    files: &[TreeFile],
+    piece_layers: &HashMap<[u8; 32], Vec<[u8; 32]>>,
+    piece_length: u64,
+) -> Result<(), Error> {
+    for root in piece_layers.keys() {
+        if !files.iter().any(|x| x.pieces_root.as_ref() == Some(root)) {
+            return Err(From::from(
+                "piece layer doesn't belong to any file in the file tree",
+            ));
+        }
+    }
+
+    for file in files {
+        let root = match &file.pieces_root {
+            Some(root) => root,
+            None => continue,
+        };
+        let num_pieces = (file.length + piece_length - 1) / piece_length;
+        match piece_layers.get(root) {
+            Some(layer) if layer.len() as u64 != num_pieces => {
+                return Err(From::from(format!(
+                    "piece layer of {} has {} hashes, but its {} bytes need {}",
+                    file.path,
+                    layer.len(),
+                    file.length,
+                    num_pieces
+                )))
+            }
+            None if num_pieces > 1 => {
+                return Err(From::from(format!(
+                    "{} has no piece layer",
+                    file.path
+                )))
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that `component`, the torrent's name or part of a file's path,
+/// names a single file or directory, so that the files it's joined into
+/// stay within the directory the torrent is downloaded to.
+fn check_path_component(component: &str) -> Result<(), Error> {
+    if component.is_empty() {
+        return Err(From::from("empty path component"));
+    }
+    if component == "." || component == ".." {
+        return Err(From::from(format!("{:?} is not allowed", component)));
+    }
+    if component.contains(|c| c == '/' || c == '\\' || c == '\0')
+        || std::path::Path::new(component).is_absolute()
+    {
+        return Err(From::from(format!(
+            "{:?} should be a single path component",
+            component
+        )));
+    }
+
+    Ok(())
+}
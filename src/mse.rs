@@ -0,0 +1,335 @@
+//! Message Stream Encryption (MSE/PE), the RC4 obfuscation layer that is
+//! negotiated before the regular BitTorrent handshake.
+
+use crate::Error;
+use rand::Rng;
+use sha1::{Digest, Sha1};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+/// The 768 bit Diffie-Hellman prime from the MSE specification.
+const P: [u8; 96] = [
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xC9, 0x0F, 0xDA, 0xA2,
+    0x21, 0x68, 0xC2, 0x34, 0xC4, 0xC6, 0x62, 0x8B, 0x80, 0xDC, 0x1C, 0xD1,
+    0x29, 0x02, 0x4E, 0x08, 0x8A, 0x67, 0xCC, 0x74, 0x02, 0x0B, 0xBE, 0xA6,
+    0x3B, 0x13, 0x9B, 0x22, 0x51, 0x4A, 0x08, 0x79, 0x8E, 0x34, 0x04, 0xDD,
+    0xEF, 0x95, 0x19, 0xB3, 0xCD, 0x3A, 0x43, 0x1B, 0x30, 0x2B, 0x0A, 0x6D,
+    0xF2, 0x5F, 0x14, 0x37, 0x4F, 0xE1, 0x35, 0x6D, 0x6D, 0x51, 0xC2, 0x45,
+    0xE4, 0x85, 0xB5, 0x76, 0x62, 0x5E, 0x7E, 0xC6, 0xF4, 0x4C, 0x42, 0xE9,
+    0xA6, 0x3A, 0x36, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x05, 0x63,
+];
+const G: u32 = 2;
+const LIMBS: usize = 24;
+const MAX_PAD: usize = 512;
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+
+/// A connection to a peer that transparently applies the RC4 streams agreed
+/// on during the MSE handshake, or passes bytes through untouched for
+/// plaintext connections.
+pub struct PeerStream {
+    stream: TcpStream,
+    encryptor: Option<Rc4>,
+    decryptor: Option<Rc4>,
+}
+
+impl PeerStream {
+    pub fn plaintext(stream: TcpStream) -> PeerStream {
+        PeerStream {
+            stream,
+            encryptor: None,
+            decryptor: None,
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encryptor.is_some()
+    }
+
+    /// Returns a new handle to the underlying socket. Anything read or
+    /// written through it bypasses encryption.
+    pub fn try_clone_socket(&self) -> io::Result<TcpStream> {
+        self.stream.try_clone()
+    }
+}
+
+impl Read for PeerStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stream.read(buf)?;
+        if let Some(decryptor) = &mut self.decryptor {
+            decryptor.apply(&mut buf[..n]);
+        }
+
+        Ok(n)
+    }
+}
+
+impl Write for PeerStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.encryptor {
+            Some(encryptor) => {
+                // The keystream has advanced past every byte of `buf`, so all
+                // of it has to go out or the peer's decryptor falls out of
+                // step with ours.
+                let mut buffer = buf.to_vec();
+                encryptor.apply(&mut buffer);
+                self.stream.write_all(&buffer)?;
+                Ok(buf.len())
+            }
+            None => self.stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+struct Rc4 {
+    s: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Rc4 {
+        let mut s = [0u8; 256];
+        for (i, x) in s.iter_mut().enumerate() {
+            *x = i as u8;
+        }
+
+        let mut j: u8 = 0;
+        for (i, k) in key.iter().cycle().take(256).enumerate() {
+            j = j.wrapping_add(s[i]).wrapping_add(*k);
+            s.swap(i, j as usize);
+        }
+
+        let mut rc4 = Rc4 { s, i: 0, j: 0 };
+        // MSE discards the first 1024 bytes of both keystreams.
+        rc4.apply(&mut [0u8; 1024]);
+        rc4
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data.iter_mut() {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.s[self.i as usize]);
+            self.s.swap(self.i as usize, self.j as usize);
+            let k =
+                self.s[self.i as usize].wrapping_add(self.s[self.j as usize]);
+            *byte ^= self.s[k as usize];
+        }
+    }
+}
+
+/// Runs the MSE handshake as the connecting side. When `require_rc4` is
+/// false the peer may choose to continue in plaintext.
+pub fn handshake(
+    mut stream: TcpStream,
+    info_hash: &[u8; 20],
+    require_rc4: bool,
+) -> Result<PeerStream, Error> {
+    // A peer that doesn't speak MSE will usually just hang up on the random
+    // looking key, but don't wait forever if it doesn't.
+    stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+
+    let mut rng = rand::thread_rng();
+    let mut private_key = [0u8; 20];
+    rng.fill(&mut private_key);
+    let p = from_be_bytes(&P);
+    let mut g = [0u32; LIMBS];
+    g[0] = G;
+    let public_key = to_be_bytes(&pow_mod(&g, &private_key, &p));
+
+    let mut buffer = public_key.to_vec();
+    buffer.extend(random_pad(&mut rng));
+    stream.write_all(&buffer)?;
+
+    let mut peer_public_key = [0u8; 96];
+    stream.read_exact(&mut peer_public_key)?;
+    let peer_public_key = from_be_bytes(&peer_public_key);
+    if cmp(&peer_public_key, &p) != std::cmp::Ordering::Less {
+        return Err(From::from("peer sent an invalid public key"));
+    }
+    let secret = to_be_bytes(&pow_mod(&peer_public_key, &private_key, &p));
+
+    let mut encryptor = Rc4::new(&hash(&[b"keyA", &secret, info_hash]));
+    let mut decryptor = Rc4::new(&hash(&[b"keyB", &secret, info_hash]));
+
+    let crypto_provide = if require_rc4 {
+        CRYPTO_RC4
+    } else {
+        CRYPTO_RC4 | CRYPTO_PLAINTEXT
+    };
+    let pad_c = random_pad(&mut rng);
+    let mut payload = vec![0u8; 8];
+    payload.extend(&crypto_provide.to_be_bytes());
+    payload.extend(&(pad_c.len() as u16).to_be_bytes());
+    payload.extend(&pad_c);
+    // The BitTorrent handshake is sent separately once the stream is set
+    // up, so there is no initial payload.
+    payload.extend(&0u16.to_be_bytes());
+    encryptor.apply(&mut payload);
+
+    let mut buffer = hash(&[b"req1", &secret]).to_vec();
+    let req2 = hash(&[b"req2", info_hash]);
+    let req3 = hash(&[b"req3", &secret]);
+    buffer.extend(req2.iter().zip(req3.iter()).map(|(a, b)| a ^ b));
+    buffer.extend(&payload);
+    stream.write_all(&buffer)?;
+
+    // The peer's reply starts with an unknown amount of padding, so look for
+    // the encrypted verification constant to find where it ends.
+    let mut vc = [0u8; 8];
+    decryptor.apply(&mut vc);
+    let mut window = [0u8; 8];
+    stream.read_exact(&mut window)?;
+    let mut skipped = 0;
+    while window != vc {
+        if skipped == MAX_PAD {
+            return Err(From::from("verification constant not found"));
+        }
+        window.rotate_left(1);
+        stream.read_exact(&mut window[7..])?;
+        skipped += 1;
+    }
+
+    let mut select = [0u8; 6];
+    stream.read_exact(&mut select)?;
+    decryptor.apply(&mut select);
+    let crypto_select =
+        u32::from_be_bytes([select[0], select[1], select[2], select[3]]);
+    let pad_d_len = u16::from_be_bytes([select[4], select[5]]) as usize;
+    if pad_d_len > MAX_PAD {
+        return Err(From::from("peer padding is too long"));
+    }
+    let mut pad_d = vec![0u8; pad_d_len];
+    stream.read_exact(&mut pad_d)?;
+    decryptor.apply(&mut pad_d);
+
+    stream.set_read_timeout(None)?;
+    match crypto_select {
+        CRYPTO_RC4 => Ok(PeerStream {
+            stream,
+            encryptor: Some(encryptor),
+            decryptor: Some(decryptor),
+        }),
+        CRYPTO_PLAINTEXT if !require_rc4 => Ok(PeerStream::plaintext(stream)),
+        _ => Err(From::from(format!(
+            "peer selected unsupported crypto method {}",
+            crypto_select
+        ))),
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+
+    hasher.finalize().into()
+}
+
+fn random_pad<R: Rng>(rng: &mut R) -> Vec<u8> {
+    let mut pad = vec![0u8; rng.gen_range(0, MAX_PAD + 1)];
+    rng.fill(&mut pad[..]);
+    pad
+}
+
+// Just enough fixed width arithmetic for the key exchange. Numbers are
+// little-endian arrays of 32 bit limbs.
+
+fn from_be_bytes(bytes: &[u8]) -> [u32; LIMBS] {
+    let mut n = [0u32; LIMBS];
+    for (i, byte) in bytes.iter().rev().enumerate() {
+        n[i / 4] |= (*byte as u32) << (8 * (i % 4));
+    }
+
+    n
+}
+
+fn to_be_bytes(n: &[u32; LIMBS]) -> [u8; 96] {
+    let mut bytes = [0u8; 96];
+    for i in 0..96 {
+        bytes[95 - i] = (n[i / 4] >> (8 * (i % 4))) as u8;
+    }
+
+    bytes
+}
+
+fn cmp(a: &[u32; LIMBS], b: &[u32; LIMBS]) -> std::cmp::Ordering {
+    a.iter().rev().cmp(b.iter().rev())
+}
+
+/// `a -= b`, wrapping on underflow.
+fn sub(a: &mut [u32; LIMBS], b: &[u32; LIMBS]) {
+    let mut borrow = 0u64;
+    for i in 0..LIMBS {
+        let diff = (a[i] as u64).wrapping_sub(b[i] as u64).wrapping_sub(borrow);
+        a[i] = diff as u32;
+        borrow = (diff >> 63) & 1;
+    }
+}
+
+/// `a += b`, returning the carry out of the top limb.
+fn add(a: &mut [u32; LIMBS], b: &[u32; LIMBS]) -> bool {
+    let mut carry = 0u64;
+    for i in 0..LIMBS {
+        let sum = a[i] as u64 + b[i] as u64 + carry;
+        a[i] = sum as u32;
+        carry = sum >> 32;
+    }
+
+    carry != 0
+}
+
+/// `a <<= 1`, returning the bit shifted out of the top limb.
+fn shl1(a: &mut [u32; LIMBS]) -> bool {
+    let mut carry = 0;
+    for limb in a.iter_mut() {
+        let next = *limb >> 31;
+        *limb = (*limb << 1) | carry;
+        carry = next;
+    }
+
+    carry != 0
+}
+
+/// `a * b mod p` for `a, b < p`, by shift and add.
+fn mul_mod(
+    a: &[u32; LIMBS],
+    b: &[u32; LIMBS],
+    p: &[u32; LIMBS],
+) -> [u32; LIMBS] {
+    let mut r = [0u32; LIMBS];
+    for i in (0..LIMBS * 32).rev() {
+        if shl1(&mut r) || cmp(&r, p) != std::cmp::Ordering::Less {
+            sub(&mut r, p);
+        }
+        if (b[i / 32] >> (i % 32)) & 1 == 1
+            && (add(&mut r, a) || cmp(&r, p) != std::cmp::Ordering::Less)
+        {
+            sub(&mut r, p);
+        }
+    }
+
+    r
+}
+
+/// `base ^ exp mod p`, with `exp` given as big-endian bytes.
+fn pow_mod(base: &[u32; LIMBS], exp: &[u8], p: &[u32; LIMBS]) -> [u32; LIMBS] {
+    let mut r = [0u32; LIMBS];
+    r[0] = 1;
+    for byte in exp {
+        for j in (0..8).rev() {
+            r = mul_mod(&r, &r, p);
+            if (byte >> j) & 1 == 1 {
+                r = mul_mod(&r, base, p);
+            }
+        }
+    }
+
+    r
+}
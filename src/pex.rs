@@ -0,0 +1,181 @@
+//! Peer exchange (BEP 11), carried over the extension protocol (BEP 10).
+
+use crate::bencode::{self, dict_val, str_val, BencodeVal};
+use crate::Error;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+/// Name of the extension in extension handshakes.
+pub const NAME: &str = "ut_pex";
+
+/// `added.f` flag for peers that are seeds.
+pub const FLAG_SEED: u8 = 0x02;
+
+/// Peers ignore PEX messages sent more often than this.
+const PEX_INTERVAL: Duration = Duration::from_secs(60);
+/// Most peers to add, or drop, in a single message.
+const MAX_PEERS_PER_MSG: usize = 50;
+
+pub struct PexMsg {
+    /// Newly connected peers and their `added.f` flags.
+    pub added: Vec<(SocketAddr, u8)>,
+    pub dropped: Vec<SocketAddr>,
+}
+
+pub fn parse(payload: &[u8]) -> Result<PexMsg, Error> {
+    let dict = match bencode::decode(payload)? {
+        BencodeVal::Dict {
+            index: _,
+            size: _,
+            dict,
+        } => dict,
+        _ => return Err(From::from("ut_pex message should be a dict")),
+    };
+
+    let field = |key: &str| match dict.get(key.as_bytes()) {
+        Some(BencodeVal::Str {
+            index: _,
+            size: _,
+            byte_str,
+        }) => Ok(*byte_str),
+        Some(_) => Err(format!("{} should be a byte string", key)),
+        None => Ok(&[][..]),
+    };
+
+    let mut added = vec![];
+    for &(peers, flags, addr_len) in
+        &[("added", "added.f", 4), ("added6", "added6.f", 16)]
+    {
+        let flags = field(flags)?;
+        for (i, socket) in compact_peers(field(peers)?, addr_len)?
+            .into_iter()
+            .enumerate()
+        {
+            added.push((socket, flags.get(i).cloned().unwrap_or(0)));
+        }
+    }
+
+    let mut dropped = compact_peers(field("dropped")?, 4)?;
+    dropped.extend(compact_peers(field("dropped6")?, 16)?);
+
+    Ok(PexMsg { added, dropped })
+}
+
+fn compact_peers(
+    bytes: &[u8],
+    addr_len: usize,
+) -> Result<Vec<SocketAddr>, Error> {
+    let len = addr_len + 2;
+    if bytes.len() % len != 0 {
+        return Err(From::from(format!(
+            "compact peers length should be a multiple of {}",
+            len
+        )));
+    }
+
+    Ok(bytes
+        .chunks(len)
+        .map(|chunk| {
+            let ip = if addr_len == 4 {
+                let mut octets = [0u8; 4];
+                octets.copy_from_slice(&chunk[..4]);
+                IpAddr::from(octets)
+            } else {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&chunk[..16]);
+                IpAddr::from(octets)
+            };
+            let port = u16::from_be_bytes([chunk[addr_len], chunk[len - 1]]);
+            SocketAddr::new(ip, port)
+        })
+        .collect())
+}
+
+/// The payload of a `ut_pex` message, not including the extended message id.
+pub fn encode(added: &[SocketAddr], dropped: &[SocketAddr]) -> Vec<u8> {
+    let (added, added6) = compact(added);
+    let (dropped, dropped6) = compact(dropped);
+    // We don't know anything about the peers we pass on, so no flags are
+    // set.
+    let added_f = vec![0u8; added.len() / 6];
+    let added6_f = vec![0u8; added6.len() / 18];
+
+    let mut dict = BTreeMap::new();
+    dict.insert("added".as_bytes(), str_val(&added));
+    dict.insert("added.f".as_bytes(), str_val(&added_f));
+    dict.insert("added6".as_bytes(), str_val(&added6));
+    dict.insert("added6.f".as_bytes(), str_val(&added6_f));
+    dict.insert("dropped".as_bytes(), str_val(&dropped));
+    dict.insert("dropped6".as_bytes(), str_val(&dropped6));
+    bencode::encode(&dict_val(dict))
+}
+
+/// Packs peers into the compact IPv4 and IPv6 forms.
+fn compact(peers: &[SocketAddr]) -> (Vec<u8>, Vec<u8>) {
+    let mut v4 = vec![];
+    let mut v6 = vec![];
+    for peer in peers {
+        match peer.ip() {
+            IpAddr::V4(ip) => {
+                v4.extend(&ip.octets());
+                v4.extend(&peer.port().to_be_bytes());
+            }
+            IpAddr::V6(ip) => {
+                v6.extend(&ip.octets());
+                v6.extend(&peer.port().to_be_bytes());
+            }
+        }
+    }
+
+    (v4, v6)
+}
+
+/// What we've told one peer about the rest of the swarm.
+pub struct PexState {
+    sent: Vec<SocketAddr>,
+    last_sent: Option<Instant>,
+}
+
+impl PexState {
+    pub fn new() -> PexState {
+        PexState {
+            sent: vec![],
+            last_sent: None,
+        }
+    }
+
+    /// The payload of the next `ut_pex` message for the peer, if one is
+    /// due. `swarm` is every peer we're connected to apart from the one the
+    /// message is for.
+    pub fn next_msg(&mut self, swarm: &[SocketAddr]) -> Option<Vec<u8>> {
+        if let Some(last_sent) = self.last_sent {
+            if last_sent.elapsed() < PEX_INTERVAL {
+                return None;
+            }
+        }
+
+        let added: Vec<SocketAddr> = swarm
+            .iter()
+            .filter(|x| !self.sent.contains(x))
+            .take(MAX_PEERS_PER_MSG)
+            .cloned()
+            .collect();
+        let dropped: Vec<SocketAddr> = self
+            .sent
+            .iter()
+            .filter(|x| !swarm.contains(x))
+            .take(MAX_PEERS_PER_MSG)
+            .cloned()
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+
+        self.sent.retain(|x| !dropped.contains(x));
+        self.sent.extend(&added);
+        self.last_sent = Some(Instant::now());
+
+        Some(encode(&added, &dropped))
+    }
+}
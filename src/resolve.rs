@@ -0,0 +1,22 @@
+//! Turning host names into addresses, for trackers that list peers by name
+//! rather than by IP. A host can have several addresses, often both IPv4
+//! and IPv6, which are all kept so that connecting can try them at once.
+
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+
+pub trait Resolver: fmt::Debug + Send + Sync {
+    /// Every address of `host`, with `port`.
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>>;
+}
+
+/// The operating system's resolver.
+#[derive(Debug)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        Ok((host, port).to_socket_addrs()?.collect())
+    }
+}
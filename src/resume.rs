@@ -0,0 +1,136 @@
+//! Picking a download up where it left off. A sidecar file next to the
+//! output records which pieces have been written, so only the pieces it
+//! doesn't vouch for need hashing again. Files being downloaded can be
+//! kept under a `.part` name until they're complete, so that an interrupted
+//! download isn't mistaken for a finished one.
+
+use crate::bitfield::Bitfield;
+use crate::download::{self, Output, Piece};
+use sha1::{Digest, Sha1};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Where the sidecar for `output` goes: the output's path with `.bitfield`
+/// added.
+pub fn sidecar_path(output: &Path) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(".bitfield");
+    PathBuf::from(path)
+}
+
+/// Where the file at `path` is downloaded to until it's complete: its path
+/// with `.part` added.
+pub fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+/// `output` with each file that isn't finished moved to its `part_path`,
+/// to download into and then hand to `finish_parts`. A file is taken to be
+/// finished if it exists and has no part file beside it, such as one an
+/// earlier run renamed.
+pub fn part_output(output: &Output) -> Output {
+    let part = |path: &Path| {
+        let part = part_path(path);
+        if path.exists() && !part.exists() {
+            path.to_path_buf()
+        } else {
+            part
+        }
+    };
+    match output {
+        Output::File(path) => Output::File(part(path)),
+        Output::Files(files) => Output::Files(
+            files
+                .iter()
+                .map(|(path, length)| (part(path), *length))
+                .collect(),
+        ),
+        Output::Memory(buffer) => Output::Memory(buffer.clone()),
+    }
+}
+
+/// Renames the part files of `parts`, made by `part_output(output)`, to
+/// their names in `output`. Each rename replaces the file in one step, so
+/// only complete files ever appear under their own names.
+pub fn finish_parts(output: &Output, parts: &Output) -> io::Result<()> {
+    let renames = match (output, parts) {
+        (Output::File(path), Output::File(part)) => vec![(path, part)],
+        (Output::Files(files), Output::Files(parts)) => files
+            .iter()
+            .zip(parts)
+            .map(|((path, _), (part, _))| (path, part))
+            .collect(),
+        _ => vec![],
+    };
+    // Files with nothing in them are never written, so have no part file.
+    for (path, part) in renames {
+        if path != part && part.exists() {
+            fs::rename(part, path)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Records `written` in the sidecar at `path`: the torrent's info hash,
+/// then a bit per piece laid out as in a bitfield message. The file is
+/// replaced in one step, so a crash leaves the old one intact.
+pub fn save_bitfield(
+    path: &Path,
+    info_hash: &[u8; 20],
+    written: &[bool],
+) -> io::Result<()> {
+    let mut contents = info_hash.to_vec();
+    contents.extend(Bitfield::from_pieces(written).as_bytes());
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, &contents)?;
+    fs::rename(&tmp, path)
+}
+
+/// The pieces the sidecar at `path` says are written, or `None` if it's
+/// missing or belongs to a different torrent.
+pub fn load_bitfield(
+    path: &Path,
+    info_hash: &[u8; 20],
+    num_pieces: usize,
+) -> Option<Vec<bool>> {
+    let contents = fs::read(path).ok()?;
+    if contents.len() != 20 + (num_pieces + 7) / 8
+        || contents[..20] != info_hash[..]
+    {
+        return None;
+    }
+
+    let bitfield = Bitfield::from_bytes(&contents[20..], num_pieces);
+    Some((0..num_pieces).map(|index| bitfield.get(index)).collect())
+}
+
+/// Which pieces `output` already holds, by index, for passing to
+/// `download_from` as `have`. Pieces set in `known` are taken on trust, and
+/// the rest are read back and hashed.
+pub fn check_pieces(
+    pieces: &[Piece],
+    num_pieces: usize,
+    piece_len: u64,
+    output: &Output,
+    known: &[bool],
+) -> Vec<bool> {
+    let mut have = vec![false; num_pieces];
+    for piece in pieces {
+        let index = piece.index as usize;
+        have[index] = known.get(index) == Some(&true) || {
+            let offset = piece.index as u64 * piece_len;
+            match download::read_block(output, offset, piece.length as usize) {
+                Ok(data) => Sha1::digest(&data)[..] == piece.hash[..],
+                Err(_) => false,
+            }
+        };
+    }
+
+    have
+}
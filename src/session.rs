@@ -0,0 +1,442 @@
+//! Several torrents downloading side by side, controlled while they run,
+//! for a long-running process such as `torrent --daemon`. Each torrent
+//! downloads into the session's directory on a thread of its own. Pausing
+//! cancels its download, and resuming starts it again from the pieces the
+//! bitfield sidecar and the data on disk say are already there.
+//!
+//! Commands never wait on a download's thread, which may be waiting on a
+//! tracker. A stopped download's thread tells the trackers it stopped on
+//! its own, and a resumed download starts once the old thread is done.
+
+use crate::download::{self, DownloadConfig, Output, Piece, Progress};
+use crate::resume;
+use crate::tracker::{
+    AnnounceConfig, AnnounceState, HttpTrackerClient, TrackerList,
+};
+use crate::Error;
+use crate::TorrentMetaInfo;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TorrentState {
+    Downloading,
+    Paused,
+    Complete,
+    /// The download ended without every piece, for the reason given.
+    Failed(String),
+}
+
+impl fmt::Display for TorrentState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TorrentState::Downloading => write!(f, "downloading"),
+            TorrentState::Paused => write!(f, "paused"),
+            TorrentState::Complete => write!(f, "complete"),
+            TorrentState::Failed(reason) => write!(f, "failed ({})", reason),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TorrentStatus {
+    pub id: usize,
+    pub name: String,
+    pub state: TorrentState,
+    pub progress: Progress,
+}
+
+impl fmt::Display for TorrentStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The name goes last, as it can have spaces in it.
+        write!(
+            f,
+            "{} {} {}/{} {}",
+            self.id,
+            self.state,
+            self.progress.completed_pieces,
+            self.progress.total_pieces,
+            self.name
+        )
+    }
+}
+
+struct Torrent {
+    id: usize,
+    name: String,
+    info_hash: [u8; 20],
+    contents: Arc<Vec<u8>>,
+    progress: Arc<Mutex<Progress>>,
+    /// Set by the download's thread when it ends.
+    state: Arc<Mutex<TorrentState>>,
+    cancel: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Torrent {
+    /// Cancels the download, if it's running, without waiting for it to
+    /// end.
+    fn stop(&mut self) {
+        self.cancel.store(true, Ordering::SeqCst);
+    }
+}
+
+pub struct Session {
+    dir: PathBuf,
+    config: DownloadConfig,
+    torrents: Vec<Torrent>,
+    next_id: usize,
+}
+
+impl Session {
+    /// A session that downloads into `dir`, with `config` for every
+    /// download. Its `output` and `bitfield_file` are set per torrent.
+    pub fn new(dir: PathBuf, config: DownloadConfig) -> Session {
+        Session {
+            dir,
+            config,
+            torrents: vec![],
+            next_id: 1,
+        }
+    }
+
+    /// Adds the torrent in `contents` and starts downloading it, returning
+    /// the id it goes by.
+    pub fn add(&mut self, contents: Vec<u8>) -> Result<usize, Error> {
+        let (name, info_hash) = {
+            let metainfo = TorrentMetaInfo::new(&contents)?;
+            (metainfo.info.name.to_string(), metainfo.info.hash)
+        };
+        if self.torrents.iter().any(|x| x.info_hash == info_hash) {
+            return Err(From::from(format!("{} is already added", name)));
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut torrent = Torrent {
+            id,
+            name,
+            info_hash,
+            contents: Arc::new(contents),
+            progress: Arc::new(Mutex::new(Progress::default())),
+            state: Arc::new(Mutex::new(TorrentState::Paused)),
+            cancel: Arc::new(AtomicBool::new(false)),
+            thread: None,
+        };
+        start(&mut torrent, &self.dir, &self.config);
+        self.torrents.push(torrent);
+        Ok(id)
+    }
+
+    pub fn list(&self) -> Vec<TorrentStatus> {
+        self.torrents.iter().map(status).collect()
+    }
+
+    pub fn status(&self, id: usize) -> Result<TorrentStatus, Error> {
+        self.torrents
+            .iter()
+            .find(|x| x.id == id)
+            .map(status)
+            .ok_or_else(|| no_torrent(id))
+    }
+
+    /// Stops downloading torrent `id` until it's resumed. A failed
+    /// download is paused too, rather than left to be retried.
+    pub fn pause(&mut self, id: usize) -> Result<(), Error> {
+        let torrent = self.torrent(id)?;
+        if *torrent.state.lock().unwrap() != TorrentState::Complete {
+            torrent.stop();
+            *torrent.state.lock().unwrap() = TorrentState::Paused;
+        }
+        Ok(())
+    }
+
+    /// Starts downloading torrent `id` again, after it was paused or
+    /// failed.
+    pub fn resume(&mut self, id: usize) -> Result<(), Error> {
+        let index = self.index(id)?;
+        let torrent = &mut self.torrents[index];
+        let state = torrent.state.lock().unwrap().clone();
+        match state {
+            TorrentState::Downloading | TorrentState::Complete => {}
+            TorrentState::Paused | TorrentState::Failed(_) => {
+                torrent.stop();
+                start(torrent, &self.dir, &self.config);
+            }
+        }
+        Ok(())
+    }
+
+    /// Stops downloading torrent `id` and forgets it. Whatever it
+    /// downloaded is left in place.
+    pub fn remove(&mut self, id: usize) -> Result<(), Error> {
+        let index = self.index(id)?;
+        self.torrents.remove(index).stop();
+        Ok(())
+    }
+
+    /// Carries out one line of the control protocol, returning the reply.
+    /// Commands are `add <torrent file>`, `list`, and `status`, `pause`,
+    /// `resume` or `remove` followed by a torrent's id. Replies end with a
+    /// line of `ok`, or `ok <id>` for `add`, or with `error <reason>`, and
+    /// `list` and `status` put a line for each torrent before it.
+    pub fn command(&mut self, line: &str) -> String {
+        let mut words = line.trim().splitn(2, ' ');
+        let command = words.next().unwrap_or("");
+        let arg = words.next().unwrap_or("").trim();
+        let id = || {
+            arg.parse::<usize>()
+                .map_err(|_| Error::from(format!("bad torrent id {:?}", arg)))
+        };
+        let reply = match command {
+            "add" => fs::read(arg)
+                .map_err(Error::from)
+                .and_then(|contents| self.add(contents))
+                .map(|id| format!("ok {}\n", id)),
+            "list" => Ok(lines(&self.list())),
+            "status" => {
+                id().and_then(|id| self.status(id)).map(|x| lines(&[x]))
+            }
+            "pause" => id().and_then(|id| self.pause(id)).map(|_| ok()),
+            "resume" => id().and_then(|id| self.resume(id)).map(|_| ok()),
+            "remove" => id().and_then(|id| self.remove(id)).map(|_| ok()),
+            _ => Err(From::from(format!("unknown command {:?}", command))),
+        };
+        reply.unwrap_or_else(|e| format!("error {}\n", e))
+    }
+
+    fn index(&self, id: usize) -> Result<usize, Error> {
+        self.torrents
+            .iter()
+            .position(|x| x.id == id)
+            .ok_or_else(|| no_torrent(id))
+    }
+
+    fn torrent(&mut self, id: usize) -> Result<&mut Torrent, Error> {
+        let index = self.index(id)?;
+        Ok(&mut self.torrents[index])
+    }
+}
+
+/// Starts downloading `torrent` on a thread of its own, once the thread of
+/// its last download has ended.
+fn start(torrent: &mut Torrent, dir: &Path, config: &DownloadConfig) {
+    let cancel = Arc::new(AtomicBool::new(false));
+    torrent.cancel = cancel.clone();
+    *torrent.state.lock().unwrap() = TorrentState::Downloading;
+
+    let previous = torrent.thread.take();
+    let contents = torrent.contents.clone();
+    let dir = dir.to_path_buf();
+    let config = config.clone();
+    let progress = torrent.progress.clone();
+    let state = torrent.state.clone();
+    torrent.thread = Some(thread::spawn(move || {
+        if let Some(previous) = previous {
+            let _ = previous.join();
+        }
+        let result = download(&contents, &dir, &config, progress, &cancel);
+        // A paused download's state is set by `pause`.
+        if cancel.load(Ordering::SeqCst) {
+            return;
+        }
+        *state.lock().unwrap() = match result {
+            Ok(()) => TorrentState::Complete,
+            Err(e) => TorrentState::Failed(e.to_string()),
+        };
+    }));
+}
+
+impl Drop for Session {
+    /// Stops every download and waits for the trackers to be told.
+    fn drop(&mut self) {
+        for torrent in self.torrents.iter_mut() {
+            torrent.stop();
+        }
+        for torrent in self.torrents.iter_mut() {
+            if let Some(thread) = torrent.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+/// Downloads the torrent in `contents` into `dir`, laid out as the `torrent`
+/// command lays it out, until it's complete or `cancel` is set. Trackers are
+/// announced to again as they ask, or when the download runs out of peers,
+/// and told when it completes and stops.
+fn download(
+    contents: &Arc<Vec<u8>>,
+    dir: &Path,
+    config: &DownloadConfig,
+    progress: Arc<Mutex<Progress>>,
+    cancel: &Arc<AtomicBool>,
+) -> Result<(), Error> {
+    let metainfo = TorrentMetaInfo::new(contents)?;
+    let info = &metainfo.info;
+    let total_length = info.total_length();
+    let mut pieces: Vec<Piece> = info
+        .piece_hashes()
+        .enumerate()
+        .map(|(index, hash)| {
+            let start = index as u64 * info.piece_length;
+            let length = total_length.saturating_sub(start);
+            Piece::new(index as u32, length.min(info.piece_length), hash)
+        })
+        .collect();
+
+    let output = Output::for_torrent(info, dir)?;
+    output.create_empty_files()?;
+    // `for_torrent` has checked the name is safe to join.
+    let root = dir.join(info.name);
+    let parts = resume::part_output(&output);
+    let sidecar = resume::sidecar_path(&root);
+    let known = resume::load_bitfield(&sidecar, &info.hash, pieces.len())
+        .unwrap_or_default();
+    let have = resume::check_pieces(
+        &pieces,
+        pieces.len(),
+        info.piece_length,
+        &parts,
+        &known,
+    );
+
+    if have.iter().all(|&x| x) {
+        let total_bytes = pieces.iter().map(|x| x.length).sum();
+        *progress.lock().unwrap() = Progress {
+            completed_pieces: pieces.len(),
+            total_pieces: pieces.len(),
+            downloaded_bytes: total_bytes,
+            total_bytes,
+            ..Default::default()
+        };
+        resume::finish_parts(&output, &parts)?;
+        return Ok(());
+    }
+
+    let client = HttpTrackerClient::new()?;
+    let mut trackers = TrackerList::new(&metainfo).cancel(cancel.clone());
+    let mut announce_state = AnnounceState::new();
+    let response = trackers.announce_with(
+        &client,
+        &metainfo,
+        &AnnounceConfig {
+            port: None,
+            event: announce_state.next_event(false),
+            ..Default::default()
+        },
+    )?;
+
+    let (reannounce, reannounce_requests) = mpsc::channel();
+    let (peer_sender, new_peers) = mpsc::channel();
+    {
+        let contents = contents.clone();
+        let client = client.clone();
+        let progress = progress.clone();
+        thread::spawn(move || {
+            let metainfo = match TorrentMetaInfo::new(&contents) {
+                Ok(metainfo) => metainfo,
+                Err(_) => return,
+            };
+            trackers.keep_announcing(
+                &client,
+                &metainfo,
+                &reannounce_requests,
+                &peer_sender,
+                || AnnounceConfig {
+                    port: None,
+                    downloaded: progress.lock().unwrap().downloaded_bytes,
+                    ..Default::default()
+                },
+            );
+        });
+    }
+    let config = DownloadConfig {
+        output: parts.clone(),
+        bitfield_file: Some(sidecar),
+        disable_pex: config.disable_pex || info.private,
+        files: info
+            .file_offsets()
+            .map(|(path, length, _)| (path.to_string(), length))
+            .collect(),
+        num_pieces: Some(pieces.len()),
+        total_length: Some(total_length),
+        reannounce: Some(reannounce),
+        ..config.clone()
+    };
+    let summary = download::download_from(
+        &mut pieces,
+        response.peers,
+        new_peers,
+        info.hash,
+        info.piece_length,
+        progress.clone(),
+        &config,
+        &have,
+        cancel.clone(),
+    );
+    // Ends the announcing thread.
+    drop(config);
+
+    let result = if summary.is_complete() {
+        resume::finish_parts(&output, &parts).map_err(Error::from)
+    } else {
+        Err(From::from(format!(
+            "{} pieces could not be downloaded",
+            summary.incomplete_pieces.len()
+        )))
+    };
+
+    // The trackers went to the announcing thread, so the completed and
+    // stopped events go through a fresh list. `announce_with` sends events
+    // whatever a tracker's `min interval`, so the schedules the old list
+    // kept aren't missed. It's left without `cancel`, which stopping the
+    // torrent sets, so that trackers still hear about it.
+    let mut trackers = TrackerList::new(&metainfo);
+    let downloaded = progress.lock().unwrap().downloaded_bytes;
+    let events = vec![
+        announce_state.next_event(summary.is_complete()),
+        announce_state.stop(),
+    ];
+    for event in events.into_iter().filter_map(|x| x) {
+        let announce_config = AnnounceConfig {
+            port: None,
+            downloaded,
+            event: Some(event),
+            ..Default::default()
+        };
+        let _ = trackers.announce_with(&client, &metainfo, &announce_config);
+    }
+
+    result
+}
+
+fn status(torrent: &Torrent) -> TorrentStatus {
+    TorrentStatus {
+        id: torrent.id,
+        name: torrent.name.clone(),
+        state: torrent.state.lock().unwrap().clone(),
+        progress: *torrent.progress.lock().unwrap(),
+    }
+}
+
+fn lines(statuses: &[TorrentStatus]) -> String {
+    let mut reply = String::new();
+    for status in statuses {
+        reply.push_str(&format!("{}\n", status));
+    }
+    reply + &ok()
+}
+
+fn ok() -> String {
+    "ok\n".to_string()
+}
+
+fn no_torrent(id: usize) -> Error {
+    From::from(format!("no torrent {}", id))
+}
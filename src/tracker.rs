@@ -0,0 +1,1059 @@
+use crate::bencode;
+use crate::clock::{Clock, SystemClock};
+use crate::download::Peer;
+use crate::resolve::{Resolver, SystemResolver};
+use crate::Error;
+use crate::TorrentMetaInfo;
+use rand::seq::SliceRandom;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr};
+use std::str::{self, FromStr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use url::percent_encoding::{percent_encode, DEFAULT_ENCODE_SET};
+use url::Url;
+
+/// How long `HttpTrackerClient` waits for a tracker before giving up.
+pub const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Times a failed announce is retried before moving on to the next tracker.
+pub const ANNOUNCE_RETRIES: u32 = 2;
+/// Wait before the first retry, doubled for each one after.
+pub const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// The most a compressed tracker response is allowed to decompress to.
+pub const MAX_RESPONSE_SIZE: u64 = 1 << 24;
+
+/// The shortest `interval` a tracker is taken at, so that one answering
+/// with 0 isn't announced to over and over.
+pub const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Client-side state sent with every announce.
+pub struct AnnounceConfig {
+    pub peer_id: [u8; 20],
+    /// Port peers can reach us on, or `None` if we don't accept incoming
+    /// connections.
+    pub port: Option<u16>,
+    /// IPv6 address and port peers can reach us on, announced as BEP 7's
+    /// `ipv6` parameter so trackers can hand it out alongside the address we
+    /// announce from.
+    pub ipv6: Option<SocketAddr>,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    /// Lifecycle event to report, from an `AnnounceState`.
+    pub event: Option<Event>,
+}
+
+impl Default for AnnounceConfig {
+    fn default() -> AnnounceConfig {
+        AnnounceConfig {
+            peer_id: *b"01234567890123456789",
+            port: Some(6881),
+            ipv6: None,
+            uploaded: 0,
+            downloaded: 0,
+            event: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    Started,
+    Completed,
+    Stopped,
+}
+
+impl Event {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Event::Started => "started",
+            Event::Completed => "completed",
+            Event::Stopped => "stopped",
+        }
+    }
+}
+
+/// Where a download is in its lifecycle, as far as the tracker knows. Hands
+/// out the event for each announce so that `started` comes first,
+/// `completed` is sent at most once and only after `started`, and `stopped`
+/// comes last.
+#[derive(Debug, Default)]
+pub struct AnnounceState {
+    started: bool,
+    completed: bool,
+    stopped: bool,
+}
+
+impl AnnounceState {
+    pub fn new() -> AnnounceState {
+        Default::default()
+    }
+
+    /// The event for a regular announce, given whether the download is
+    /// complete.
+    pub fn next_event(&mut self, complete: bool) -> Option<Event> {
+        if self.stopped {
+            return None;
+        }
+
+        if !self.started {
+            self.started = true;
+            // Downloads that start out complete never send `completed`.
+            self.completed = complete;
+            return Some(Event::Started);
+        }
+
+        if complete && !self.completed {
+            self.completed = true;
+            return Some(Event::Completed);
+        }
+
+        None
+    }
+
+    /// The event for the final announce, or `None` if the tracker never
+    /// heard we started.
+    pub fn stop(&mut self) -> Option<Event> {
+        if !self.started || self.stopped {
+            return None;
+        }
+
+        self.stopped = true;
+        Some(Event::Stopped)
+    }
+}
+
+/// Announces to the torrent's tracker over HTTP and returns its response.
+pub fn announce(
+    metainfo: &TorrentMetaInfo,
+    config: &AnnounceConfig,
+) -> Result<TrackerResponse, Error> {
+    announce_with(&HttpTrackerClient::new()?, metainfo, config)
+}
+
+/// Like `announce`, but delivers the announce through `client`.
+pub fn announce_with<C: TrackerClient>(
+    client: &C,
+    metainfo: &TorrentMetaInfo,
+    config: &AnnounceConfig,
+) -> Result<TrackerResponse, Error> {
+    match &metainfo.announce {
+        Some(url) => announce_to(client, url, metainfo, config),
+        None => Err(From::from("torrent has no announce URL")),
+    }
+}
+
+/// Like `announce_with`, but to the tracker at `url` rather than the
+/// torrent's main one.
+pub fn announce_to<C: TrackerClient>(
+    client: &C,
+    url: &str,
+    metainfo: &TorrentMetaInfo,
+    config: &AnnounceConfig,
+) -> Result<TrackerResponse, Error> {
+    let total_length = metainfo.info.total_length();
+
+    let params = AnnounceParams {
+        announce: url,
+        info_hash: metainfo.info.hash,
+        peer_id: config.peer_id,
+        port: config.port,
+        ipv6: config.ipv6,
+        uploaded: config.uploaded,
+        downloaded: config.downloaded,
+        left: total_length.saturating_sub(config.downloaded),
+        compact: true,
+        event: config.event,
+    };
+
+    client.announce(&params)
+}
+
+/// A torrent's trackers, grouped into the tiers of its announce list
+/// (BEP 12). Announces go to the first tracker that answers, trying each
+/// tier in turn, and a tracker that answers is moved to the front of its
+/// tier so that later announces try it first. A tracker that fails is
+/// retried with exponential backoff before moving on, as trackers are often
+/// only briefly overloaded.
+///
+/// Each tracker's intervals are kept separately, so a tracker that asked
+/// for a `min interval` is skipped until it's passed, rather than announced
+/// to early because another tracker's interval ran out.
+pub struct TrackerList {
+    tiers: Vec<Vec<String>>,
+    retries: u32,
+    retry_delay: Duration,
+    clock: Arc<Clock>,
+    cancel: Option<Arc<AtomicBool>>,
+    /// The trackers that have answered, by URL.
+    schedules: HashMap<String, Schedule>,
+}
+
+/// When a tracker was last announced to and the intervals it answered with.
+struct Schedule {
+    announced: Instant,
+    interval: Option<Duration>,
+    min_interval: Option<Duration>,
+    /// Regular announces that have failed in a row since it last answered.
+    failures: u32,
+    /// When to try again after a failed regular announce, in place of
+    /// waiting out `interval`.
+    retry_at: Option<Instant>,
+}
+
+impl Schedule {
+    fn new(announced: Instant, response: &TrackerResponse) -> Schedule {
+        Schedule {
+            announced,
+            interval: response
+                .interval
+                .map(|x| Duration::from_secs(x).max(MIN_ANNOUNCE_INTERVAL)),
+            min_interval: response.min_interval.map(Duration::from_secs),
+            failures: 0,
+            retry_at: None,
+        }
+    }
+
+    /// When the next regular announce is due: once the tracker's `interval`
+    /// is up, or sooner if the last one failed. `None` if it never is.
+    fn next_due(&self) -> Option<Instant> {
+        self.retry_at
+            .or_else(|| self.announced.checked_add(self.interval?))
+    }
+
+    /// Whether a regular announce is due.
+    fn due(&self, now: Instant) -> bool {
+        self.next_due().map_or(false, |due| now >= due)
+    }
+
+    /// Whether a regular announce now would come before the tracker's
+    /// `min interval` is up.
+    fn too_soon(&self, now: Instant) -> bool {
+        match self.min_interval {
+            // A `min interval` too long to add up is never up.
+            Some(min_interval) => self
+                .announced
+                .checked_add(min_interval)
+                .map_or(true, |up| now < up),
+            None => false,
+        }
+    }
+}
+
+impl TrackerList {
+    /// The torrent's announce list, or its single tracker if it has none.
+    /// Trackers within a tier are shuffled, as BEP 12 asks.
+    pub fn new(metainfo: &TorrentMetaInfo) -> TrackerList {
+        let mut tiers = match &metainfo.announce_list {
+            Some(tiers) => tiers.clone(),
+            None => metainfo
+                .announce
+                .iter()
+                .map(|url| vec![url.to_string()])
+                .collect(),
+        };
+        tiers.retain(|tier| !tier.is_empty());
+
+        let mut rng = rand::thread_rng();
+        for tier in tiers.iter_mut() {
+            tier.shuffle(&mut rng);
+        }
+
+        TrackerList {
+            tiers,
+            retries: ANNOUNCE_RETRIES,
+            retry_delay: RETRY_DELAY,
+            clock: Arc::new(SystemClock),
+            cancel: None,
+            schedules: HashMap::new(),
+        }
+    }
+
+    /// Sets how many times a failed announce is retried. Defaults to
+    /// `ANNOUNCE_RETRIES`.
+    pub fn retries(mut self, retries: u32) -> TrackerList {
+        self.retries = retries;
+        self
+    }
+
+    /// Sets the wait before the first retry. Defaults to `RETRY_DELAY`.
+    pub fn retry_delay(mut self, retry_delay: Duration) -> TrackerList {
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    /// Sets the clock retries wait on. Defaults to `SystemClock`.
+    pub fn clock(mut self, clock: Arc<Clock>) -> TrackerList {
+        self.clock = clock;
+        self
+    }
+
+    /// Sets a flag that, once set, stops announces from retrying or moving
+    /// on to another tracker, so that a download being stopped doesn't wait
+    /// on trackers that don't answer. An announce already sent is still
+    /// waited for.
+    pub fn cancel(mut self, cancel: Arc<AtomicBool>) -> TrackerList {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    pub fn tiers(&self) -> &Vec<Vec<String>> {
+        &self.tiers
+    }
+
+    /// How long until the soonest regular announce is due, going by the
+    /// `interval` of each tracker that has answered. `None` if none of them
+    /// gave one.
+    pub fn next_announce(&self) -> Option<Duration> {
+        let now = self.clock.now();
+        self.schedules
+            .values()
+            .filter_map(Schedule::next_due)
+            .min()
+            .map(|due| due.saturating_duration_since(now))
+    }
+
+    /// Announces over HTTP to the first tracker that answers.
+    pub fn announce(
+        &mut self,
+        metainfo: &TorrentMetaInfo,
+        config: &AnnounceConfig,
+    ) -> Result<TrackerResponse, Error> {
+        self.announce_with(&HttpTrackerClient::new()?, metainfo, config)
+    }
+
+    /// Like `announce`, but delivers the announces through `client`. If
+    /// every tracker fails, returns the last attempt's error.
+    ///
+    /// Trackers whose `min interval` isn't up are skipped, unless the
+    /// announce reports an event, which trackers need to hear about
+    /// whenever it happens.
+    pub fn announce_with<C: TrackerClient>(
+        &mut self,
+        client: &C,
+        metainfo: &TorrentMetaInfo,
+        config: &AnnounceConfig,
+    ) -> Result<TrackerResponse, Error> {
+        let mut last_error = Error::from("torrent has no trackers");
+        for tier in self.tiers.iter_mut() {
+            for i in 0..tier.len() {
+                let now = self.clock.now();
+                let too_soon = config.event.is_none()
+                    && self
+                        .schedules
+                        .get(&tier[i])
+                        .map_or(false, |x| x.too_soon(now));
+                if too_soon {
+                    last_error = Error::from(format!(
+                        "{} asked not to be announced to again yet",
+                        tier[i]
+                    ));
+                    continue;
+                }
+
+                let mut delay = self.retry_delay;
+                for attempt in 0..=self.retries {
+                    if attempt > 0 {
+                        self.clock.sleep(delay);
+                        delay *= 2;
+                    }
+                    if is_set(&self.cancel) {
+                        return Err(From::from("announce cancelled"));
+                    }
+                    match announce_to(client, &tier[i], metainfo, config) {
+                        Ok(mut response) => {
+                            let schedule =
+                                Schedule::new(self.clock.now(), &response);
+                            self.schedules.insert(tier[i].clone(), schedule);
+                            let tracker = tier.remove(i);
+                            tier.insert(0, tracker);
+                            dedup_peers(&mut response.peers);
+                            return Ok(response);
+                        }
+                        Err(e) => {
+                            println!("announce to {} failed: {}", tier[i], e);
+                            last_error = e;
+                        }
+                    }
+                }
+            }
+        }
+
+        Err(last_error)
+    }
+
+    /// Announces to each tracker whose `interval` is up, returning the
+    /// responses of those that answer. A tracker that doesn't is tried again
+    /// later, backing off as `announce_with` does between retries, up to
+    /// the wait before its last retry.
+    pub fn announce_due_with<C: TrackerClient>(
+        &mut self,
+        client: &C,
+        metainfo: &TorrentMetaInfo,
+        config: &AnnounceConfig,
+    ) -> Vec<TrackerResponse> {
+        let now = self.clock.now();
+        let due: Vec<String> = self
+            .schedules
+            .iter()
+            .filter(|(_, schedule)| schedule.due(now))
+            .map(|(url, _)| url.clone())
+            .collect();
+
+        let mut responses = vec![];
+        for url in due {
+            if is_set(&self.cancel) {
+                break;
+            }
+            match announce_to(client, &url, metainfo, config) {
+                Ok(mut response) => {
+                    let schedule = Schedule::new(self.clock.now(), &response);
+                    self.schedules.insert(url, schedule);
+                    dedup_peers(&mut response.peers);
+                    responses.push(response);
+                }
+                Err(e) => {
+                    println!("announce to {} failed: {}", url, e);
+                    let schedule = self.schedules.get_mut(&url).unwrap();
+                    let doublings =
+                        schedule.failures.min(self.retries.saturating_sub(1));
+                    let delay = self
+                        .retry_delay
+                        .checked_mul(2u32.saturating_pow(doublings))
+                        .unwrap_or(self.retry_delay);
+                    schedule.failures = schedule.failures.saturating_add(1);
+                    schedule.retry_at = self.clock.now().checked_add(delay);
+                }
+            }
+        }
+
+        responses
+    }
+
+    /// Announces whenever a tracker is due or `requests` asks for peers,
+    /// sending the peers that come back to `peers`, until either channel
+    /// hangs up. `config` makes each announce's config as it's sent, so it
+    /// can report how much has been downloaded by then.
+    pub fn keep_announcing<C, F>(
+        &mut self,
+        client: &C,
+        metainfo: &TorrentMetaInfo,
+        requests: &Receiver<()>,
+        peers: &Sender<Peer>,
+        config: F,
+    ) where
+        C: TrackerClient,
+        F: Fn() -> AnnounceConfig,
+    {
+        loop {
+            let asked = match self.next_announce() {
+                Some(wait) => requests.recv_timeout(wait),
+                None => {
+                    requests.recv().map_err(|_| RecvTimeoutError::Disconnected)
+                }
+            };
+            let config = config();
+            let responses = match asked {
+                Ok(()) => match self.announce_with(client, metainfo, &config) {
+                    Ok(response) => vec![response],
+                    Err(e) => {
+                        println!("{}", e);
+                        continue;
+                    }
+                },
+                Err(RecvTimeoutError::Timeout) => {
+                    self.announce_due_with(client, metainfo, &config)
+                }
+                Err(RecvTimeoutError::Disconnected) => return,
+            };
+            for peer in responses.into_iter().flat_map(|x| x.peers) {
+                if peers.send(peer).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+fn is_set(flag: &Option<Arc<AtomicBool>>) -> bool {
+    flag.as_ref().map_or(false, |x| x.load(Ordering::SeqCst))
+}
+
+/// Adds the peers in `new` that aren't in `peers` already, for merging the
+/// responses of several trackers.
+pub fn merge_peers(peers: &mut Vec<Peer>, new: Vec<Peer>) {
+    peers.extend(new);
+    dedup_peers(peers);
+}
+
+/// Removes repeated peers, keeping the first of each address.
+fn dedup_peers(peers: &mut Vec<Peer>) {
+    let mut seen = HashSet::new();
+    peers.retain(|peer| seen.insert(*peer));
+}
+
+pub struct AnnounceParams<'a> {
+    pub announce: &'a str,
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub port: Option<u16>,
+    pub ipv6: Option<SocketAddr>,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    /// Ask for the compact peer list.
+    pub compact: bool,
+    pub event: Option<Event>,
+}
+
+impl<'a> AnnounceParams<'a> {
+    pub fn url(&self) -> Result<Url, Error> {
+        let info_hash = format!(
+            "info_hash={}",
+            percent_encode(&self.info_hash, DEFAULT_ENCODE_SET).to_string()
+        );
+        let peer_id = format!(
+            "peer_id={}",
+            percent_encode(&self.peer_id, DEFAULT_ENCODE_SET).to_string()
+        );
+        let uploaded = format!("uploaded={}", self.uploaded);
+        let downloaded = format!("downloaded={}", self.downloaded);
+        let left = format!("left={}", self.left);
+        let compact = format!("compact={}", if self.compact { 1 } else { 0 });
+
+        let mut params = vec![info_hash, peer_id];
+        if let Some(port) = self.port {
+            params.push(format!("port={}", port));
+        }
+        if let Some(ipv6) = self.ipv6 {
+            let ipv6 = ipv6.to_string();
+            params.push(format!(
+                "ipv6={}",
+                percent_encode(ipv6.as_bytes(), DEFAULT_ENCODE_SET)
+            ));
+        }
+        params.extend(vec![uploaded, downloaded, left, compact]);
+        if let Some(event) = self.event {
+            params.push(format!("event={}", event.as_str()));
+        }
+        let params = params.join("&");
+
+        let mut url = Url::parse(self.announce)?;
+        url.set_query(Some(&params));
+        Ok(url)
+    }
+}
+
+pub struct TrackerResponse {
+    /// Seconds the client should wait between regular announces.
+    pub interval: Option<u64>,
+    /// Announces more frequent than this many seconds may be rejected.
+    pub min_interval: Option<u64>,
+    /// Opaque id to send back with the next announce.
+    pub tracker_id: Option<String>,
+    /// Number of seeders in the swarm.
+    pub complete: Option<u64>,
+    /// Number of leechers in the swarm.
+    pub incomplete: Option<u64>,
+    /// Something the tracker wants shown to the user. Unlike a failure
+    /// reason, which comes back as `Error::TrackerFailure`, the rest of the
+    /// response is still good.
+    pub warning_message: Option<String>,
+    pub peers: Vec<Peer>,
+    /// Which of the two peer list models the tracker answered with.
+    pub peers_format: PeersFormat,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PeersFormat {
+    /// A byte string of packed addresses, as asked for with `compact=1`.
+    Compact,
+    /// A list of dictionaries with `peer id`, `ip` and `port`.
+    Dictionary,
+}
+
+/// Transport used to deliver an announce to a tracker. Implement this to
+/// route announces through a proxy or a different HTTP client.
+pub trait TrackerClient {
+    fn announce(
+        &self,
+        params: &AnnounceParams,
+    ) -> Result<TrackerResponse, Error>;
+}
+
+/// The default `TrackerClient`, announcing over plain HTTP with `reqwest`.
+/// The tracker's own host name is looked up by `reqwest`.
+#[derive(Clone)]
+pub struct HttpTrackerClient {
+    client: reqwest::Client,
+    /// Looks up peers that the tracker lists by host name.
+    resolver: Arc<Resolver>,
+}
+
+impl TrackerClient for HttpTrackerClient {
+    /// Some trackers reject `compact=1` or mangle their compact reply, so if
+    /// a compact reply can't be parsed, or the tracker refuses the announce
+    /// over it, it's retried once without it.
+    fn announce(
+        &self,
+        params: &AnnounceParams,
+    ) -> Result<TrackerResponse, Error> {
+        let response = self.fetch(params)?;
+        match parse_response_with(&response, &*self.resolver) {
+            Err(e) if params.compact && compact_failed(&e) => {
+                println!("compact announce failed, retrying: {}", e);
+                let params = AnnounceParams {
+                    compact: false,
+                    ..*params
+                };
+                parse_response_with(&self.fetch(&params)?, &*self.resolver)
+            }
+            result => result,
+        }
+    }
+}
+
+impl HttpTrackerClient {
+    /// A client that gives up on trackers after `ANNOUNCE_TIMEOUT`.
+    pub fn new() -> Result<HttpTrackerClient, Error> {
+        HttpTrackerClient::with_timeout(ANNOUNCE_TIMEOUT)
+    }
+
+    pub fn with_timeout(timeout: Duration) -> Result<HttpTrackerClient, Error> {
+        let client = reqwest::Client::builder().timeout(timeout).build()?;
+        Ok(HttpTrackerClient {
+            client,
+            resolver: Arc::new(SystemResolver),
+        })
+    }
+
+    /// Sets the resolver for peers listed by host name. Defaults to
+    /// `SystemResolver`.
+    pub fn resolver(mut self, resolver: Arc<Resolver>) -> HttpTrackerClient {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Sends the announce and returns the body of a successful response.
+    fn fetch(&self, params: &AnnounceParams) -> Result<Vec<u8>, Error> {
+        let url = params.url()?;
+        let mut body = match self.client.get(url.clone()).send() {
+            Ok(body) => body,
+            // Left as a `reqwest::Error` so callers can tell with
+            // `Error::is_timeout` and move on to another tracker.
+            Err(e) if e.is_timeout() => return Err(Error::Http(e)),
+            Err(e) => {
+                return Err(From::from(format!(
+                    "announce to {} failed: {}",
+                    url, e
+                )))
+            }
+        };
+        let mut body_buf: Vec<u8> = vec![];
+        body.copy_to(&mut body_buf)?;
+
+        let status = body.status();
+        if !status.is_success() {
+            return Err(From::from(format!(
+                "tracker responded with HTTP {} {}: {}",
+                status.as_u16(),
+                status.canonical_reason().unwrap_or(""),
+                String::from_utf8_lossy(&body_buf).trim()
+            )));
+        }
+
+        Ok(body_buf)
+    }
+}
+
+/// Whether `error`, from parsing the reply to a compact announce, may be
+/// down to asking for a compact reply: the reply didn't parse, or the
+/// tracker refused the announce and mentioned compact replies in its
+/// reason.
+fn compact_failed(error: &Error) -> bool {
+    match error {
+        Error::TrackerFailure(reason) => {
+            reason.to_lowercase().contains("compact")
+        }
+        _ => true,
+    }
+}
+
+pub fn parse_response(response: &[u8]) -> Result<TrackerResponse, Error> {
+    parse_response_with(response, &SystemResolver)
+}
+
+/// Like `parse_response`, but looks up peers listed by host name with
+/// `resolver`. A peer with several addresses is listed once for each.
+pub fn parse_response_with(
+    response: &[u8],
+    resolver: &Resolver,
+) -> Result<TrackerResponse, Error> {
+    let response = &decompress(response)?[..];
+    let dict = match bencode::decode(response) {
+        Ok(bencode::BencodeVal::Dict {
+            index: _,
+            size: _,
+            dict,
+        }) => dict,
+        // Broken trackers answer with error pages, which are worth telling
+        // apart from a garbled response.
+        _ if looks_like_html(response) => {
+            return Err(From::from(format!(
+                "tracker returned an HTML page instead of bencode: {}",
+                snippet(response)
+            )));
+        }
+        Ok(_) => {
+            return Err(From::from(format!(
+                "response should be a dictionary: {}",
+                snippet(response)
+            )));
+        }
+        Err(e) => {
+            return Err(From::from(format!(
+                "tracker returned malformed bencode ({}): {}",
+                e,
+                snippet(response)
+            )));
+        }
+    };
+
+    // A failure reason means the announce was refused and nothing else in
+    // the response counts, whereas a warning comes with a normal response.
+    if let Some(reason) = optional_str(&dict, "failure reason")? {
+        return Err(Error::TrackerFailure(reason));
+    }
+
+    let warning_message = optional_str(&dict, "warning message")?;
+    let interval = optional_int(&dict, "interval")?;
+    let min_interval = optional_int(&dict, "min interval")?;
+    let tracker_id = optional_str(&dict, "tracker id")?;
+    let complete = optional_int(&dict, "complete")?;
+    let incomplete = optional_int(&dict, "incomplete")?;
+
+    let (mut peers, peers_format) = match dict.get("peers".as_bytes()) {
+        Some(bencode::BencodeVal::Str {
+            index: _,
+            size: _,
+            byte_str,
+        }) => (parse_compact_peers(byte_str)?, PeersFormat::Compact),
+        Some(bencode::BencodeVal::List {
+            index: _,
+            size: _,
+            list,
+        }) => (parse_dict_peers(list, resolver)?, PeersFormat::Dictionary),
+        _ => return Err(From::from("peers should be a byte string or a list")),
+    };
+    // IPv6 peers come separately in a compact response (BEP 7).
+    match dict.get("peers6".as_bytes()) {
+        Some(bencode::BencodeVal::Str {
+            index: _,
+            size: _,
+            byte_str,
+        }) => peers.extend(parse_compact_peers6(byte_str)?),
+        Some(_) => return Err(From::from("peers6 should be a byte string")),
+        None => {}
+    }
+
+    Ok(TrackerResponse {
+        interval,
+        min_interval,
+        tracker_id,
+        complete,
+        incomplete,
+        warning_message,
+        peers,
+        peers_format,
+    })
+}
+
+/// Encodes a response the way a tracker would send it. IPv4 peers in a
+/// compact response go in `peers`, and IPv6 ones in BEP 7's `peers6`.
+pub fn encode_response(response: &TrackerResponse) -> Vec<u8> {
+    let mut dict = BTreeMap::new();
+    let ints = [
+        ("interval", response.interval),
+        ("min interval", response.min_interval),
+        ("complete", response.complete),
+        ("incomplete", response.incomplete),
+    ];
+    for &(key, val) in &ints {
+        if let Some(val) = val {
+            dict.insert(key.as_bytes(), bencode::int_val(val as i64));
+        }
+    }
+    if let Some(tracker_id) = &response.tracker_id {
+        dict.insert(
+            "tracker id".as_bytes(),
+            bencode::str_val(tracker_id.as_bytes()),
+        );
+    }
+    if let Some(warning_message) = &response.warning_message {
+        dict.insert(
+            "warning message".as_bytes(),
+            bencode::str_val(warning_message.as_bytes()),
+        );
+    }
+
+    let mut peers = vec![];
+    let mut peers6 = vec![];
+    let ips: Vec<String> = response
+        .peers
+        .iter()
+        .map(|x| x.socket.ip().to_string())
+        .collect();
+    match response.peers_format {
+        PeersFormat::Compact => {
+            for peer in &response.peers {
+                let port = peer.socket.port().to_be_bytes();
+                match peer.socket.ip() {
+                    IpAddr::V4(ip) => {
+                        peers.extend(&ip.octets());
+                        peers.extend(&port);
+                    }
+                    IpAddr::V6(ip) => {
+                        peers6.extend(&ip.octets());
+                        peers6.extend(&port);
+                    }
+                }
+            }
+            dict.insert("peers".as_bytes(), bencode::str_val(&peers));
+            if !peers6.is_empty() {
+                dict.insert("peers6".as_bytes(), bencode::str_val(&peers6));
+            }
+        }
+        PeersFormat::Dictionary => {
+            let list = response
+                .peers
+                .iter()
+                .zip(&ips)
+                .map(|(peer, ip)| {
+                    let mut peer_dict = BTreeMap::new();
+                    if let Some(id) = &peer.id {
+                        peer_dict
+                            .insert("peer id".as_bytes(), bencode::str_val(id));
+                    }
+                    peer_dict.insert(
+                        "ip".as_bytes(),
+                        bencode::str_val(ip.as_bytes()),
+                    );
+                    peer_dict.insert(
+                        "port".as_bytes(),
+                        bencode::int_val(peer.socket.port() as i64),
+                    );
+                    bencode::dict_val(peer_dict)
+                })
+                .collect();
+            dict.insert("peers".as_bytes(), bencode::list_val(list));
+        }
+    }
+
+    bencode::encode(&bencode::dict_val(dict))
+}
+
+/// Parses the compact peer list, six bytes per peer: an IPv4 address
+/// followed by a port, both in network byte order.
+fn parse_compact_peers(bytes: &[u8]) -> Result<Vec<Peer>, Error> {
+    if bytes.len() % 6 != 0 {
+        return Err(From::from(
+            "compact peers length should be a multiple of 6",
+        ));
+    }
+
+    Ok(bytes
+        .chunks(6)
+        .map(|chunk| {
+            let ip = IpAddr::from([chunk[0], chunk[1], chunk[2], chunk[3]]);
+            let port = u16::from_be_bytes([chunk[4], chunk[5]]);
+            Peer {
+                id: None,
+                socket: SocketAddr::new(ip, port),
+            }
+        })
+        .collect())
+}
+
+/// Parses BEP 7's compact IPv6 peer list, eighteen bytes per peer: an IPv6
+/// address followed by a port, both in network byte order.
+fn parse_compact_peers6(bytes: &[u8]) -> Result<Vec<Peer>, Error> {
+    if bytes.len() % 18 != 0 {
+        return Err(From::from(
+            "compact peers6 length should be a multiple of 18",
+        ));
+    }
+
+    Ok(bytes
+        .chunks(18)
+        .map(|chunk| {
+            let mut ip = [0; 16];
+            ip.copy_from_slice(&chunk[..16]);
+            let port = u16::from_be_bytes([chunk[16], chunk[17]]);
+            Peer {
+                id: None,
+                socket: SocketAddr::new(IpAddr::from(ip), port),
+            }
+        })
+        .collect())
+}
+
+fn parse_dict_peers(
+    peers_list: &Vec<bencode::BencodeVal>,
+    resolver: &Resolver,
+) -> Result<Vec<Peer>, Error> {
+    let mut peers: Vec<Peer> = Vec::new();
+    for peer_dict in peers_list {
+        if let bencode::BencodeVal::Dict {
+            index: _,
+            size: _,
+            dict,
+        } = peer_dict
+        {
+            let id = match dict.get("peer id".as_bytes()) {
+                Some(&bencode::BencodeVal::Str {
+                    index: _,
+                    size: _,
+                    byte_str,
+                }) if byte_str.len() == 20 => {
+                    let mut array = [0u8; 20];
+                    array.clone_from_slice(&byte_str[0..20]);
+                    array
+                }
+                _ => return Err(From::from("peer id should be a byte string")),
+            };
+
+            let host = match dict.get("ip".as_bytes()) {
+                Some(bencode::BencodeVal::Str {
+                    index: _,
+                    size: _,
+                    byte_str,
+                }) => str::from_utf8(byte_str)?,
+                _ => return Err(From::from("ip should be a byte string")),
+            };
+
+            let port = match dict.get("port".as_bytes()) {
+                Some(bencode::BencodeVal::Int {
+                    index: _,
+                    size: _,
+                    int,
+                }) => u16::try_from(*int).ok(),
+                _ => None,
+            };
+            let port = match port {
+                Some(port) => port,
+                None => return Err(From::from("port should be a valid port")),
+            };
+
+            // The ip may be a host name, which can have several addresses.
+            let sockets = match IpAddr::from_str(host) {
+                Ok(ip) => vec![SocketAddr::new(ip, port)],
+                Err(_) => match resolver.resolve(host, port) {
+                    Ok(sockets) => sockets,
+                    Err(e) => {
+                        println!("can't resolve peer {}: {}", host, e);
+                        continue;
+                    }
+                },
+            };
+            peers.extend(sockets.into_iter().map(|socket| Peer {
+                id: Some(id),
+                socket,
+            }));
+        }
+    }
+
+    Ok(peers)
+}
+
+fn optional_int(
+    dict: &BTreeMap<&[u8], bencode::BencodeVal>,
+    key: &str,
+) -> Result<Option<u64>, Error> {
+    match dict.get(key.as_bytes()) {
+        Some(bencode::BencodeVal::Int {
+            index: _,
+            size: _,
+            int,
+        }) => match u64::try_from(*int) {
+            Ok(int) => Ok(Some(int)),
+            Err(_) => {
+                Err(From::from(format!("{} should not be negative", key)))
+            }
+        },
+        Some(_) => Err(From::from(format!("{} should be an integer", key))),
+        None => Ok(None),
+    }
+}
+
+/// Undoes gzip or zlib compression that a tracker applied without setting
+/// `Content-Encoding`. A bencoded response starts with `d`, so it can't be
+/// mistaken for either. Responses that decompress to more than
+/// `MAX_RESPONSE_SIZE` are refused.
+fn decompress(response: &[u8]) -> Result<Cow<[u8]>, Error> {
+    let mut decompressed = vec![];
+    // One byte over the limit is enough to tell it was exceeded.
+    let limit = MAX_RESPONSE_SIZE + 1;
+    if response.starts_with(&[0x1f, 0x8b]) {
+        libflate::gzip::Decoder::new(response)?
+            .take(limit)
+            .read_to_end(&mut decompressed)?;
+    } else if response.len() >= 2
+        && response[0] == 0x78
+        && u16::from_be_bytes([response[0], response[1]]) % 31 == 0
+    {
+        libflate::zlib::Decoder::new(response)?
+            .take(limit)
+            .read_to_end(&mut decompressed)?;
+    } else {
+        return Ok(Cow::Borrowed(response));
+    }
+
+    if decompressed.len() as u64 > MAX_RESPONSE_SIZE {
+        return Err(From::from(format!(
+            "tracker response decompresses to more than {} bytes",
+            MAX_RESPONSE_SIZE
+        )));
+    }
+    Ok(Cow::Owned(decompressed))
+}
+
+fn looks_like_html(response: &[u8]) -> bool {
+    response
+        .iter()
+        .find(|x| !x.is_ascii_whitespace())
+        .map_or(false, |&x| x == b'<')
+}
+
+/// The start of a response, for showing in errors.
+fn snippet(response: &[u8]) -> String {
+    const MAX_LEN: usize = 100;
+    let text =
+        String::from_utf8_lossy(&response[..response.len().min(MAX_LEN)]);
+    let text = text.trim();
+    if response.len() > MAX_LEN {
+        format!("{:?}...", text)
+    } else {
+        format!("{:?}", text)
+    }
+}
+
+fn optional_str(
+    dict: &BTreeMap<&[u8], bencode::BencodeVal>,
+    key: &str,
+) -> Result<Option<String>, Error> {
+    match dict.get(key.as_bytes()) {
+        Some(bencode::BencodeVal::Str {
+            index: _,
+            size: _,
+            byte_str,
+        }) => Ok(Some(String::from_utf8_lossy(byte_str).into_owned())),
+        Some(_) => Err(From::from(format!("{} should be a byte str", key))),
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,207 @@
+//! A minimal HTTP tracker, for tests and small private swarms.
+//!
+//! It keeps every swarm in memory and lists peers at the address they
+//! announced from, ignoring the `ip` and `ipv6` parameters. There's no
+//! scrape support.
+
+use crate::download::Peer;
+use crate::tracker::{self, Event, PeersFormat, TrackerResponse};
+use crate::Error;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use url::percent_encoding::percent_decode;
+
+/// Seconds clients are told to wait between announces.
+const INTERVAL: u64 = 1800;
+/// Peers sent when the announce doesn't ask for a number.
+const DEFAULT_NUMWANT: usize = 50;
+/// Longest request we'll read before giving up on it.
+const MAX_REQUEST_LEN: usize = 8192;
+
+struct SwarmPeer {
+    id: [u8; 20],
+    seed: bool,
+    last_seen: Instant,
+}
+
+type Swarms = HashMap<[u8; 20], HashMap<SocketAddr, SwarmPeer>>;
+
+pub struct TrackerServer {
+    listener: TcpListener,
+    swarms: Arc<Mutex<Swarms>>,
+}
+
+impl TrackerServer {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<TrackerServer, Error> {
+        Ok(TrackerServer {
+            listener: TcpListener::bind(addr)?,
+            swarms: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Answers announces until accepting a connection fails, handling each
+    /// connection on its own thread.
+    pub fn run(&self) -> Result<(), Error> {
+        loop {
+            let (stream, addr) = self.listener.accept()?;
+            let swarms = self.swarms.clone();
+            thread::spawn(move || {
+                if let Err(e) = handle_connection(stream, addr.ip(), &swarms) {
+                    println!("tracker: request from {} failed: {}", addr, e);
+                }
+            });
+        }
+    }
+}
+
+fn handle_connection(
+    mut stream: TcpStream,
+    ip: IpAddr,
+    swarms: &Mutex<Swarms>,
+) -> Result<(), Error> {
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    let mut request: Vec<u8> = vec![];
+    let mut buffer = [0u8; 1024];
+    while !request.windows(4).any(|x| x == b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST_LEN {
+            return Err(From::from("request is too long"));
+        }
+        let n = stream.read(&mut buffer)?;
+        if n == 0 {
+            return Err(From::from("connection closed mid-request"));
+        }
+        request.extend(&buffer[..n]);
+    }
+
+    // "GET /announce?info_hash=... HTTP/1.1"
+    let request_line = request.split(|&x| x == b'\r').next().unwrap();
+    let target = str::from_utf8(request_line)?
+        .split(' ')
+        .nth(1)
+        .ok_or("request line should have a target")?;
+    let query = target.splitn(2, '?').nth(1).unwrap_or("");
+
+    let body = match announce(query, ip, swarms) {
+        Ok(response) => tracker::encode_response(&response),
+        Err(e) => failure(&e.to_string()),
+    };
+    let header = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(header.as_bytes())?;
+    stream.write_all(&body)?;
+    Ok(())
+}
+
+fn announce(
+    query: &str,
+    ip: IpAddr,
+    swarms: &Mutex<Swarms>,
+) -> Result<TrackerResponse, Error> {
+    let params: HashMap<&str, Vec<u8>> = query
+        .split('&')
+        .filter_map(|x| {
+            let mut pair = x.splitn(2, '=');
+            let key = pair.next()?;
+            let val = percent_decode(pair.next()?.as_bytes()).collect();
+            Some((key, val))
+        })
+        .collect();
+    let param = |key: &str| -> Result<&str, Error> {
+        let val = params.get(key).ok_or_else(|| format!("missing {}", key))?;
+        Ok(str::from_utf8(val)?)
+    };
+
+    let info_hash = hash_param(&params, "info_hash")?;
+    let peer_id = hash_param(&params, "peer_id")?;
+    let socket = SocketAddr::new(ip, param("port")?.parse()?);
+    let seed = param("left").map_or(false, |x| x == "0");
+    let compact = param("compact").map_or(true, |x| x != "0");
+    let numwant = match param("numwant") {
+        Ok(numwant) => numwant.parse()?,
+        Err(_) => DEFAULT_NUMWANT,
+    };
+    let stopped =
+        param("event").map_or(false, |x| x == Event::Stopped.as_str());
+
+    let mut swarms = swarms.lock().unwrap();
+    let swarm = swarms.entry(info_hash).or_insert_with(HashMap::new);
+    // Peers that stop announcing have gone without saying so.
+    let timeout = Duration::from_secs(2 * INTERVAL);
+    swarm.retain(|_, x| x.last_seen.elapsed() < timeout);
+    if stopped {
+        swarm.remove(&socket);
+    } else {
+        swarm.insert(
+            socket,
+            SwarmPeer {
+                id: peer_id,
+                seed,
+                last_seen: Instant::now(),
+            },
+        );
+    }
+
+    let complete = swarm.values().filter(|x| x.seed).count();
+    let peers = if stopped {
+        vec![]
+    } else {
+        swarm
+            .iter()
+            .filter(|&(&x, _)| x != socket)
+            .take(numwant)
+            .map(|(&socket, peer)| Peer {
+                id: Some(peer.id),
+                socket,
+            })
+            .collect()
+    };
+
+    Ok(TrackerResponse {
+        interval: Some(INTERVAL),
+        min_interval: None,
+        tracker_id: None,
+        complete: Some(complete as u64),
+        incomplete: Some((swarm.len() - complete) as u64),
+        warning_message: None,
+        peers,
+        peers_format: if compact {
+            PeersFormat::Compact
+        } else {
+            PeersFormat::Dictionary
+        },
+    })
+}
+
+fn hash_param(
+    params: &HashMap<&str, Vec<u8>>,
+    key: &str,
+) -> Result<[u8; 20], Error> {
+    match params.get(key) {
+        Some(val) if val.len() == 20 => {
+            let mut hash = [0u8; 20];
+            hash.copy_from_slice(val);
+            Ok(hash)
+        }
+        Some(_) => Err(From::from(format!("{} should be 20 bytes", key))),
+        None => Err(From::from(format!("missing {}", key))),
+    }
+}
+
+fn failure(reason: &str) -> Vec<u8> {
+    let mut bytes = format!("d14:failure reason{}:", reason.len()).into_bytes();
+    bytes.extend(reason.as_bytes());
+    bytes.push(b'e');
+    bytes
+}
@@ -0,0 +1,93 @@
+extern crate torrent;
+
+use torrent::bencode::{self, BencodeVal, DecodeError};
+
+fn decode_str(bytes: &[u8]) -> Option<(&[u8], usize)> {
+    match bencode::decode(bytes) {
+        Ok(BencodeVal::Str {
+            index: _,
+            size,
+            byte_str,
+        }) => Some((byte_str, size)),
+        _ => None,
+    }
+}
+
+#[test]
+fn decodes_string_ending_at_eof() {
+    assert_eq!(decode_str(b"4:spam"), Some((&b"spam"[..], 6)));
+    assert_eq!(decode_str(b"0:"), Some((&b""[..], 2)));
+}
+
+#[test]
+fn rejects_truncated_string() {
+    assert_eq!(decode_str(b"5:spam"), None);
+    assert_eq!(decode_str(b"1:"), None);
+}
+
+#[test]
+fn rejects_bad_string_lengths() {
+    assert_eq!(decode_str(b":spam"), None);
+    assert_eq!(decode_str(b"99999999999999999999999:spam"), None);
+    assert_eq!(decode_str(b"18446744073709551615:spam"), None);
+}
+
+#[test]
+fn reports_why_decoding_failed() {
+    let error = |bytes: &[u8]| bencode::decode(bytes).unwrap_err();
+    assert_eq!(error(b"i01e"), DecodeError::LeadingZero);
+    assert_eq!(error(b"i-0e"), DecodeError::NegativeZero);
+    assert_eq!(error(b"l4:spam"), DecodeError::Eof);
+    assert_eq!(error(b"d3:cow"), DecodeError::Eof);
+    assert_eq!(error(b"x"), DecodeError::UnexpectedChar(b'x'));
+    assert_eq!(
+        error(b"99999999999999999999999:spam"),
+        DecodeError::InvalidLength
+    );
+    assert_eq!(
+        bencode::dict_entry(b"li1ee", b"info").unwrap_err(),
+        DecodeError::NotDict
+    );
+}
+
+/// Checks `val` and everything nested in it re-decode from their own slice
+/// of `bytes` to the same value.
+fn check_sizes(bytes: &[u8], val: &BencodeVal) {
+    let (index, size) = match val {
+        BencodeVal::Int { index, size, .. } => (*index, *size),
+        BencodeVal::Str { index, size, .. } => (*index, *size),
+        BencodeVal::List { index, list, size } => {
+            for item in list {
+                check_sizes(bytes, item);
+            }
+            (*index, *size)
+        }
+        BencodeVal::Dict { index, dict, size } => {
+            for item in dict.values() {
+                check_sizes(bytes, item);
+            }
+            (*index, *size)
+        }
+    };
+    let slice = &bytes[index..index + size];
+    assert_eq!(bencode::decode(slice).unwrap(), *val, "{:?}", slice);
+}
+
+#[test]
+fn nested_sizes_cover_exact_encoding() {
+    let info = &b"d5:filesld6:lengthi-12e4:pathl1:a2:bceed6:lengthi0e\
+                  4:pathl10:0123456789eee4:name3:e:e12:piece lengthi16384e\
+                  4:listllleleedee6:pieces0:e"[..];
+    let mut torrent = b"d8:announce3:url4:info".to_vec();
+    torrent.extend(info);
+    torrent.extend(b"4:zzzzli1ei2eee");
+
+    let val = bencode::decode(&torrent).unwrap();
+    check_sizes(&torrent, &val);
+    match val.path(&[b"info"]) {
+        Some(BencodeVal::Dict { index, size, .. }) => {
+            assert_eq!(&torrent[*index..*index + *size], info);
+        }
+        _ => panic!("info should be a dict"),
+    }
+}
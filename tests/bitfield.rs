@@ -0,0 +1,40 @@
+extern crate torrent;
+
+use torrent::bitfield::Bitfield;
+
+#[test]
+fn sets_and_counts_pieces() {
+    let mut bitfield = Bitfield::from_pieces(&[true, false, false, true]);
+    bitfield.set(2);
+    assert!(bitfield.get(0) && !bitfield.get(1) && bitfield.get(2));
+    assert!(!bitfield.get(4));
+    assert_eq!(bitfield.count_ones(), 3);
+    assert_eq!(
+        bitfield.iter_set_indices().collect::<Vec<_>>(),
+        vec![0, 2, 3]
+    );
+    assert_eq!(bitfield.as_bytes(), &[0b1011_0000]);
+}
+
+#[test]
+fn clears_spare_bits() {
+    // 10 pieces fill a byte and the top two bits of the next.
+    let bitfield = Bitfield::from_bytes(&[0xff, 0xff, 0xff], 10);
+    assert_eq!(bitfield.as_bytes(), &[0xff, 0b1100_0000]);
+    assert_eq!(bitfield.count_ones(), 10);
+    assert_eq!(Bitfield::full(10), bitfield);
+
+    let short = Bitfield::from_bytes(&[0x80], 10);
+    assert_eq!(short.as_bytes(), &[0x80, 0]);
+}
+
+#[test]
+fn merges_pieces() {
+    let mut bitfield = Bitfield::default();
+    let mut have = Bitfield::new(12);
+    have.set(11);
+    bitfield.union(&have);
+    bitfield.union(&Bitfield::from_pieces(&[true]));
+    assert_eq!(bitfield.len(), 12);
+    assert_eq!(bitfield.iter_set_indices().collect::<Vec<_>>(), vec![0, 11]);
+}
@@ -0,0 +1,1384 @@
+extern crate sha1;
+extern crate torrent;
+
+use sha1::{Digest, Sha1};
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::process;
+use std::sync::atomic::AtomicBool;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use torrent::builder::TorrentBuilder;
+use torrent::clock::MockClock;
+use torrent::download::{
+    self, ConnectionLimit, DownloadConfig, DownloadState, FileCompleted,
+    Handshake, HandshakeResult, JobState, Output, Peer, Piece, Progress,
+    SyncPolicy,
+};
+use torrent::resume;
+use torrent::tracker::{self, AnnounceConfig};
+use torrent::TorrentMetaInfo;
+
+const PIECE_LENGTH: u64 = 1 << 14;
+
+/// Downloads a three-piece torrent from a seed on loopback, found through a
+/// tracker on loopback, and checks the bytes downloaded match the source.
+#[test]
+fn downloads_from_local_tracker_and_seed() {
+    let dir = env::temp_dir().join(format!("torrent-e2e-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    // The last piece is short, so the final block is a partial one.
+    let data: Vec<u8> = (0..2 * PIECE_LENGTH + 1000)
+        .map(|i| (i * 7 % 251) as u8)
+        .collect();
+    let source = dir.join("source");
+    fs::write(&source, &data).unwrap();
+
+    let seed = TcpListener::bind("127.0.0.1:0").unwrap();
+    let seed_addr = seed.local_addr().unwrap();
+    let tracker = TcpListener::bind("127.0.0.1:0").unwrap();
+    let announce = format!("http://{}/announce", tracker.local_addr().unwrap());
+
+    let contents = TorrentBuilder::new(&source)
+        .piece_length(PIECE_LENGTH)
+        .announce(&announce)
+        .build()
+        .unwrap();
+    let metainfo = TorrentMetaInfo::new(&contents).unwrap();
+    assert_eq!(metainfo.info.num_pieces(), 3);
+
+    thread::spawn(move || serve_tracker(tracker, seed_addr));
+    let seed_data = data.clone();
+    let info_hash = metainfo.info.hash;
+    thread::spawn(move || serve_seed(seed, info_hash, seed_data, None));
+
+    let response =
+        tracker::announce(&metainfo, &AnnounceConfig::default()).unwrap();
+    assert_eq!(response.peers.len(), 1);
+    assert_eq!(response.peers[0].socket, seed_addr);
+
+    let total_length = metainfo.info.total_length();
+    let mut pieces: Vec<Piece> = metainfo
+        .info
+        .piece_hashes()
+        .enumerate()
+        .map(|(index, hash)| {
+            let start = index as u64 * PIECE_LENGTH;
+            let length = std::cmp::min(PIECE_LENGTH, total_length - start);
+            Piece::new(index as u32, length, hash)
+        })
+        .collect();
+
+    // Nothing listens on this one, so connecting to it fails.
+    let dead_addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let mut peers = response.peers;
+    peers.push(Peer {
+        id: None,
+        socket: dead_addr,
+    });
+
+    let output = Arc::new(Mutex::new(vec![]));
+    let (file_completed, completed_files) = mpsc::channel();
+    let (handshakes, handshake_results) = mpsc::channel();
+    let progress = Arc::new(Mutex::new(Progress::default()));
+    let config = DownloadConfig {
+        timeout: Some(Duration::from_secs(30)),
+        output: Output::Memory(output.clone()),
+        // Pretend the data is split into a file within the first piece and
+        // one spanning the rest.
+        files: vec![
+            ("a".to_string(), 100),
+            ("b".to_string(), data.len() as u64 - 100),
+        ],
+        file_completed: Some(file_completed),
+        handshakes: Some(handshakes),
+        ..Default::default()
+    };
+    let (_, new_peers) = mpsc::channel();
+    let summary = download::download_from(
+        &mut pieces,
+        peers,
+        new_peers,
+        metainfo.info.hash,
+        metainfo.info.piece_length,
+        progress.clone(),
+        &config,
+        &[],
+        Arc::new(AtomicBool::new(false)),
+    );
+
+    assert!(summary.is_complete(), "{:?}", summary);
+    assert_eq!(*output.lock().unwrap(), data);
+    let mut completed: Vec<FileCompleted> =
+        completed_files.try_iter().collect();
+    completed.sort_by_key(|x| x.index);
+    assert_eq!(
+        completed,
+        vec![
+            FileCompleted {
+                index: 0,
+                path: "a".to_string(),
+            },
+            FileCompleted {
+                index: 1,
+                path: "b".to_string(),
+            },
+        ]
+    );
+
+    let mut peer_id = [0u8; 20];
+    peer_id.copy_from_slice(b"-SEED-00000000000000");
+    let results: Vec<HandshakeResult> = handshake_results.try_iter().collect();
+    assert_eq!(results.len(), 2);
+    let seed_result = results.iter().find(|x| x.socket == seed_addr).unwrap();
+    // The seed echoes our reserved bytes, so it claims the same extensions
+    // as us.
+    assert_eq!(
+        seed_result.outcome,
+        Ok(Handshake {
+            peer_id,
+            extensions: true,
+            fast: true,
+            encrypted: false,
+        })
+    );
+    let dead_result = results.iter().find(|x| x.socket == dead_addr).unwrap();
+    assert!(dead_result.outcome.is_err());
+    let progress = progress.lock().unwrap();
+    assert_eq!(progress.handshakes_succeeded, 1);
+    assert_eq!(progress.handshakes_failed, 1);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+/// Requests the short final piece at its own length, and checks it against
+/// a hash of just its bytes.
+#[test]
+fn verifies_short_final_piece() {
+    let dir = env::temp_dir().join(format!("torrent-short-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let data: Vec<u8> = (0..PIECE_LENGTH + 5000)
+        .map(|i| (i * 3 % 241) as u8)
+        .collect();
+    let source = dir.join("source");
+    fs::write(&source, &data).unwrap();
+    let contents = TorrentBuilder::new(&source)
+        .piece_length(PIECE_LENGTH)
+        .build()
+        .unwrap();
+    let metainfo = TorrentMetaInfo::new(&contents).unwrap();
+    let total_length = metainfo.info.total_length();
+    let mut pieces: Vec<Piece> = metainfo
+        .info
+        .piece_hashes()
+        .enumerate()
+        .map(|(index, hash)| {
+            let start = index as u64 * PIECE_LENGTH;
+            let length = std::cmp::min(PIECE_LENGTH, total_length - start);
+            Piece::new(index as u32, length, hash)
+        })
+        .collect();
+    assert_eq!(pieces.len(), 2);
+    assert_eq!(pieces[1].length, 5000);
+    assert_eq!(pieces[1].hash, piece_hash(&data, 1));
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let peer = Peer {
+        id: None,
+        socket: listener.local_addr().unwrap(),
+    };
+    let (to_test, requests) = mpsc::channel();
+    let info_hash = metainfo.info.hash;
+    let seed_data = data.clone();
+    thread::spawn(move || {
+        serve_seed(listener, info_hash, seed_data, Some(to_test))
+    });
+
+    let output = Arc::new(Mutex::new(vec![]));
+    let config = DownloadConfig {
+        timeout: Some(Duration::from_secs(30)),
+        output: Output::Memory(output.clone()),
+        ..Default::default()
+    };
+    let (_, new_peers) = mpsc::channel();
+    let summary = download::download_from(
+        &mut pieces,
+        vec![peer],
+        new_peers,
+        info_hash,
+        metainfo.info.piece_length,
+        Arc::new(Mutex::new(Progress::default())),
+        &config,
+        &[],
+        Arc::new(AtomicBool::new(false)),
+    );
+
+    assert!(summary.is_complete(), "{:?}", summary);
+    assert_eq!(summary.state.hash_failures, vec![0, 0]);
+    assert_eq!(*output.lock().unwrap(), data);
+    let last: Vec<(u32, u32, u32)> =
+        requests.try_iter().filter(|x| x.0 == 1).collect();
+    assert_eq!(last, vec![(1, 0, 5000)]);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+/// Splits the download across files, with pieces spanning file boundaries,
+/// and reads it back from them.
+#[test]
+fn writes_across_files() {
+    let dir = env::temp_dir().join(format!("torrent-files-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+
+    let data: Vec<u8> = (0..2 * PIECE_LENGTH + 1000)
+        .map(|i| (i * 11 % 241) as u8)
+        .collect();
+    let info_hash = [9; 20];
+    let mut pieces: Vec<Piece> = (0..3)
+        .map(|index| {
+            let start = index as u64 * PIECE_LENGTH;
+            let length = std::cmp::min(PIECE_LENGTH, data.len() as u64 - start);
+            Piece::new(index, length, piece_hash(&data, index))
+        })
+        .collect();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let peer = Peer {
+        id: None,
+        socket: listener.local_addr().unwrap(),
+    };
+    let seed_data = data.clone();
+    thread::spawn(move || serve_seed(listener, info_hash, seed_data, None));
+
+    let lengths =
+        [100, PIECE_LENGTH, 0, data.len() as u64 - 100 - PIECE_LENGTH];
+    let files: Vec<(std::path::PathBuf, u64)> = lengths
+        .iter()
+        .enumerate()
+        .map(|(i, &length)| (dir.join("sub").join(i.to_string()), length))
+        .collect();
+    let output = Output::Files(files.clone());
+    let config = DownloadConfig {
+        timeout: Some(Duration::from_secs(30)),
+        output: output.clone(),
+        ..Default::default()
+    };
+    let (_, new_peers) = mpsc::channel();
+    let summary = download::download_from(
+        &mut pieces,
+        vec![peer],
+        new_peers,
+        info_hash,
+        PIECE_LENGTH,
+        Arc::new(Mutex::new(Progress::default())),
+        &config,
+        &[],
+        Arc::new(AtomicBool::new(false)),
+    );
+    assert!(summary.is_complete(), "{:?}", summary);
+
+    let mut start = 0;
+    for (path, length) in &files {
+        let end = start + *length as usize;
+        // Nothing is written to an empty file, so it's never created.
+        if *length > 0 {
+            assert_eq!(fs::read(path).unwrap(), &data[start..end]);
+        }
+        start = end;
+    }
+    let have =
+        resume::check_pieces(&pieces, pieces.len(), PIECE_LENGTH, &output, &[]);
+    assert_eq!(have, vec![true; 3]);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+/// Saves the bitfield once the download ends when the output is only
+/// synced then.
+#[test]
+fn saves_bitfield_on_completion() {
+    let dir = env::temp_dir().join(format!("torrent-sync-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+
+    let data: Vec<u8> =
+        (0..2 * PIECE_LENGTH).map(|i| (i % 233) as u8).collect();
+    let info_hash = [6; 20];
+    let mut pieces: Vec<Piece> = (0..2)
+        .map(|index| Piece::new(index, PIECE_LENGTH, piece_hash(&data, index)))
+        .collect();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let peer = Peer {
+        id: None,
+        socket: listener.local_addr().unwrap(),
+    };
+    let seed_data = data.clone();
+    thread::spawn(move || serve_seed(listener, info_hash, seed_data, None));
+
+    let bitfield_file = dir.join("part.bitfield");
+    let config = DownloadConfig {
+        timeout: Some(Duration::from_secs(30)),
+        output: Output::File(dir.join("part")),
+        bitfield_file: Some(bitfield_file.clone()),
+        sync_policy: SyncPolicy::OnCompletion,
+        ..Default::default()
+    };
+    let (_, new_peers) = mpsc::channel();
+    let summary = download::download_from(
+        &mut pieces,
+        vec![peer],
+        new_peers,
+        info_hash,
+        PIECE_LENGTH,
+        Arc::new(Mutex::new(Progress::default())),
+        &config,
+        &[],
+        Arc::new(AtomicBool::new(false)),
+    );
+    assert!(summary.is_complete(), "{:?}", summary);
+    assert_eq!(fs::read(dir.join("part")).unwrap(), data);
+    assert_eq!(
+        resume::load_bitfield(&bitfield_file, &info_hash, 2),
+        Some(vec![true; 2])
+    );
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn restores_download_state() {
+    let new_pieces = || -> Vec<Piece> {
+        (0..4)
+            .map(|index| Piece::new(index, 100, [0; 20]))
+            .collect()
+    };
+    let mut pieces = new_pieces();
+    pieces[1].job_state = JobState::Done;
+    pieces[2].job_state = JobState::Downloading;
+    pieces[3].job_state = JobState::Failed;
+    pieces[3].hash_failures = 5;
+    let state = DownloadState::new(&pieces);
+    assert_eq!(state.downloaded_bytes, 100);
+    assert_eq!(state.total_bytes, 400);
+
+    let mut restored = new_pieces();
+    restored.reverse();
+    state.restore(&mut restored);
+    restored.reverse();
+    let job_states: Vec<JobState> =
+        restored.iter().map(|x| x.job_state).collect();
+    assert_eq!(
+        job_states,
+        vec![
+            JobState::Available,
+            JobState::Done,
+            JobState::Available,
+            JobState::Failed,
+        ]
+    );
+    assert!(restored[1].is_complete());
+    assert_eq!(restored[3].hash_failures, 5);
+}
+
+/// Downloads the last of three pieces from a peer that meanwhile requests
+/// blocks of the first two from us, and checks only the valid request is
+/// answered.
+#[test]
+fn serves_requested_blocks() {
+    let data: Vec<u8> =
+        (0..3 * PIECE_LENGTH).map(|i| (i % 253) as u8).collect();
+    let info_hash = [7; 20];
+    let mut pieces: Vec<Piece> = (0..3)
+        .map(|index| Piece::new(index, PIECE_LENGTH, piece_hash(&data, index)))
+        .collect();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let peer = Peer {
+        id: None,
+        socket: listener.local_addr().unwrap(),
+    };
+    let (to_test, served) = mpsc::channel();
+    let peer_data = data.clone();
+    thread::spawn(move || serve_leech(listener, info_hash, peer_data, to_test));
+
+    let output =
+        Arc::new(Mutex::new(data[..2 * PIECE_LENGTH as usize].to_vec()));
+    let config = DownloadConfig {
+        timeout: Some(Duration::from_secs(30)),
+        output: Output::Memory(output.clone()),
+        ..Default::default()
+    };
+    let (_, new_peers) = mpsc::channel();
+    let summary = download::download_from(
+        &mut pieces,
+        vec![peer],
+        new_peers,
+        info_hash,
+        PIECE_LENGTH,
+        Arc::new(Mutex::new(Progress::default())),
+        &config,
+        &[true, true, false],
+        Arc::new(AtomicBool::new(false)),
+    );
+
+    assert!(summary.is_complete(), "{:?}", summary);
+    assert_eq!(*output.lock().unwrap(), data);
+    let (begin, block) = served.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(begin, 100);
+    let start = PIECE_LENGTH as usize + 100;
+    assert_eq!(block, &data[start..start + 1000]);
+}
+
+/// Tells a fast extension peer we have everything with `have all`, or with
+/// a bitfield and `have` messages when the bitfield is lazy.
+#[test]
+fn advertises_pieces_with_fast_extension() {
+    let num_pieces = 6;
+    for &lazy in &[false, true] {
+        let mut pieces: Vec<Piece> = (0..num_pieces)
+            .map(|index| Piece::new(index, PIECE_LENGTH, [0; 20]))
+            .collect();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = Peer {
+            id: None,
+            socket: listener.local_addr().unwrap(),
+        };
+        let (to_test, received) = mpsc::channel();
+        let msgs = if lazy { 5 } else { 1 };
+        thread::spawn(move || serve_fast_peer(listener, msgs, to_test));
+
+        let config = DownloadConfig {
+            timeout: Some(Duration::from_secs(30)),
+            lazy_bitfield: lazy,
+            ..Default::default()
+        };
+        let (_, new_peers) = mpsc::channel();
+        let summary = download::download_from(
+            &mut pieces,
+            vec![peer],
+            new_peers,
+            [7; 20],
+            PIECE_LENGTH,
+            Arc::new(Mutex::new(Progress::default())),
+            &config,
+            &[true; 6],
+            Arc::new(AtomicBool::new(false)),
+        );
+        assert!(summary.is_complete(), "{:?}", summary);
+
+        let msgs = received.recv_timeout(Duration::from_secs(5)).unwrap();
+        if !lazy {
+            assert_eq!(msgs, vec![vec![0x0E]]);
+            continue;
+        }
+        // Four pieces held back from the bitfield, then sent as haves.
+        assert_eq!(msgs[0][0], 5);
+        let mut have = msgs[0][1];
+        assert_eq!(have.count_ones(), 2);
+        for msg in &msgs[1..] {
+            assert_eq!(msg[0], 4);
+            have |= 0b1000_0000 >> be_u32(&msg[1..5]);
+        }
+        assert_eq!(have, 0b1111_1100);
+    }
+}
+
+/// Sends a bitfield rather than `have all` when only some of the torrent's
+/// pieces are wanted, even if all of those are written.
+#[test]
+fn advertises_written_pieces_of_partial_download() {
+    let mut pieces: Vec<Piece> = (0..2)
+        .map(|index| Piece::new(index, PIECE_LENGTH, [0; 20]))
+        .collect();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let peer = Peer {
+        id: None,
+        socket: listener.local_addr().unwrap(),
+    };
+    let (to_test, received) = mpsc::channel();
+    thread::spawn(move || serve_fast_peer(listener, 1, to_test));
+
+    let config = DownloadConfig {
+        timeout: Some(Duration::from_secs(30)),
+        num_pieces: Some(4),
+        total_length: Some(4 * PIECE_LENGTH),
+        ..Default::default()
+    };
+    let (_, new_peers) = mpsc::channel();
+    let summary = download::download_from(
+        &mut pieces,
+        vec![peer],
+        new_peers,
+        [7; 20],
+        PIECE_LENGTH,
+        Arc::new(Mutex::new(Progress::default())),
+        &config,
+        &[true, true],
+        Arc::new(AtomicBool::new(false)),
+    );
+    assert!(summary.is_complete(), "{:?}", summary);
+
+    let msgs = received.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(msgs, vec![vec![5, 0b1100_0000]]);
+}
+
+/// Sets a reserved bit in the handshake for each extension that's enabled.
+#[test]
+fn advertises_enabled_extensions() {
+    let (dht_nodes, _) = mpsc::channel();
+    let configs = vec![
+        (DownloadConfig::default(), [0, 0, 0, 0, 0, 0x10, 0, 0x04]),
+        (
+            DownloadConfig {
+                disable_pex: true,
+                disable_fast: true,
+                ..Default::default()
+            },
+            [0; 8],
+        ),
+        (
+            DownloadConfig {
+                disable_pex: true,
+                dht_nodes: Some(dht_nodes),
+                ..Default::default()
+            },
+            [0, 0, 0, 0, 0, 0, 0, 0x05],
+        ),
+    ];
+    for (config, reserved) in configs {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = Peer {
+            id: None,
+            socket: listener.local_addr().unwrap(),
+        };
+        let handshake = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut handshake = [0u8; 68];
+            stream.read_exact(&mut handshake).unwrap();
+            handshake
+        });
+
+        let mut pieces = vec![Piece::new(0, PIECE_LENGTH, [0; 20])];
+        let (_, new_peers) = mpsc::channel();
+        download::download_from(
+            &mut pieces,
+            vec![peer],
+            new_peers,
+            [0; 20],
+            PIECE_LENGTH,
+            Arc::new(Mutex::new(Progress::default())),
+            &config,
+            &[],
+            Arc::new(AtomicBool::new(false)),
+        );
+        assert_eq!(handshake.join().unwrap()[20..28], reserved);
+    }
+}
+
+/// Negotiates `ut_pex` in the extension handshake, and downloads from a
+/// seed a peer passes on with it.
+#[test]
+fn finds_seed_through_peer_exchange() {
+    let data: Vec<u8> = (0..PIECE_LENGTH).map(|i| (i % 239) as u8).collect();
+    let info_hash = [9; 20];
+    let mut pieces = vec![Piece::new(0, PIECE_LENGTH, piece_hash(&data, 0))];
+
+    let seed = TcpListener::bind("127.0.0.1:0").unwrap();
+    let seed_addr = seed.local_addr().unwrap();
+    let seed_data = data.clone();
+    thread::spawn(move || serve_seed(seed, info_hash, seed_data, None));
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let peer = Peer {
+        id: None,
+        socket: listener.local_addr().unwrap(),
+    };
+    let (to_test, handshakes) = mpsc::channel();
+    thread::spawn(move || {
+        serve_pex_peer(listener, info_hash, seed_addr, to_test)
+    });
+
+    let output = Arc::new(Mutex::new(vec![]));
+    let config = DownloadConfig {
+        timeout: Some(Duration::from_secs(30)),
+        output: Output::Memory(output.clone()),
+        ..Default::default()
+    };
+    let (_, new_peers) = mpsc::channel();
+    let summary = download::download_from(
+        &mut pieces,
+        vec![peer],
+        new_peers,
+        info_hash,
+        PIECE_LENGTH,
+        Arc::new(Mutex::new(Progress::default())),
+        &config,
+        &[],
+        Arc::new(AtomicBool::new(false)),
+    );
+
+    assert!(summary.is_complete(), "{:?}", summary);
+    assert_eq!(*output.lock().unwrap(), data);
+    let handshake = handshakes.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(handshake, b"\x00d1:md6:ut_pexi1eee".to_vec());
+}
+
+/// Connects to a peer listed under several addresses once, on whichever
+/// of them answers.
+#[test]
+fn races_addresses_of_one_peer() {
+    let data: Vec<u8> = (0..PIECE_LENGTH).map(|i| (i % 199) as u8).collect();
+    let info_hash = [4; 20];
+    let mut pieces = vec![Piece::new(0, PIECE_LENGTH, piece_hash(&data, 0))];
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let seed_addr = listener.local_addr().unwrap();
+    let seed_data = data.clone();
+    thread::spawn(move || serve_seed(listener, info_hash, seed_data, None));
+    // Nothing listens on this one, so connecting to it fails.
+    let dead_addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let peers = vec![dead_addr, seed_addr]
+        .into_iter()
+        .map(|socket| Peer {
+            id: Some(*b"-SEED-00000000000000"),
+            socket,
+        })
+        .collect();
+
+    let (handshakes, handshake_results) = mpsc::channel();
+    let config = DownloadConfig {
+        timeout: Some(Duration::from_secs(30)),
+        output: Output::Memory(Arc::new(Mutex::new(vec![]))),
+        handshakes: Some(handshakes),
+        ..Default::default()
+    };
+    let (_, new_peers) = mpsc::channel();
+    let summary = download::download_from(
+        &mut pieces,
+        peers,
+        new_peers,
+        info_hash,
+        PIECE_LENGTH,
+        Arc::new(Mutex::new(Progress::default())),
+        &config,
+        &[],
+        Arc::new(AtomicBool::new(false)),
+    );
+    assert!(summary.is_complete(), "{:?}", summary);
+
+    let results: Vec<HandshakeResult> = handshake_results.try_iter().collect();
+    assert_eq!(results.len(), 1);
+    assert!(results[0].outcome.is_ok());
+}
+
+/// Keeps its one connection to a peer that announces keep listing, rather
+/// than connecting to it again each time.
+#[test]
+fn keeps_connections_across_announces() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let peer = Peer {
+        id: None,
+        socket: listener.local_addr().unwrap(),
+    };
+    let (to_test, accepted) = mpsc::channel();
+    thread::spawn(move || {
+        let mut streams = vec![];
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut handshake = [0u8; 68];
+            if stream.read_exact(&mut handshake).is_ok() {
+                handshake[48..].copy_from_slice(b"-KEEP-00000000000000");
+                let _ = stream.write_all(&handshake);
+            }
+            let _ = to_test.send(());
+            streams.push(stream);
+        }
+    });
+
+    let (peer_sender, new_peers) = mpsc::channel();
+    let announced = peer.clone();
+    thread::spawn(move || {
+        for _ in 0..3 {
+            thread::sleep(Duration::from_millis(300));
+            peer_sender.send(announced.clone()).unwrap();
+        }
+    });
+
+    let mut pieces = vec![Piece::new(0, PIECE_LENGTH, [0; 20])];
+    let config = DownloadConfig {
+        timeout: Some(Duration::from_secs(2)),
+        ..Default::default()
+    };
+    let summary = download::download_from(
+        &mut pieces,
+        vec![peer],
+        new_peers,
+        [0; 20],
+        PIECE_LENGTH,
+        Arc::new(Mutex::new(Progress::default())),
+        &config,
+        &[],
+        Arc::new(AtomicBool::new(false)),
+    );
+    assert!(!summary.is_complete());
+    assert_eq!(accepted.try_iter().count(), 1);
+}
+
+/// Gives up on a peer that never answers once the clock passes the
+/// download's timeout, without really waiting for it.
+#[test]
+fn times_out_on_clock() {
+    // Connections are accepted by the OS but the handshake is never
+    // answered.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let peer = Peer {
+        id: None,
+        socket: listener.local_addr().unwrap(),
+    };
+    let mut pieces = vec![Piece::new(0, PIECE_LENGTH, [0; 20])];
+
+    let clock = Arc::new(MockClock::new());
+    let config = DownloadConfig {
+        timeout: Some(Duration::from_secs(3600)),
+        clock: Some(clock.clone()),
+        ..Default::default()
+    };
+    let advance = clock.clone();
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(200));
+        advance.advance(Duration::from_secs(7200));
+    });
+
+    let (_, new_peers) = mpsc::channel();
+    let summary = download::download_from(
+        &mut pieces,
+        vec![peer],
+        new_peers,
+        [0; 20],
+        PIECE_LENGTH,
+        Arc::new(Mutex::new(Progress::default())),
+        &config,
+        &[],
+        Arc::new(AtomicBool::new(false)),
+    );
+    assert!(!summary.is_complete());
+    drop(listener);
+}
+
+/// Waits for a slot rather than connecting when a shared connection limit
+/// is already used up.
+#[test]
+fn respects_connection_limit() {
+    let limit = ConnectionLimit::new(1);
+    let slot = limit.try_acquire().unwrap();
+    assert!(limit.try_acquire().is_none());
+    assert_eq!(limit.open(), 1);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let peer = Peer {
+        id: None,
+        socket: listener.local_addr().unwrap(),
+    };
+    let mut pieces = vec![Piece::new(0, PIECE_LENGTH, [0; 20])];
+    let clock = Arc::new(MockClock::new());
+    let config = DownloadConfig {
+        timeout: Some(Duration::from_secs(60)),
+        clock: Some(clock.clone()),
+        connection_limit: Some(limit.clone()),
+        ..Default::default()
+    };
+    let advance = clock.clone();
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(300));
+        advance.advance(Duration::from_secs(120));
+    });
+
+    let (_, new_peers) = mpsc::channel();
+    let summary = download::download_from(
+        &mut pieces,
+        vec![peer],
+        new_peers,
+        [0; 20],
+        PIECE_LENGTH,
+        Arc::new(Mutex::new(Progress::default())),
+        &config,
+        &[],
+        Arc::new(AtomicBool::new(false)),
+    );
+    assert!(!summary.is_complete());
+    assert!(listener.accept().is_err());
+
+    drop(slot);
+    assert_eq!(limit.open(), 0);
+    assert!(limit.try_acquire().is_some());
+}
+
+/// Asks for more peers while nobody has a piece, and gives up on it once
+/// the announces stop helping.
+#[test]
+fn reannounces_for_unavailable_pieces() {
+    let mut pieces = vec![Piece::new(0, PIECE_LENGTH, [0; 20])];
+    let clock = Arc::new(MockClock::new());
+    let (reannounce, reannounce_requests) = mpsc::channel();
+    let config = DownloadConfig {
+        unavailable_timeout: Some(Duration::from_secs(60)),
+        reannounce: Some(reannounce),
+        max_reannounces: Some(2),
+        clock: Some(clock.clone()),
+        ..Default::default()
+    };
+    let advance = clock.clone();
+    thread::spawn(move || {
+        for _ in 0..100 {
+            thread::sleep(Duration::from_millis(50));
+            advance.advance(Duration::from_secs(61));
+        }
+    });
+
+    // Kept open, so running out of peers isn't what ends the download.
+    let (_peer_sender, new_peers) = mpsc::channel::<Peer>();
+    let summary = download::download_from(
+        &mut pieces,
+        vec![],
+        new_peers,
+        [0; 20],
+        PIECE_LENGTH,
+        Arc::new(Mutex::new(Progress::default())),
+        &config,
+        &[],
+        Arc::new(AtomicBool::new(false)),
+    );
+    assert_eq!(summary.unavailable_pieces, vec![0]);
+    assert_eq!(reannounce_requests.try_iter().count(), 2);
+}
+
+/// A piece that can't be written isn't counted as downloaded.
+#[test]
+fn leaves_unwritten_pieces_incomplete() {
+    let dir =
+        env::temp_dir().join(format!("torrent-nowrite-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    // A file where the output's directory should be.
+    fs::write(dir.join("file"), b"").unwrap();
+
+    let data: Vec<u8> = (0..PIECE_LENGTH).map(|i| (i % 251) as u8).collect();
+    let info_hash = [9; 20];
+    let mut pieces = vec![Piece::new(0, PIECE_LENGTH, piece_hash(&data, 0))];
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let peer = Peer {
+        id: None,
+        socket: listener.local_addr().unwrap(),
+    };
+    let seed_data = data.clone();
+    thread::spawn(move || serve_seed(listener, info_hash, seed_data, None));
+
+    let config = DownloadConfig {
+        timeout: Some(Duration::from_secs(30)),
+        output: Output::Files(vec![(
+            dir.join("file").join("data"),
+            PIECE_LENGTH,
+        )]),
+        ..Default::default()
+    };
+    let progress = Arc::new(Mutex::new(Progress::default()));
+    let (_, new_peers) = mpsc::channel::<Peer>();
+    let summary = download::download_from(
+        &mut pieces,
+        vec![peer],
+        new_peers,
+        info_hash,
+        PIECE_LENGTH,
+        progress.clone(),
+        &config,
+        &[],
+        Arc::new(AtomicBool::new(false)),
+    );
+
+    assert!(!summary.is_complete());
+    assert_eq!(summary.failed_pieces, vec![0]);
+    assert_eq!(progress.lock().unwrap().completed_pieces, 0);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+/// A block shorter than asked for is asked for again rather than kept.
+#[test]
+fn asks_again_for_short_blocks() {
+    let data: Vec<u8> = (0..PIECE_LENGTH).map(|i| (i % 241) as u8).collect();
+    let info_hash = [10; 20];
+    let mut pieces = vec![Piece::new(0, PIECE_LENGTH, piece_hash(&data, 0))];
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let peer = Peer {
+        id: None,
+        socket: listener.local_addr().unwrap(),
+    };
+    let seed_data = data.clone();
+    thread::spawn(move || {
+        serve_short_block_seed(listener, info_hash, seed_data)
+    });
+
+    let output = Arc::new(Mutex::new(vec![0u8; data.len()]));
+    let config = DownloadConfig {
+        timeout: Some(Duration::from_secs(30)),
+        output: Output::Memory(output.clone()),
+        ..Default::default()
+    };
+    let (_, new_peers) = mpsc::channel::<Peer>();
+    let summary = download::download_from(
+        &mut pieces,
+        vec![peer],
+        new_peers,
+        info_hash,
+        PIECE_LENGTH,
+        Arc::new(Mutex::new(Progress::default())),
+        &config,
+        &[],
+        Arc::new(AtomicBool::new(false)),
+    );
+
+    assert!(summary.is_complete(), "{:?}", summary);
+    assert_eq!(*output.lock().unwrap(), data);
+}
+
+/// Downloads only the second and last of four pieces.
+#[test]
+fn downloads_some_of_the_pieces() {
+    let data: Vec<u8> =
+        (0..4 * PIECE_LENGTH).map(|i| (i * 3 % 251) as u8).collect();
+    let info_hash = [8; 20];
+    let mut pieces: Vec<Piece> = [1, 3]
+        .iter()
+        .map(|&index| Piece::new(index, PIECE_LENGTH, piece_hash(&data, index)))
+        .collect();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let peer = Peer {
+        id: None,
+        socket: listener.local_addr().unwrap(),
+    };
+    let seed_data = data.clone();
+    thread::spawn(move || serve_seed(listener, info_hash, seed_data, None));
+
+    let output = Arc::new(Mutex::new(vec![0u8; data.len()]));
+    let config = DownloadConfig {
+        timeout: Some(Duration::from_secs(30)),
+        output: Output::Memory(output.clone()),
+        ..Default::default()
+    };
+    let (_, new_peers) = mpsc::channel::<Peer>();
+    let summary = download::download_from(
+        &mut pieces,
+        vec![peer],
+        new_peers,
+        info_hash,
+        PIECE_LENGTH,
+        Arc::new(Mutex::new(Progress::default())),
+        &config,
+        &[],
+        Arc::new(AtomicBool::new(false)),
+    );
+
+    assert!(summary.is_complete(), "{:?}", summary);
+    let output = output.lock().unwrap();
+    for &index in &[1, 3] {
+        let range =
+            index * PIECE_LENGTH as usize..(index + 1) * PIECE_LENGTH as usize;
+        assert_eq!(output[range.clone()], data[range]);
+    }
+}
+
+/// Downloads from a seed that announces its pieces with `have` messages
+/// after the bitfield's grace period, instead of sending a bitfield.
+#[test]
+fn downloads_from_peer_without_bitfield() {
+    let data: Vec<u8> =
+        (0..2 * PIECE_LENGTH).map(|i| (i % 251) as u8).collect();
+    let info_hash = [7; 20];
+    let mut pieces: Vec<Piece> = (0..2)
+        .map(|index| Piece::new(index, PIECE_LENGTH, piece_hash(&data, index)))
+        .collect();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let peer = Peer {
+        id: None,
+        socket: listener.local_addr().unwrap(),
+    };
+    let peer_data = data.clone();
+    thread::spawn(move || serve_have_seed(listener, info_hash, peer_data));
+
+    let output = Arc::new(Mutex::new(vec![0u8; data.len()]));
+    let config = DownloadConfig {
+        timeout: Some(Duration::from_secs(30)),
+        bitfield_grace: Some(Duration::from_millis(100)),
+        output: Output::Memory(output.clone()),
+        ..Default::default()
+    };
+    // Kept open, so the download waits for the seed's `have` messages
+    // rather than running out of peers while it has nothing.
+    let (_peer_sender, new_peers) = mpsc::channel::<Peer>();
+    let summary = download::download_from(
+        &mut pieces,
+        vec![peer],
+        new_peers,
+        info_hash,
+        PIECE_LENGTH,
+        Arc::new(Mutex::new(Progress::default())),
+        &config,
+        &[],
+        Arc::new(AtomicBool::new(false)),
+    );
+
+    assert!(summary.is_complete(), "{:?}", summary);
+    assert_eq!(*output.lock().unwrap(), data);
+}
+
+/// Answers a single announce with a compact peer list holding `peer`.
+fn serve_tracker(listener: TcpListener, peer: SocketAddr) {
+    let (mut stream, _) = listener.accept().unwrap();
+
+    let mut request: Vec<u8> = vec![];
+    let mut buffer = [0u8; 1024];
+    while !request.windows(4).any(|x| x == b"\r\n\r\n") {
+        let n = stream.read(&mut buffer).unwrap();
+        if n == 0 {
+            return;
+        }
+        request.extend(&buffer[..n]);
+    }
+
+    let ip = match peer.ip() {
+        IpAddr::V4(ip) => ip.octets(),
+        IpAddr::V6(_) => panic!("compact peers are IPv4 only"),
+    };
+    let mut body = b"d8:intervali1800e5:peers6:".to_vec();
+    body.extend(&ip);
+    body.extend(&peer.port().to_be_bytes());
+    body.push(b'e');
+
+    let header = format!(
+        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(header.as_bytes()).unwrap();
+    stream.write_all(&body).unwrap();
+}
+
+/// A seed that has every piece and serves whatever is requested, passing
+/// each request on to `requests` as `(index, begin, length)`.
+fn serve_seed(
+    listener: TcpListener,
+    info_hash: [u8; 20],
+    data: Vec<u8>,
+    requests: Option<mpsc::Sender<(u32, u32, u32)>>,
+) {
+    let (mut stream, _) = listener.accept().unwrap();
+
+    let mut handshake = [0u8; 68];
+    stream.read_exact(&mut handshake).unwrap();
+    assert_eq!(&handshake[28..48], &info_hash[..]);
+    handshake[48..].copy_from_slice(b"-SEED-00000000000000");
+    stream.write_all(&handshake).unwrap();
+
+    let num_pieces = (data.len() as u64 + PIECE_LENGTH - 1) / PIECE_LENGTH;
+    let mut bitfield = vec![0u8; (num_pieces as usize + 7) / 8];
+    for index in 0..num_pieces as usize {
+        bitfield[index / 8] |= 0b1000_0000 >> (index % 8);
+    }
+    send_msg(&mut stream, 5, &bitfield);
+
+    answer_requests(stream, &data, requests);
+}
+
+/// A seed that skips its bitfield and, once the grace period for it is
+/// up, announces each piece with a `have` message.
+fn serve_have_seed(listener: TcpListener, info_hash: [u8; 20], data: Vec<u8>) {
+    let (mut stream, _) = listener.accept().unwrap();
+
+    let mut handshake = [0u8; 68];
+    stream.read_exact(&mut handshake).unwrap();
+    assert_eq!(&handshake[28..48], &info_hash[..]);
+    handshake[48..].copy_from_slice(b"-HAVE-00000000000000");
+    stream.write_all(&handshake).unwrap();
+
+    thread::sleep(Duration::from_millis(500));
+    let num_pieces = (data.len() as u64 + PIECE_LENGTH - 1) / PIECE_LENGTH;
+    for index in 0..num_pieces as u32 {
+        send_msg(&mut stream, 4, &index.to_be_bytes());
+    }
+
+    answer_requests(stream, &data, None);
+}
+
+/// Unchokes the peer once it's interested and serves the blocks it
+/// requests, until it disconnects.
+fn answer_requests(
+    mut stream: TcpStream,
+    data: &[u8],
+    requests: Option<mpsc::Sender<(u32, u32, u32)>>,
+) {
+    loop {
+        let mut len = [0u8; 4];
+        if stream.read_exact(&mut len).is_err() {
+            return;
+        }
+        let len = u32::from_be_bytes(len) as usize;
+        if len == 0 {
+            continue;
+        }
+        let mut msg = vec![0u8; len];
+        if stream.read_exact(&mut msg).is_err() {
+            return;
+        }
+
+        match msg[0] {
+            // interested
+            2 => send_msg(&mut stream, 1, &[]),
+            // request
+            6 => {
+                let index = be_u32(&msg[1..5]) as usize;
+                let begin = be_u32(&msg[5..9]) as usize;
+                let length = be_u32(&msg[9..13]) as usize;
+                if let Some(requests) = &requests {
+                    let request = (index as u32, begin as u32, length as u32);
+                    let _ = requests.send(request);
+                }
+                let start = index * PIECE_LENGTH as usize + begin;
+
+                let mut payload = msg[1..9].to_vec();
+                payload.extend(&data[start..start + length]);
+                send_msg(&mut stream, 7, &payload);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A seed of a single piece that answers the first request with a block
+/// a byte short, and every request after that as asked.
+fn serve_short_block_seed(
+    listener: TcpListener,
+    info_hash: [u8; 20],
+    data: Vec<u8>,
+) {
+    let (mut stream, _) = listener.accept().unwrap();
+
+    let mut handshake = [0u8; 68];
+    stream.read_exact(&mut handshake).unwrap();
+    assert_eq!(&handshake[28..48], &info_hash[..]);
+    handshake[48..].copy_from_slice(b"-SEED-00000000000000");
+    stream.write_all(&handshake).unwrap();
+    send_msg(&mut stream, 5, &[0b1000_0000]);
+
+    loop {
+        let mut len = [0u8; 4];
+        stream.read_exact(&mut len).unwrap();
+        let mut msg = vec![0u8; u32::from_be_bytes(len) as usize];
+        stream.read_exact(&mut msg).unwrap();
+        match msg.first() {
+            // interested
+            Some(2) => send_msg(&mut stream, 1, &[]),
+            // request
+            Some(6) => {
+                let begin = be_u32(&msg[5..9]) as usize;
+                let length = be_u32(&msg[9..13]) as usize;
+                let mut payload = msg[1..9].to_vec();
+                payload.extend(&data[begin..begin + length - 1]);
+                send_msg(&mut stream, 7, &payload);
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    answer_requests(stream, &data, None);
+}
+
+/// A peer with only the last piece. Once we unchoke it, it asks for an
+/// oversized block, then a valid one of piece 1, and passes on the first
+/// block it's sent. Our requests are only answered after that, so the
+/// download can't end first.
+fn serve_leech(
+    listener: TcpListener,
+    info_hash: [u8; 20],
+    data: Vec<u8>,
+    to_test: mpsc::Sender<(u32, Vec<u8>)>,
+) {
+    let (mut stream, _) = listener.accept().unwrap();
+
+    let mut handshake = [0u8; 68];
+    stream.read_exact(&mut handshake).unwrap();
+    assert_eq!(&handshake[28..48], &info_hash[..]);
+    handshake[48..].copy_from_slice(b"-LEECH-0000000000000");
+    stream.write_all(&handshake).unwrap();
+    send_msg(&mut stream, 5, &[0b0010_0000]);
+    send_msg(&mut stream, 2, &[]);
+
+    let mut requests = vec![];
+    let mut served = false;
+    loop {
+        let mut len = [0u8; 4];
+        if stream.read_exact(&mut len).is_err() {
+            return;
+        }
+        let len = u32::from_be_bytes(len) as usize;
+        if len == 0 {
+            continue;
+        }
+        let mut msg = vec![0u8; len];
+        if stream.read_exact(&mut msg).is_err() {
+            return;
+        }
+
+        match msg[0] {
+            // unchoke
+            1 => {
+                for &(index, begin, length) in
+                    &[(0u32, 0u32, 1u32 << 15), (1, 100, 1000)]
+                {
+                    let mut payload = index.to_be_bytes().to_vec();
+                    payload.extend(&begin.to_be_bytes());
+                    payload.extend(&length.to_be_bytes());
+                    send_msg(&mut stream, 6, &payload);
+                }
+            }
+            // interested
+            2 => send_msg(&mut stream, 1, &[]),
+            // request
+            6 => requests.push(msg),
+            // piece
+            7 if !served => {
+                assert_eq!(be_u32(&msg[1..5]), 1);
+                to_test
+                    .send((be_u32(&msg[5..9]), msg[9..].to_vec()))
+                    .unwrap();
+                served = true;
+            }
+            _ => {}
+        }
+
+        if served {
+            for msg in requests.drain(..) {
+                let index = be_u32(&msg[1..5]) as usize;
+                let begin = be_u32(&msg[5..9]) as usize;
+                let length = be_u32(&msg[9..13]) as usize;
+                let start = index * PIECE_LENGTH as usize + begin;
+
+                let mut payload = msg[1..9].to_vec();
+                payload.extend(&data[start..start + length]);
+                send_msg(&mut stream, 7, &payload);
+            }
+        }
+    }
+}
+
+/// A peer with the fast extension and no pieces. It passes on the first
+/// `count` messages it's sent, then says it has nothing.
+fn serve_fast_peer(
+    listener: TcpListener,
+    count: usize,
+    to_test: mpsc::Sender<Vec<Vec<u8>>>,
+) {
+    let (mut stream, _) = listener.accept().unwrap();
+
+    let mut handshake = [0u8; 68];
+    stream.read_exact(&mut handshake).unwrap();
+    assert_eq!(handshake[27] & 0x04, 0x04);
+    handshake[48..].copy_from_slice(b"-FAST-00000000000000");
+    stream.write_all(&handshake).unwrap();
+
+    let mut msgs = vec![];
+    while msgs.len() < count {
+        let mut len = [0u8; 4];
+        stream.read_exact(&mut len).unwrap();
+        let mut msg = vec![0u8; u32::from_be_bytes(len) as usize];
+        stream.read_exact(&mut msg).unwrap();
+        msgs.push(msg);
+    }
+    to_test.send(msgs).unwrap();
+    // have none
+    send_msg(&mut stream, 0x0F, &[]);
+    let _ = stream.read(&mut [0u8; 1]);
+}
+
+/// A peer with no pieces that supports `ut_pex` under id 7. It passes on
+/// the payload of our extension handshake, then tells us about `seed`
+/// before sending its empty bitfield, so the download doesn't run out of
+/// peers first.
+fn serve_pex_peer(
+    listener: TcpListener,
+    info_hash: [u8; 20],
+    seed: SocketAddr,
+    to_test: mpsc::Sender<Vec<u8>>,
+) {
+    let (mut stream, _) = listener.accept().unwrap();
+
+    let mut handshake = [0u8; 68];
+    stream.read_exact(&mut handshake).unwrap();
+    assert_eq!(&handshake[28..48], &info_hash[..]);
+    assert_eq!(handshake[25] & 0x10, 0x10);
+    handshake[48..].copy_from_slice(b"-PEX0-00000000000000");
+    stream.write_all(&handshake).unwrap();
+
+    loop {
+        let mut len = [0u8; 4];
+        stream.read_exact(&mut len).unwrap();
+        let mut msg = vec![0u8; u32::from_be_bytes(len) as usize];
+        stream.read_exact(&mut msg).unwrap();
+        if msg.first() == Some(&20) {
+            to_test.send(msg[1..].to_vec()).unwrap();
+            break;
+        }
+    }
+    send_msg(&mut stream, 20, b"\x00d1:md6:ut_pexi7eee");
+
+    let ip = match seed.ip() {
+        IpAddr::V4(ip) => ip.octets(),
+        IpAddr::V6(_) => panic!("compact peers are IPv4 only"),
+    };
+    // Sent with the id our handshake gave `ut_pex`.
+    let mut payload = b"\x01d5:added6:".to_vec();
+    payload.extend(&ip);
+    payload.extend(&seed.port().to_be_bytes());
+    payload.push(b'e');
+    send_msg(&mut stream, 20, &payload);
+    send_msg(&mut stream, 5, &[0]);
+
+    let mut buffer = [0u8; 1024];
+    while stream.read(&mut buffer).map_or(false, |n| n > 0) {}
+}
+
+fn piece_hash(data: &[u8], index: u32) -> [u8; 20] {
+    let start = (index as u64 * PIECE_LENGTH) as usize;
+    let end = std::cmp::min(start + PIECE_LENGTH as usize, data.len());
+    let digest = Sha1::digest(&data[start..end]);
+    let mut hash = [0u8; 20];
+    hash.copy_from_slice(&digest);
+    hash
+}
+
+fn send_msg(stream: &mut TcpStream, id: u8, payload: &[u8]) {
+    let len = (payload.len() as u32 + 1).to_be_bytes();
+    let mut buffer = len.to_vec();
+    buffer.push(id);
+    buffer.extend(payload);
+    let _ = stream.write_all(&buffer);
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
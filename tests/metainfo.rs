@@ -0,0 +1,387 @@
+extern crate torrent;
+
+use std::borrow::Cow;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+use torrent::builder::TorrentBuilder;
+use torrent::download::Output;
+use torrent::{File, Info, TorrentMetaInfo};
+
+#[test]
+fn rejects_trailing_bytes() {
+    let dir = env::temp_dir().join(format!("torrent-meta-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let source = dir.join("source");
+    fs::write(&source, vec![0u8; 1000]).unwrap();
+
+    let mut contents = TorrentBuilder::new(&source)
+        .announce("http://localhost/announce")
+        .build()
+        .unwrap();
+    let _ = fs::remove_dir_all(&dir);
+    assert!(TorrentMetaInfo::new(&contents).is_ok());
+
+    contents.extend(b"garbage");
+    assert!(TorrentMetaInfo::new(&contents).is_err());
+}
+
+#[test]
+fn rejects_files_that_arent_torrents() {
+    for contents in &[&b"\x89PNG\r\n\x1a\n"[..], b"", b"li1ee"] {
+        let error = TorrentMetaInfo::new(contents).err().unwrap().to_string();
+        assert_eq!(
+            error,
+            "not a valid torrent file: expected a bencode dictionary"
+        );
+    }
+}
+
+/// A single-file torrent with the given `length` and `piece length`, and a
+/// hash for each of its pieces, up to 16 so that huge lengths stay small.
+fn torrent_with_lengths(length: i64, piece_length: i64) -> Vec<u8> {
+    let num_pieces = if length > 0 && piece_length > 0 {
+        ((length + piece_length - 1) / piece_length).min(16)
+    } else {
+        1
+    };
+    torrent_with_pieces(
+        length,
+        piece_length,
+        &vec![0; num_pieces as usize * 20],
+    )
+}
+
+/// A single-file torrent with the given `length`, `piece length` and
+/// `pieces`.
+fn torrent_with_pieces(
+    length: i64,
+    piece_length: i64,
+    pieces: &[u8],
+) -> Vec<u8> {
+    let mut contents = format!(
+        "d8:announce25:http://localhost/announce4:infod6:lengthi{}e\
+         4:name1:a12:piece lengthi{}e6:pieces{}:",
+        length,
+        piece_length,
+        pieces.len()
+    )
+    .into_bytes();
+    contents.extend(pieces);
+    contents.extend(b"ee");
+    contents
+}
+
+/// A torrent named `name` holding one file at `path`, each component of
+/// which is a bencode string.
+fn torrent_with_path(name: &[u8], path: &[&[u8]]) -> Vec<u8> {
+    let mut contents =
+        b"d8:announce25:http://localhost/announce4:infod5:filesld6:lengthi1e\
+          4:pathl"
+            .to_vec();
+    for component in path {
+        contents.extend(format!("{}:", component.len()).into_bytes());
+        contents.extend(*component);
+    }
+    contents.extend(format!("eee4:name{}:", name.len()).into_bytes());
+    contents.extend(name);
+    contents.extend(b"12:piece lengthi16384e6:pieces20:");
+    contents.extend(&[0; 20]);
+    contents.extend(b"ee");
+    contents
+}
+
+#[test]
+fn rejects_unsafe_names() {
+    assert!(TorrentMetaInfo::new(&torrent_with_path(b"a", &[b"b"])).is_ok());
+    let names: &[&[u8]] =
+        &[b"", b".", b"..", b"/etc", b"a/b", b"a\\b", b"\xff"];
+    for name in names {
+        let contents = torrent_with_path(name, &[b"b"]);
+        assert!(TorrentMetaInfo::new(&contents).is_err(), "{:?}", name);
+    }
+}
+
+#[test]
+fn rejects_unsafe_file_paths() {
+    let contents = torrent_with_path(b"a", &[b"b", b"c"]);
+    let metainfo = TorrentMetaInfo::new(&contents).unwrap();
+    assert_eq!(metainfo.info.files.unwrap()[0].path, "b/c");
+
+    let paths: &[&[&[u8]]] = &[
+        &[],
+        &[b""],
+        &[b"b", b""],
+        &[b"."],
+        &[b"..", b"b"],
+        &[b"b", b"..", b"..", b"c"],
+        &[b"/etc", b"passwd"],
+        &[b"b/../../c"],
+        &[b"b\\c"],
+        &[b"\xff"],
+    ];
+    for path in paths {
+        let contents = torrent_with_path(b"a", path);
+        assert!(TorrentMetaInfo::new(&contents).is_err(), "{:?}", path);
+    }
+}
+
+#[test]
+fn rejects_out_of_range_lengths() {
+    assert!(TorrentMetaInfo::new(&torrent_with_lengths(10, 16384)).is_ok());
+    assert!(TorrentMetaInfo::new(&torrent_with_lengths(-10, 16384)).is_err());
+    assert!(TorrentMetaInfo::new(&torrent_with_lengths(10, 0)).is_err());
+    // Piece offsets have to fit the 32 bits the wire protocol gives them.
+    assert!(TorrentMetaInfo::new(&torrent_with_lengths(10, 1 << 32)).is_err());
+
+    // Lengths beyond 32 bits are fine otherwise.
+    let contents = torrent_with_lengths(10 << 30, 1 << 31);
+    let metainfo = TorrentMetaInfo::new(&contents).unwrap();
+    assert_eq!(metainfo.info.total_length(), 10 << 30);
+    assert_eq!(metainfo.info.piece_byte_range(4), (8 << 30, 10 << 30));
+}
+
+#[test]
+fn rejects_too_many_pieces() {
+    // A few bytes of torrent claiming a petabyte in 1 byte pieces.
+    let contents = torrent_with_lengths(1 << 50, 1);
+    let e = TorrentMetaInfo::new(&contents).err().unwrap();
+    assert!(e.to_string().contains("more than the limit"), "{}", e);
+
+    let contents = torrent_with_lengths(10 * 16384, 16384);
+    assert!(TorrentMetaInfo::with_max_pieces(&contents, 10).is_ok());
+    assert!(TorrentMetaInfo::with_max_pieces(&contents, 9).is_err());
+}
+
+#[test]
+fn rejects_wrong_number_of_piece_hashes() {
+    let piece_length = 16384;
+    let contents =
+        torrent_with_pieces(2 * piece_length, piece_length, &[0; 40]);
+    assert!(TorrentMetaInfo::new(&contents).is_ok());
+
+    for &num_hashes in &[1, 3] {
+        let pieces = vec![0; num_hashes * 20];
+        let contents =
+            torrent_with_pieces(2 * piece_length, piece_length, &pieces);
+        let e = TorrentMetaInfo::new(&contents).err().unwrap();
+        assert!(e.to_string().contains("piece hashes"), "{}", e);
+    }
+
+    let contents =
+        torrent_with_pieces(2 * piece_length, piece_length, &[0; 50]);
+    let e = TorrentMetaInfo::new(&contents).err().unwrap();
+    assert!(e.to_string().contains("multiple of 20"), "{}", e);
+}
+
+/// A hybrid v1 and v2 torrent of a file of `length` bytes in pieces of
+/// 16 KiB, whose `pieces root` is all ones, with a piece layer of the given
+/// number of hashes for each root in `layers`.
+fn hybrid_torrent(length: u64, layers: &[([u8; 32], usize)]) -> Vec<u8> {
+    let num_pieces = (length + (1 << 14) - 1) >> 14;
+    let mut contents = b"d4:infod9:file treed1:ad0:d6:lengthi".to_vec();
+    contents.extend(format!("{}e11:pieces root32:", length).into_bytes());
+    contents.extend(&[1; 32]);
+    contents.extend(format!("eee6:lengthi{}e", length).into_bytes());
+    contents.extend(b"4:name1:a12:piece lengthi16384e");
+    contents.extend(format!("6:pieces{}:", num_pieces * 20).into_bytes());
+    contents.extend(vec![0; num_pieces as usize * 20]);
+    contents.extend(b"e12:piece layersd");
+    for (root, hashes) in layers {
+        contents.extend(b"32:");
+        contents.extend(root);
+        contents.extend(format!("{}:", hashes * 32).into_bytes());
+        contents.extend(vec![2; hashes * 32]);
+    }
+    contents.extend(b"ee");
+    contents
+}
+
+#[test]
+fn associates_piece_layers_with_files() {
+    let contents = hybrid_torrent(3 << 14, &[([1; 32], 3)]);
+    let metainfo = TorrentMetaInfo::new(&contents).unwrap();
+    assert_eq!(metainfo.file_tree.len(), 1);
+    assert_eq!(metainfo.file_tree[0].path, "a");
+    assert_eq!(metainfo.file_tree[0].length, 3 << 14);
+    let root = metainfo.file_tree[0].pieces_root.unwrap();
+    assert_eq!(root, [1; 32]);
+    assert_eq!(metainfo.piece_layer(&root).unwrap(), &[[2; 32]; 3][..]);
+
+    // A file of one piece needs no layer.
+    assert!(TorrentMetaInfo::new(&hybrid_torrent(1 << 14, &[])).is_ok());
+
+    let bad = [
+        (
+            vec![([1; 32], 2)],
+            "has 2 hashes, but its 49152 bytes need 3",
+        ),
+        (
+            vec![([1; 32], 4)],
+            "has 4 hashes, but its 49152 bytes need 3",
+        ),
+        (vec![], "a has no piece layer"),
+        (
+            vec![([1; 32], 3), ([3; 32], 3)],
+            "doesn't belong to any file",
+        ),
+    ];
+    for (layers, error) in &bad {
+        let contents = hybrid_torrent(3 << 14, layers);
+        let e = TorrentMetaInfo::new(&contents).err().unwrap().to_string();
+        assert!(e.contains(error), "{}", e);
+    }
+}
+
+/// Files of 10, 3, 0, 2 and 20 bytes, in pieces of 8 bytes.
+fn multi_file_info() -> Info<'static> {
+    let files = [10, 3, 0, 2, 20]
+        .iter()
+        .enumerate()
+        .map(|(i, &length)| File {
+            length,
+            path: format!("file{}", i),
+        })
+        .collect();
+    Info {
+        name: "multi",
+        piece_length: 8,
+        raw_pieces: Cow::Owned(vec![0; 5 * 20]),
+        length: None,
+        files: Some(files),
+        source: None,
+        cross_seed: None,
+        private: false,
+        hash: [0; 20],
+    }
+}
+
+#[test]
+fn lays_out_files_under_dir() {
+    let dir = Path::new("out");
+    match Output::for_torrent(&multi_file_info(), dir).unwrap() {
+        Output::Files(files) => {
+            assert_eq!(files[0], (dir.join("multi").join("file0"), 10));
+            assert_eq!(files.len(), 5);
+        }
+        output => panic!("{:?}", output),
+    }
+
+    let info = Info {
+        name: "..",
+        ..multi_file_info()
+    };
+    assert!(Output::for_torrent(&info, dir).is_err());
+    let mut info = multi_file_info();
+    info.files.as_mut().unwrap()[1].path = "../../etc/passwd".to_string();
+    assert!(Output::for_torrent(&info, dir).is_err());
+    info.files.as_mut().unwrap()[1].path = "/etc/passwd".to_string();
+    assert!(Output::for_torrent(&info, dir).is_err());
+}
+
+#[test]
+fn file_offsets() {
+    let info = multi_file_info();
+    let offsets: Vec<(&str, u64, u64)> = info.file_offsets().collect();
+    assert_eq!(
+        offsets,
+        vec![
+            ("file0", 10, 0),
+            ("file1", 3, 10),
+            ("file2", 0, 13),
+            ("file3", 2, 13),
+            ("file4", 20, 15),
+        ]
+    );
+
+    let info = Info {
+        files: None,
+        length: Some(20),
+        ..multi_file_info()
+    };
+    let offsets: Vec<(&str, u64, u64)> = info.file_offsets().collect();
+    assert_eq!(offsets, vec![("multi", 20, 0)]);
+}
+
+#[test]
+fn piece_byte_ranges() {
+    let info = multi_file_info();
+    assert_eq!(info.total_length(), 35);
+    assert_eq!(info.piece_byte_range(0), (0, 8));
+    assert_eq!(info.piece_byte_range(3), (24, 32));
+    // The last piece is short.
+    assert_eq!(info.piece_byte_range(4), (32, 35));
+}
+
+#[test]
+fn piece_within_one_file() {
+    let info = multi_file_info();
+    assert_eq!(info.files_for_piece(0), vec![(0, 0, 8)]);
+    assert_eq!(info.files_for_piece(3), vec![(4, 9, 8)]);
+    assert_eq!(info.files_for_piece(4), vec![(4, 17, 3)]);
+}
+
+#[test]
+fn piece_crossing_one_file_boundary() {
+    let info = multi_file_info();
+    // The end of file0 and all of file1.
+    assert_eq!(info.files_for_range(8, 13), vec![(0, 8, 2), (1, 0, 3)]);
+    // The end of file3 and the start of file4.
+    assert_eq!(info.files_for_range(14, 18), vec![(3, 1, 1), (4, 0, 3)]);
+}
+
+#[test]
+fn piece_crossing_several_file_boundaries() {
+    let info = multi_file_info();
+    // The empty file2 sits between file1 and file3 but is never included.
+    assert_eq!(
+        info.files_for_piece(1),
+        vec![(0, 8, 2), (1, 0, 3), (3, 0, 2), (4, 0, 1)]
+    );
+    let total: u64 = info.files_for_piece(1).iter().map(|x| x.2).sum();
+    assert_eq!(total, 8);
+}
+
+#[test]
+fn single_file_piece() {
+    let info = Info {
+        files: None,
+        length: Some(20),
+        ..multi_file_info()
+    };
+    assert_eq!(info.files_for_piece(2), vec![(0, 16, 4)]);
+}
+
+#[test]
+fn parses_trackerless_torrent() {
+    let mut contents = b"d4:infod6:lengthi10e4:name1:a12:piece lengthi16384e\
+                         6:pieces20:"
+        .to_vec();
+    contents.extend(&[0; 20]);
+    contents.extend(b"e5:nodesll9:127.0.0.1i6881eel11:example.comi1eeee");
+
+    let metainfo = TorrentMetaInfo::new(&contents).unwrap();
+    assert!(metainfo.announce.is_none());
+    assert_eq!(
+        metainfo.nodes,
+        Some(vec![
+            ("127.0.0.1".to_string(), 6881),
+            ("example.com".to_string(), 1),
+        ])
+    );
+}
+
+#[test]
+fn warns_about_unusual_piece_lengths() {
+    let contents = torrent_with_lengths(10, 1 << 18);
+    let metainfo = TorrentMetaInfo::new(&contents).unwrap();
+    assert!(metainfo.info.validate().is_empty());
+    assert!(metainfo.info.validate_strict().is_ok());
+
+    // Parses fine, but neither a power of two nor large enough.
+    let contents = torrent_with_lengths(10, 1000);
+    let metainfo = TorrentMetaInfo::new(&contents).unwrap();
+    assert_eq!(metainfo.info.validate().len(), 2);
+    assert!(metainfo.info.validate_strict().is_err());
+}
@@ -0,0 +1,83 @@
+extern crate sha1;
+extern crate torrent;
+
+use sha1::{Digest, Sha1};
+use std::env;
+use std::fs;
+use std::process;
+use std::sync::{Arc, Mutex};
+use torrent::download::{Output, Piece};
+use torrent::resume;
+
+#[test]
+fn saves_and_loads_bitfield() {
+    let dir = env::temp_dir().join(format!("torrent-resume-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = resume::sidecar_path(&dir.join("part"));
+    assert_eq!(path, dir.join("part.bitfield"));
+
+    let written =
+        vec![true, false, false, true, true, false, true, false, true];
+    resume::save_bitfield(&path, &[1; 20], &written).unwrap();
+    assert_eq!(resume::load_bitfield(&path, &[1; 20], 9), Some(written));
+    // Another torrent's, or the wrong size.
+    assert_eq!(resume::load_bitfield(&path, &[2; 20], 9), None);
+    assert_eq!(resume::load_bitfield(&path, &[1; 20], 17), None);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn renames_part_files_when_finished() {
+    let dir = env::temp_dir().join(format!("torrent-part-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let (done, pending) = (dir.join("done"), dir.join("pending"));
+    fs::write(&done, b"finished earlier").unwrap();
+    let output = Output::Files(vec![(done.clone(), 16), (pending.clone(), 8)]);
+
+    let parts = resume::part_output(&output);
+    let part = dir.join("pending.part");
+    match &parts {
+        Output::Files(files) => {
+            assert_eq!(files, &vec![(done.clone(), 16), (part.clone(), 8)])
+        }
+        _ => panic!("expected files"),
+    }
+
+    fs::write(&part, b"complete").unwrap();
+    resume::finish_parts(&output, &parts).unwrap();
+    assert!(!part.exists());
+    assert_eq!(fs::read(&pending).unwrap(), b"complete");
+    assert_eq!(fs::read(&done).unwrap(), b"finished earlier");
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn checks_pieces_not_in_bitfield() {
+    let data: Vec<u8> = (0..40).collect();
+    let pieces: Vec<Piece> = data
+        .chunks(16)
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut hash = [0u8; 20];
+            hash.copy_from_slice(&Sha1::digest(chunk));
+            Piece::new(index as u32, chunk.len() as u64, hash)
+        })
+        .collect();
+
+    // Piece 0 is corrupt but trusted, piece 1 is good and piece 2 is
+    // corrupt.
+    let mut output = data.clone();
+    output[0] = 0xff;
+    output[39] = 0xff;
+    let output = Output::Memory(Arc::new(Mutex::new(output)));
+    let have =
+        resume::check_pieces(&pieces, pieces.len(), 16, &output, &[true]);
+    assert_eq!(have, vec![true, true, false]);
+
+    // Nothing can be read back from an empty output.
+    let output = Output::Memory(Arc::new(Mutex::new(vec![])));
+    let have = resume::check_pieces(&pieces, pieces.len(), 16, &output, &[]);
+    assert_eq!(have, vec![false, false, false]);
+}
@@ -0,0 +1,86 @@
+extern crate torrent;
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+use torrent::builder::TorrentBuilder;
+use torrent::download::DownloadConfig;
+use torrent::session::{Session, TorrentState};
+
+/// A torrent of the file `name` in `dir`, filled with `length` bytes, whose
+/// tracker refuses connections.
+fn make_torrent(dir: &Path, name: &str, length: usize) -> Vec<u8> {
+    fs::create_dir_all(dir).unwrap();
+    let source = dir.join(name);
+    let data: Vec<u8> = (0..length).map(|i| (i % 251) as u8).collect();
+    fs::write(&source, data).unwrap();
+    TorrentBuilder::new(&source)
+        .announce("http://127.0.0.1:1/announce")
+        .build()
+        .unwrap()
+}
+
+#[test]
+fn runs_control_commands() {
+    let dir =
+        env::temp_dir().join(format!("torrent-session-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let contents = make_torrent(&dir.join("source"), "data", 40000);
+    let torrent_file = dir.join("data.torrent");
+    fs::write(&torrent_file, contents).unwrap();
+
+    let mut session =
+        Session::new(dir.join("downloads"), DownloadConfig::default());
+    let add = format!("add {}", torrent_file.display());
+    assert_eq!(session.command(&add), "ok 1\n");
+    assert!(session
+        .command(&add)
+        .starts_with("error data is already added"));
+
+    assert_eq!(session.command("pause 1"), "ok\n");
+    let status = session.command("status 1");
+    assert!(status.starts_with("1 paused "), "{}", status);
+    assert!(status.ends_with(" data\nok\n"), "{}", status);
+    assert_eq!(session.command("list"), status);
+
+    assert_eq!(session.command("remove 1"), "ok\n");
+    assert_eq!(session.command("status 1"), "error no torrent 1\n");
+    assert_eq!(session.command("list"), "ok\n");
+    assert_eq!(session.command("pause x"), "error bad torrent id \"x\"\n");
+    assert!(session.command("frobnicate").starts_with("error unknown"));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn completes_torrents_already_on_disk() {
+    let dir = env::temp_dir().join(format!("torrent-done-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    // The data is where the session downloads to, so the tracker is never
+    // needed.
+    let contents = make_torrent(&dir, "data", 40000);
+
+    let mut session = Session::new(dir.clone(), DownloadConfig::default());
+    let id = session.add(contents).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(30);
+    let status = loop {
+        let status = session.status(id).unwrap();
+        if status.state != TorrentState::Downloading
+            || Instant::now() > deadline
+        {
+            break status;
+        }
+        thread::sleep(Duration::from_millis(20));
+    };
+    assert_eq!(status.state, TorrentState::Complete);
+    assert_eq!(
+        status.progress.completed_pieces,
+        status.progress.total_pieces
+    );
+    assert!(!dir.join("data.part").exists());
+
+    let _ = fs::remove_dir_all(&dir);
+}
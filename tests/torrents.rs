@@ -0,0 +1,121 @@
+//! Parses checked-in torrents with known contents. They cover a single-file
+//! torrent with the optional `comment`, `creation date` and `created by`
+//! keys, a multi-file torrent with an `announce-list` and an empty file, and
+//! a private torrent with UTF-8 names. The expected info hashes were
+//! computed separately with Python's `hashlib` over each raw info dict.
+
+extern crate torrent;
+
+use torrent::{info_hash_of, TorrentMetaInfo};
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+#[test]
+fn single_file() {
+    let contents = include_bytes!("data/single.torrent");
+    let metainfo = TorrentMetaInfo::new(contents).unwrap();
+    let info = &metainfo.info;
+
+    assert_eq!(
+        metainfo.announce.unwrap(),
+        "http://tracker.example.org:6969/announce"
+    );
+    assert!(metainfo.announce_list.is_none());
+    assert_eq!(info.name, "pride-and-prejudice.txt");
+    assert_eq!(info.piece_length, 262144);
+    assert_eq!(info.num_pieces(), 4);
+    assert_eq!(info.total_length(), 3 * 262144 + 4321);
+    assert_eq!(
+        info.file_list(),
+        vec![("pride-and-prejudice.txt", 3 * 262144 + 4321)]
+    );
+    assert!(!info.private);
+    assert_eq!(hex(&info.hash), "de91cbcbcb44bf0b87b54f700b639aa1379e03f6");
+}
+
+#[test]
+fn multi_file_with_announce_list() {
+    let contents = include_bytes!("data/multi.torrent");
+    let metainfo = TorrentMetaInfo::new(contents).unwrap();
+    let info = &metainfo.info;
+
+    assert_eq!(
+        metainfo.announce.as_ref().unwrap(),
+        "udp://tracker.example.org:1337/announce"
+    );
+    assert_eq!(
+        metainfo.announce_list,
+        Some(vec![
+            vec![
+                "udp://tracker.example.org:1337/announce".to_string(),
+                "http://tracker.example.org/announce".to_string(),
+            ],
+            vec!["http://backup.example.net/announce".to_string()],
+        ])
+    );
+    assert_eq!(info.name, "dataset");
+    assert_eq!(info.piece_length, 65536);
+    assert_eq!(info.num_pieces(), 6);
+    assert_eq!(info.total_length(), 383333);
+    assert_eq!(
+        info.file_list(),
+        vec![
+            ("README.txt", 100000),
+            ("empty.log", 0),
+            ("data/part1.bin", 250000),
+            ("data/nested/part2.bin", 33333),
+        ]
+    );
+    assert_eq!(hex(&info.hash), "324e111d6c70d3df036cc2012a0e9432e4d0c710");
+}
+
+#[test]
+fn private_with_utf8_names() {
+    let contents = include_bytes!("data/utf8.torrent");
+    let metainfo = TorrentMetaInfo::new(contents).unwrap();
+    let info = &metainfo.info;
+
+    assert_eq!(info.name, "Ünïcödé 名前");
+    assert_eq!(info.num_pieces(), 3);
+    assert_eq!(info.total_length(), 45000);
+    assert_eq!(
+        info.file_list(),
+        vec![("Ünïcödé/日本語.txt", 40000), ("Ünïcödé/ñ.md", 5000)]
+    );
+    assert!(info.private);
+    assert_eq!(hex(&info.hash), "0bf7d38dc5f9f43e4478daf91b9b478e834c2e3a");
+}
+
+#[test]
+fn recomputes_info_hashes() {
+    for contents in &[
+        &include_bytes!("data/single.torrent")[..],
+        &include_bytes!("data/multi.torrent")[..],
+        &include_bytes!("data/utf8.torrent")[..],
+    ] {
+        let mut metainfo = TorrentMetaInfo::new(contents).unwrap();
+        assert!(metainfo.info.verify_hash());
+
+        metainfo.info.name = "tampered";
+        assert!(!metainfo.info.verify_hash());
+    }
+}
+
+#[test]
+fn hashes_info_without_parsing() {
+    for contents in &[
+        &include_bytes!("data/single.torrent")[..],
+        &include_bytes!("data/multi.torrent")[..],
+        &include_bytes!("data/utf8.torrent")[..],
+    ] {
+        let metainfo = TorrentMetaInfo::new(contents).unwrap();
+        assert_eq!(info_hash_of(contents).unwrap(), metainfo.info.hash);
+    }
+
+    assert!(info_hash_of(b"d8:announce3:urle").is_err());
+    assert!(info_hash_of(b"d4:infoli1eee").is_err());
+    assert!(info_hash_of(b"d4:infod4:name").is_err());
+    assert!(info_hash_of(b"l4:infoe").is_err());
+}
@@ -0,0 +1,543 @@
+extern crate torrent;
+
+use std::cell::{Cell, RefCell};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+use torrent::clock::{Clock, MockClock};
+use torrent::download::Peer;
+use torrent::resolve::Resolver;
+use torrent::tracker::{
+    self, AnnounceConfig, AnnounceParams, HttpTrackerClient, PeersFormat,
+    TrackerClient, TrackerList, TrackerResponse,
+};
+use torrent::{Error, TorrentMetaInfo};
+
+#[test]
+fn reports_html_error_pages() {
+    let page = b"<html><body>502 Bad Gateway</body></html>";
+    let error = tracker::parse_response(page).err().unwrap().to_string();
+    assert!(error.contains("HTML page"), "{}", error);
+    assert!(error.contains("502 Bad Gateway"), "{}", error);
+}
+
+#[test]
+fn reports_malformed_bencode() {
+    let error = tracker::parse_response(b"d8:intervali1800")
+        .err()
+        .unwrap()
+        .to_string();
+    assert!(error.contains("malformed bencode"), "{}", error);
+}
+
+#[test]
+fn reports_failure_reason() {
+    // Latin-1 rather than UTF-8.
+    let error = tracker::parse_response(b"d14:failure reason8:t\xe9l\xe9chare")
+        .err()
+        .unwrap();
+    match error {
+        Error::TrackerFailure(reason) => {
+            assert!(reason.starts_with("t\u{fffd}l"))
+        }
+        e => panic!("expected a tracker failure, got {}", e),
+    }
+}
+
+#[test]
+fn keeps_response_with_warning() {
+    let response = tracker::parse_response(
+        b"d8:intervali1800e5:peers0:15:warning message9:slow downe",
+    )
+    .unwrap();
+    assert_eq!(response.interval, Some(1800));
+    assert_eq!(response.warning_message, Some("slow down".to_string()));
+}
+
+/// Gives every host the same IPv6 and IPv4 address.
+#[derive(Debug)]
+struct DualStackResolver;
+
+impl Resolver for DualStackResolver {
+    fn resolve(&self, _: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        Ok(vec![
+            SocketAddr::new("::1".parse().unwrap(), port),
+            SocketAddr::new("127.0.0.1".parse().unwrap(), port),
+        ])
+    }
+}
+
+#[test]
+fn resolves_peers_listed_by_name() {
+    let response = tracker::parse_response_with(
+        b"d5:peersld7:peer id20:-NAME-000000000000002:ip9:peer.test4:porti6881eeee",
+        &DualStackResolver,
+    )
+    .unwrap();
+    let sockets: Vec<SocketAddr> =
+        response.peers.iter().map(|x| x.socket).collect();
+    assert_eq!(
+        sockets,
+        vec![
+            "[::1]:6881".parse().unwrap(),
+            "127.0.0.1:6881".parse().unwrap()
+        ]
+    );
+    assert!(response
+        .peers
+        .iter()
+        .all(|x| x.id == Some(*b"-NAME-00000000000000")));
+}
+
+#[test]
+fn rejects_out_of_range_peer_ports() {
+    for port in &["-1", "65536", "4295000000"] {
+        let body = format!(
+            "d5:peersld7:peer id20:-PORT-000000000000002:ip9:127.0.0.1\
+             4:porti{}eeee",
+            port
+        );
+        let e = tracker::parse_response(body.as_bytes()).err().unwrap();
+        assert!(e.to_string().contains("valid port"), "{}", e);
+    }
+}
+
+#[test]
+fn parses_gzip_response() {
+    // An announce response compressed by a tracker that didn't set
+    // Content-Encoding.
+    let response = include_bytes!("data/announce_response.gz");
+    let response = tracker::parse_response(response).unwrap();
+    assert_eq!(response.interval, Some(1800));
+    assert_eq!(response.peers.len(), 1);
+    assert_eq!(response.peers[0].socket, "127.0.0.1:6881".parse().unwrap());
+}
+
+#[test]
+fn refuses_oversized_compressed_response() {
+    let response = include_bytes!("data/oversized_response.gz");
+    let e = tracker::parse_response(response).err().unwrap();
+    assert!(e.to_string().contains("decompresses to more than"), "{}", e);
+}
+
+/// Answers an announce with each of `bodies` in turn, passing on the
+/// request line of each to the receiver returned along with the tracker's
+/// URL.
+fn serve_bodies(
+    bodies: Vec<&'static [u8]>,
+) -> (String, mpsc::Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/announce", listener.local_addr().unwrap());
+    let (requests, received) = mpsc::channel();
+    thread::spawn(move || {
+        for body in bodies {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request: Vec<u8> = vec![];
+            let mut buffer = [0u8; 1024];
+            while !request.windows(4).any(|x| x == b"\r\n\r\n") {
+                let n = stream.read(&mut buffer).unwrap();
+                request.extend(&buffer[..n]);
+            }
+            let line = String::from_utf8_lossy(&request);
+            requests
+                .send(line.lines().next().unwrap().to_string())
+                .unwrap();
+            let header = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\
+                 Connection: close\r\n\r\n",
+                body.len()
+            );
+            stream.write_all(header.as_bytes()).unwrap();
+            stream.write_all(body).unwrap();
+        }
+    });
+    (url, received)
+}
+
+#[test]
+fn falls_back_to_non_compact_only_for_compact_failures() {
+    let client = HttpTrackerClient::new().unwrap();
+    let params = |url| AnnounceParams {
+        announce: url,
+        info_hash: [1; 20],
+        peer_id: [2; 20],
+        port: Some(6881),
+        ipv6: None,
+        uploaded: 0,
+        downloaded: 0,
+        left: 100,
+        compact: true,
+        event: None,
+    };
+    let dict_peers = &b"d8:intervali1800e5:peersld2:ip9:127.0.0.1\
+                         7:peer id20:-XX0000-0000000000004:porti6881eeee"[..];
+
+    // A compact reply that doesn't parse.
+    let (url, requests) = serve_bodies(vec![b"d5:peers5:abcdee", dict_peers]);
+    let response = client.announce(&params(&url)).unwrap();
+    assert_eq!(response.peers_format, PeersFormat::Dictionary);
+    let requests: Vec<String> = requests.iter().take(2).collect();
+    assert!(requests[0].contains("compact=1"), "{}", requests[0]);
+    assert!(requests[1].contains("compact=0"), "{}", requests[1]);
+
+    // A tracker refusing compact replies.
+    let (url, _requests) = serve_bodies(vec![
+        b"d14:failure reason24:compact is not supportede",
+        dict_peers,
+    ]);
+    let response = client.announce(&params(&url)).unwrap();
+    assert_eq!(response.peers_format, PeersFormat::Dictionary);
+
+    // Any other failure is final.
+    let (url, requests) =
+        serve_bodies(vec![b"d14:failure reason14:not authorisede", dict_peers]);
+    match client.announce(&params(&url)) {
+        Err(Error::TrackerFailure(reason)) => {
+            assert_eq!(reason, "not authorised")
+        }
+        result => panic!("{:?}", result.map(|x| x.peers)),
+    }
+    assert_eq!(requests.try_iter().count(), 1);
+}
+
+#[test]
+fn parses_ipv6_peers() {
+    let mut body = b"d8:intervali1800e5:peers6:\x7f\x00\x00\x01\x1a\xe1\
+                     6:peers618:"
+        .to_vec();
+    body.extend(&[0x20, 0x01, 0x0d, 0xb8]);
+    body.extend(&[0; 11]);
+    body.extend(&[1, 0x1a, 0xe2]);
+    body.push(b'e');
+    let response = tracker::parse_response(&body).unwrap();
+    let peers: Vec<String> = response
+        .peers
+        .iter()
+        .map(|x| x.socket.to_string())
+        .collect();
+    assert_eq!(peers, vec!["127.0.0.1:6881", "[2001:db8::1]:6882"]);
+
+    // What a tracker encodes, including its IPv6 peers, comes back out.
+    let encoded = tracker::encode_response(&response);
+    assert_eq!(
+        tracker::parse_response(&encoded).unwrap().peers,
+        response.peers
+    );
+
+    let e = tracker::parse_response(b"d5:peers0:6:peers65:abcdee")
+        .err()
+        .unwrap();
+    assert!(e.to_string().contains("multiple of 18"), "{}", e);
+}
+
+#[test]
+fn merges_peers_by_address() {
+    let socket = "127.0.0.1:6881".parse().unwrap();
+    let mut peers = vec![Peer { id: None, socket }];
+    tracker::merge_peers(
+        &mut peers,
+        vec![
+            Peer {
+                id: Some([1; 20]),
+                socket,
+            },
+            Peer {
+                id: None,
+                socket: "127.0.0.1:6882".parse().unwrap(),
+            },
+        ],
+    );
+    assert_eq!(peers.len(), 2);
+    assert_eq!(peers[0].id, None);
+    assert_eq!(peers[1].to_string(), "127.0.0.1:6882");
+}
+
+/// Fails the first `failures` announces.
+struct FlakyClient {
+    failures: u32,
+    attempts: Cell<u32>,
+}
+
+impl TrackerClient for FlakyClient {
+    fn announce(
+        &self,
+        _params: &AnnounceParams,
+    ) -> Result<TrackerResponse, Error> {
+        self.attempts.set(self.attempts.get() + 1);
+        if self.attempts.get() <= self.failures {
+            return Err(From::from("tracker is overloaded"));
+        }
+        Ok(TrackerResponse {
+            interval: Some(1800),
+            min_interval: None,
+            tracker_id: None,
+            complete: None,
+            incomplete: None,
+            warning_message: None,
+            peers: vec![],
+            peers_format: PeersFormat::Compact,
+        })
+    }
+}
+
+#[test]
+fn retries_failed_announces() {
+    let contents = include_bytes!("data/single.torrent");
+    let metainfo = TorrentMetaInfo::new(contents).unwrap();
+    let config = AnnounceConfig::default();
+    let clock = Arc::new(MockClock::new());
+    let start = clock.now();
+    let new_list = |retries| {
+        TrackerList::new(&metainfo)
+            .retries(retries)
+            .clock(clock.clone())
+    };
+
+    let client = FlakyClient {
+        failures: 2,
+        attempts: Cell::new(0),
+    };
+    assert!(new_list(2)
+        .announce_with(&client, &metainfo, &config)
+        .is_ok());
+    assert_eq!(client.attempts.get(), 3);
+    // Waits of 1 and 2 seconds before the retries.
+    assert_eq!(clock.now() - start, Duration::from_secs(3));
+
+    let client = FlakyClient {
+        failures: 2,
+        attempts: Cell::new(0),
+    };
+    assert!(new_list(1)
+        .announce_with(&client, &metainfo, &config)
+        .is_err());
+    assert_eq!(client.attempts.get(), 2);
+}
+
+/// A tracker that fails its regular announces stays scheduled, and is
+/// tried again with the same backoff as retries.
+#[test]
+fn retries_failed_regular_announces() {
+    let contents = include_bytes!("data/single.torrent");
+    let metainfo = TorrentMetaInfo::new(contents).unwrap();
+    let config = AnnounceConfig::default();
+    let clock = Arc::new(MockClock::new());
+    let mut trackers =
+        TrackerList::new(&metainfo).retries(2).clock(clock.clone());
+    let client = FlakyClient {
+        failures: 0,
+        attempts: Cell::new(0),
+    };
+    trackers.announce_with(&client, &metainfo, &config).unwrap();
+
+    clock.advance(Duration::from_secs(1800));
+    let client = FlakyClient {
+        failures: 3,
+        attempts: Cell::new(0),
+    };
+    // Waits of 1 and 2 seconds, then 2 again once past the retries.
+    for &wait in &[1, 2, 2] {
+        assert!(trackers
+            .announce_due_with(&client, &metainfo, &config)
+            .is_empty());
+        assert_eq!(trackers.next_announce(), Some(Duration::from_secs(wait)));
+        clock.advance(Duration::from_secs(wait));
+    }
+    let responses = trackers.announce_due_with(&client, &metainfo, &config);
+    assert_eq!(responses.len(), 1);
+    assert_eq!(client.attempts.get(), 4);
+    assert_eq!(trackers.next_announce(), Some(Duration::from_secs(1800)));
+}
+
+#[test]
+fn announces_when_asked_until_hung_up() {
+    let contents = include_bytes!("data/single.torrent");
+    let metainfo = TorrentMetaInfo::new(contents).unwrap();
+    let mut trackers =
+        TrackerList::new(&metainfo).clock(Arc::new(MockClock::new()));
+    let client = FlakyClient {
+        failures: 0,
+        attempts: Cell::new(0),
+    };
+    let (ask, requests) = mpsc::channel();
+    let (peers, _) = mpsc::channel();
+    ask.send(()).unwrap();
+    ask.send(()).unwrap();
+    drop(ask);
+    trackers.keep_announcing(
+        &client,
+        &metainfo,
+        &requests,
+        &peers,
+        AnnounceConfig::default,
+    );
+    assert_eq!(client.attempts.get(), 2);
+}
+
+#[test]
+fn rejects_negative_intervals() {
+    for body in &[
+        &b"d8:intervali-1e5:peers0:e"[..],
+        b"d12:min intervali-1e5:peers0:e",
+    ] {
+        let e = tracker::parse_response(body).err().unwrap();
+        assert!(e.to_string().contains("should not be negative"), "{}", e);
+    }
+}
+
+/// Answers every announce with `interval`.
+struct IntervalClient {
+    interval: u64,
+}
+
+impl TrackerClient for IntervalClient {
+    fn announce(
+        &self,
+        _params: &AnnounceParams,
+    ) -> Result<TrackerResponse, Error> {
+        Ok(TrackerResponse {
+            interval: Some(self.interval),
+            min_interval: Some(self.interval),
+            tracker_id: None,
+            complete: None,
+            incomplete: None,
+            warning_message: None,
+            peers: vec![],
+            peers_format: PeersFormat::Compact,
+        })
+    }
+}
+
+#[test]
+fn keeps_intervals_in_range() {
+    let contents = include_bytes!("data/single.torrent");
+    let metainfo = TorrentMetaInfo::new(contents).unwrap();
+    let config = AnnounceConfig::default();
+    let clock = Arc::new(MockClock::new());
+
+    let mut trackers = TrackerList::new(&metainfo).clock(clock.clone());
+    let client = IntervalClient { interval: 0 };
+    trackers.announce_with(&client, &metainfo, &config).unwrap();
+    assert_eq!(
+        trackers.next_announce(),
+        Some(tracker::MIN_ANNOUNCE_INTERVAL)
+    );
+
+    // Too far off to ever be due.
+    let mut trackers = TrackerList::new(&metainfo).clock(clock.clone());
+    let client = IntervalClient {
+        interval: u64::max_value(),
+    };
+    trackers.announce_with(&client, &metainfo, &config).unwrap();
+    assert_eq!(trackers.next_announce(), None);
+    assert!(trackers
+        .announce_due_with(&client, &metainfo, &config)
+        .is_empty());
+    assert!(trackers.announce_with(&client, &metainfo, &config).is_err());
+}
+
+/// Fails every announce, setting `cancel` as it does.
+struct CancellingClient {
+    cancel: Arc<AtomicBool>,
+    attempts: Cell<u32>,
+}
+
+impl TrackerClient for CancellingClient {
+    fn announce(
+        &self,
+        _params: &AnnounceParams,
+    ) -> Result<TrackerResponse, Error> {
+        self.attempts.set(self.attempts.get() + 1);
+        self.cancel.store(true, Ordering::SeqCst);
+        Err(From::from("tracker is overloaded"))
+    }
+}
+
+#[test]
+fn stops_retrying_once_cancelled() {
+    let contents = include_bytes!("data/single.torrent");
+    let metainfo = TorrentMetaInfo::new(contents).unwrap();
+    let cancel = Arc::new(AtomicBool::new(false));
+    let client = CancellingClient {
+        cancel: cancel.clone(),
+        attempts: Cell::new(0),
+    };
+    let e = TrackerList::new(&metainfo)
+        .retries(2)
+        .clock(Arc::new(MockClock::new()))
+        .cancel(cancel)
+        .announce_with(&client, &metainfo, &AnnounceConfig::default())
+        .err()
+        .unwrap();
+    assert_eq!(e.to_string(), "announce cancelled");
+    assert_eq!(client.attempts.get(), 1);
+}
+
+/// Answers every announce, recording which tracker it went to. `strict`
+/// asks for a `min interval`, and the others only give an `interval`.
+struct ScheduledClient {
+    strict: &'static str,
+    announces: RefCell<Vec<String>>,
+}
+
+impl TrackerClient for ScheduledClient {
+    fn announce(
+        &self,
+        params: &AnnounceParams,
+    ) -> Result<TrackerResponse, Error> {
+        self.announces
+            .borrow_mut()
+            .push(params.announce.to_string());
+        let strict = params.announce == self.strict;
+        Ok(TrackerResponse {
+            interval: Some(if strict { 1800 } else { 600 }),
+            min_interval: if strict { Some(300) } else { None },
+            tracker_id: None,
+            complete: None,
+            incomplete: None,
+            warning_message: None,
+            peers: vec![],
+            peers_format: PeersFormat::Compact,
+        })
+    }
+}
+
+#[test]
+fn keeps_each_trackers_schedule() {
+    let contents = include_bytes!("data/single.torrent");
+    let mut metainfo = TorrentMetaInfo::new(contents).unwrap();
+    let strict = "http://strict.example/announce";
+    let lenient = "http://lenient.example/announce";
+    metainfo.announce_list =
+        Some(vec![vec![strict.to_string()], vec![lenient.to_string()]]);
+    let clock = Arc::new(MockClock::new());
+    let mut trackers = TrackerList::new(&metainfo).clock(clock.clone());
+    let client = ScheduledClient {
+        strict,
+        announces: Default::default(),
+    };
+    let config = AnnounceConfig::default();
+    let announce = |trackers: &mut TrackerList| {
+        trackers.announce_with(&client, &metainfo, &config).unwrap();
+        client.announces.borrow_mut().pop().unwrap()
+    };
+
+    assert_eq!(announce(&mut trackers), strict);
+    // Within the strict tracker's min interval, so the next tier is used.
+    clock.advance(Duration::from_secs(60));
+    assert_eq!(announce(&mut trackers), lenient);
+    clock.advance(Duration::from_secs(240));
+    assert_eq!(announce(&mut trackers), strict);
+
+    // The lenient tracker is due 600 seconds after it was announced to,
+    // without waiting on the strict one's interval.
+    assert_eq!(trackers.next_announce(), Some(Duration::from_secs(360)));
+    clock.advance(Duration::from_secs(360));
+    let responses = trackers.announce_due_with(&client, &metainfo, &config);
+    assert_eq!(responses.len(), 1);
+    assert_eq!(*client.announces.borrow(), vec![lenient.to_string()]);
+    assert_eq!(trackers.next_announce(), Some(Duration::from_secs(600)));
+}
@@ -0,0 +1,62 @@
+#![cfg(feature = "tracker-server")]
+
+extern crate torrent;
+
+use std::thread;
+use torrent::tracker::{
+    AnnounceParams, Event, HttpTrackerClient, PeersFormat, TrackerClient,
+};
+use torrent::tracker_server::TrackerServer;
+
+#[test]
+fn tracks_peers_per_info_hash() {
+    let server = TrackerServer::bind("127.0.0.1:0").unwrap();
+    let announce = format!("http://{}/announce", server.local_addr().unwrap());
+    thread::spawn(move || server.run());
+
+    let client = HttpTrackerClient::new().unwrap();
+    let params = |port, info_hash, event| AnnounceParams {
+        announce: &announce,
+        info_hash: [info_hash; 20],
+        peer_id: [port as u8; 20],
+        port: Some(port),
+        ipv6: None,
+        uploaded: 0,
+        downloaded: 0,
+        left: if port == 6881 { 0 } else { 100 },
+        compact: true,
+        event,
+    };
+
+    let response = client.announce(&params(6881, 1, None)).unwrap();
+    assert!(response.peers.is_empty());
+    assert_eq!(response.complete, Some(1));
+
+    let response = client.announce(&params(6882, 1, None)).unwrap();
+    assert_eq!(response.peers.len(), 1);
+    assert_eq!(response.peers[0].socket, "127.0.0.1:6881".parse().unwrap());
+    assert_eq!(response.peers_format, PeersFormat::Compact);
+    assert_eq!(response.complete, Some(1));
+    assert_eq!(response.incomplete, Some(1));
+
+    // Other torrents' swarms are kept apart.
+    let response = client.announce(&params(6883, 2, None)).unwrap();
+    assert!(response.peers.is_empty());
+
+    let response = client
+        .announce(&AnnounceParams {
+            compact: false,
+            ..params(6883, 1, None)
+        })
+        .unwrap();
+    assert_eq!(response.peers.len(), 2);
+    assert_eq!(response.peers_format, PeersFormat::Dictionary);
+
+    client
+        .announce(&params(6881, 1, Some(Event::Stopped)))
+        .unwrap();
+    let response = client.announce(&params(6882, 1, None)).unwrap();
+    assert_eq!(response.peers.len(), 1);
+    assert_eq!(response.peers[0].socket, "127.0.0.1:6883".parse().unwrap());
+    assert_eq!(response.complete, Some(0));
+}